@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS public."SegmentIndex"
+(
+    file text NOT NULL,
+    start bigint NOT NULL,
+    "end" bigint NOT NULL,
+    collection text NOT NULL,
+    date date NOT NULL,
+    checksum text NOT NULL DEFAULT '',
+    created_at timestamptz NOT NULL DEFAULT now(),
+    CONSTRAINT "SegmentIndex_pkey" PRIMARY KEY (file, start)
+)
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS public."IdempotencyKeys"
+(
+    collection text NOT NULL,
+    key text NOT NULL,
+    reference text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    CONSTRAINT "IdempotencyKeys_pkey" PRIMARY KEY (collection, key)
+)
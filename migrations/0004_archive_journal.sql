@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS public."ArchiveJournal"
+(
+    id bigserial NOT NULL,
+    file text NOT NULL,
+    node text NOT NULL,
+    error text,
+    finished_at timestamptz NOT NULL DEFAULT now(),
+    CONSTRAINT "ArchiveJournal_pkey" PRIMARY KEY (id)
+);
+
+CREATE INDEX IF NOT EXISTS "ArchiveJournal_file_idx" ON public."ArchiveJournal" (file)
@@ -0,0 +1,6 @@
+ALTER TABLE public."SegmentIndex"
+    ADD COLUMN IF NOT EXISTS codec text NOT NULL DEFAULT 'gzip',
+    ADD COLUMN IF NOT EXISTS tier text NOT NULL DEFAULT 'efs',
+    ADD COLUMN IF NOT EXISTS s3_key text;
+
+CREATE INDEX IF NOT EXISTS "SegmentIndex_collection_date_idx" ON public."SegmentIndex" (collection, date)
@@ -0,0 +1,326 @@
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
+use rusoto_s3::S3Client;
+use std::io::Write;
+
+use crate::config::{Config, PostgresConfig};
+use crate::facades::compression::gzip_decompress;
+use crate::facades::efs_facade::read_manifest;
+use crate::facades::{archivist, catalog, migrations, postgres_facade, s3, scrubber};
+use crate::handlers::collections::{extract_query_params, get_handler};
+use crate::state::AppState;
+
+//Every subcommand reads the same config as the server (.env, CONFIG_FILE, environment)
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Caching proxy storing payloads on EFS and archiving them to S3"
+)]
+pub struct Cli {
+    //The server is started when no subcommand is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server
+    Serve,
+    /// Archive the collection files of a past day to S3_BUCKET
+    Archive {
+        /// Day of the files, YYYY-MM-DD
+        #[arg(long)]
+        date: NaiveDate,
+        /// Only archive this collection
+        #[arg(long)]
+        collection: Option<String>,
+    },
+    /// Print the manifest of a collection file as JSON
+    InspectManifest {
+        /// Collection file, without extension
+        file: String,
+    },
+    /// Check every segment of a collection file, bad segments are quarantined
+    Verify {
+        /// Collection file, without extension
+        file: String,
+    },
+    /// Write the payload of a reference to stdout
+    Get {
+        /// {file}?start={start}&end={end}, as returned by POST
+        reference: String,
+        /// Write the payload as it was posted instead of gzip
+        #[arg(long)]
+        decompress: bool,
+    },
+    /// List the buckets reachable with the S3 config
+    ListBuckets,
+    /// Create an S3 bucket
+    CreateBucket { name: String },
+    /// Apply the pending schema migrations to the configured Postgres database
+    MigrateDb,
+}
+
+/*Steps
+1. Build what the command needs from the config (storage tiers, S3 client, Postgres pool)
+2. Run it, the result goes to out
+*/
+pub async fn run(command: Command, config: &Config, out: &mut impl Write) -> Result<(), String> {
+    match command {
+        Command::Serve => Err("serve is run by main".to_string()),
+        Command::Archive { date, collection } => {
+            let files =
+                archivist::list_day_files(&config.base_path, date, collection.as_deref()).await?;
+            let bucket = config
+                .s3_bucket
+                .as_ref()
+                .ok_or("S3_BUCKET must be set to archive")?;
+            let client = create_s3_client(config);
+            let state = AppState::from_config(config.clone())?;
+
+            for file in files {
+                archivist::archive_file(&config.base_path, &file, bucket, client.clone()).await?;
+                if let Some(pool) = state.catalog() {
+                    catalog::record_archived(pool, &file, &file).await;
+                }
+                write_line(out, &file)?;
+            }
+            Ok(())
+        }
+        Command::InspectManifest { file } => {
+            let manifest = read_manifest(&config.base_path, file.clone())
+                .await?
+                .ok_or(format!("no manifest for {}", file))?;
+            let json = serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())?;
+            write_line(out, &json)
+        }
+        Command::Verify { file } => {
+            let report = scrubber::scrub_file(&config.base_path, &file).await?;
+            write_line(
+                out,
+                &format!(
+                    "{} segments checked, {} quarantined",
+                    report.segments, report.bad_segments
+                ),
+            )?;
+            match report.bad_segments {
+                0 => Ok(()),
+                bad => Err(format!("{} bad segments in {}", bad, file)),
+            }
+        }
+        Command::Get {
+            reference,
+            decompress,
+        } => {
+            let (file, _) = reference
+                .split_once('?')
+                .ok_or("expected {file}?start={start}&end={end}")?;
+            let params = extract_query_params(&reference);
+            let (start, end) = match (
+                params.get("start").map(|s| s.parse::<u64>()),
+                params.get("end").map(|e| e.parse::<u64>()),
+            ) {
+                (Some(Ok(start)), Some(Ok(end))) if start < end => (start, end),
+                _ => return Err("unable to parse start & end params".to_string()),
+            };
+
+            let state = AppState::from_config(config.clone())?;
+            let bytes = get_handler(&state, file.to_string(), start, end)
+                .await?
+                .ok_or("unable to find supplied byte range")?;
+            let bytes = match decompress {
+                true => gzip_decompress(bytes)?,
+                false => bytes,
+            };
+            out.write_all(&bytes).map_err(|e| e.to_string())
+        }
+        Command::ListBuckets => {
+            let buckets = s3::list_buckets(create_s3_client(config))
+                .await
+                .map_err(|e| e.to_string())?;
+            for bucket in buckets {
+                write_line(out, &bucket)?;
+            }
+            Ok(())
+        }
+        Command::CreateBucket { name } => {
+            s3::create_bucket(&name, create_s3_client(config))
+                .await
+                .map_err(|e| e.to_string())?;
+            write_line(out, &format!("created {}", name))
+        }
+        Command::MigrateDb => {
+            let pg = config.postgres.as_ref().ok_or(
+                "POSTGRES_HOST, POSTGRES_USER, POSTGRES_PASSWORD and POSTGRES_DB must be set",
+            )?;
+            //One connection is enough, the TLS and timeout settings still apply
+            let pool = postgres_facade::create_pool_from_config(&PostgresConfig {
+                pool_size: 1,
+                ..pg.clone()
+            })?;
+            let mut client = postgres_facade::get_client(&pool).await?;
+            for version in migrations::migrate(&mut client).await? {
+                write_line(out, &format!("applied migration {}", version))?;
+            }
+            write_line(
+                out,
+                &format!("schema is at version {}", migrations::latest_version()),
+            )
+        }
+    }
+}
+
+fn create_s3_client(config: &Config) -> S3Client {
+    s3::create_client(config.s3_endpoint.as_deref(), config.s3_region.as_deref())
+}
+
+fn write_line(out: &mut impl Write, line: &str) -> Result<(), String> {
+    writeln!(out, "{}", line).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::facades::efs_facade::{EfsBackend, Metadata};
+    use crate::facades::fake_s3::FakeS3;
+    use crate::facades::storage::StorageBackend;
+    use crate::facades::{compression::gzip_compress, integrity};
+    use chrono::Utc;
+    use serial_test::serial;
+    use std::env;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from([&["proxy_cache_aws"], args].concat())
+    }
+
+    fn efs_config(base: &str) -> Config {
+        Config {
+            base_path: base.to_string(),
+            ..Config::default()
+        }
+    }
+
+    async fn append(base: &str, collection: &str, bytes: &[u8]) -> String {
+        let compressed = gzip_compress(bytes.to_vec()).unwrap();
+        let meta = Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            0,
+            0,
+            integrity::checksum(&compressed),
+        );
+        let (file, start, end) = EfsBackend::new(base)
+            .append(collection, compressed, meta)
+            .await
+            .unwrap();
+        format!("{}?start={}&end={}", file, start, end)
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse(&[]).unwrap().command, None);
+        assert_eq!(
+            parse(&["archive", "--date", "2023-08-01"]).unwrap().command,
+            Some(Command::Archive {
+                date: NaiveDate::from_ymd_opt(2023, 8, 1).unwrap(),
+                collection: None
+            })
+        );
+        assert_eq!(
+            parse(&["get", "file?start=0&end=10", "--decompress"])
+                .unwrap()
+                .command,
+            Some(Command::Get {
+                reference: "file?start=0&end=10".to_string(),
+                decompress: true
+            })
+        );
+        assert!(parse(&["archive", "--date", "yesterday"]).is_err());
+        assert!(parse(&["inspect-manifest"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn get_and_inspect_a_reference() {
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap();
+        let config = efs_config(base_path);
+        let reference = append(base_path, "cli_collection", b"cli payload").await;
+
+        let mut out = Vec::new();
+        let get = Command::Get {
+            reference: reference.clone(),
+            decompress: true,
+        };
+        run(get, &config, &mut out).await.unwrap();
+        assert_eq!(out, b"cli payload");
+
+        let mut out = Vec::new();
+        let (file, _) = reference.split_once('?').unwrap();
+        let inspect = Command::InspectManifest {
+            file: file.to_string(),
+        };
+        run(inspect, &config, &mut out).await.unwrap();
+        let manifest: Vec<Metadata> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(manifest.len(), 1);
+
+        let missing = Command::Get {
+            reference: format!("{}?start=100&end=200", file),
+            decompress: false,
+        };
+        assert!(run(missing, &config, &mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn archive_a_past_day() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket("cli-bucket");
+        env::set_var("AWS_ACCESS_KEY_ID", "fake");
+        env::set_var("AWS_SECRET_ACCESS_KEY", "fake");
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap();
+        env::set_var("BASE_PATH", base_path);
+
+        let reference = append(base_path, "archived_cli_collection", b"old payload").await;
+        let (file, _) = reference.split_once('?').unwrap();
+        //Written today, renamed as if it was from an earlier day
+        let old_file = file.replace(&Utc::now().format("%Y-%m-%d").to_string(), "2023-08-01");
+        for extension in [".gzip", ".manifest"] {
+            std::fs::rename(
+                base.path().join(format!("{}{}", file, extension)),
+                base.path().join(format!("{}{}", old_file, extension)),
+            )
+            .unwrap();
+        }
+
+        let config = Config {
+            s3_bucket: Some("cli-bucket".to_string()),
+            s3_endpoint: Some(fake.endpoint().to_string()),
+            s3_region: Some("fake".to_string()),
+            ..efs_config(base_path)
+        };
+        let today = Command::Archive {
+            date: Utc::now().date_naive(),
+            collection: None,
+        };
+        assert!(run(today, &config, &mut Vec::new()).await.is_err());
+
+        let mut out = Vec::new();
+        let archive = Command::Archive {
+            date: NaiveDate::from_ymd_opt(2023, 8, 1).unwrap(),
+            collection: Some("archived_cli_collection".to_string()),
+        };
+        run(archive, &config, &mut out).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", old_file));
+        assert!(fake.object("cli-bucket", &old_file).is_some());
+
+        let mut out = Vec::new();
+        run(Command::ListBuckets, &config, &mut out).await.unwrap();
+        assert_eq!(out, b"cli-bucket\n");
+
+        env::remove_var("BASE_PATH");
+        env::remove_var("AWS_ACCESS_KEY_ID");
+        env::remove_var("AWS_SECRET_ACCESS_KEY");
+    }
+}
@@ -0,0 +1,960 @@
+use dotenv::dotenv;
+use rusoto_core::Region;
+
+use crate::facades::durability::DurabilityMode;
+use crate::facades::node::is_valid_node_id;
+use crate::facades::rotation::RotationWindow;
+use std::{
+    collections::HashMap, env, fs, net::SocketAddr, path::Path, str::FromStr, time::Duration,
+};
+
+//Keys of the config file. The environment variable with the same name in uppercase wins over the file.
+const KEYS: [&str; 51] = [
+    "app_host",
+    "app_port",
+    "with_logs",
+    "base_path",
+    "legacy_path",
+    "storage_backend",
+    "node_id",
+    "node_id_file",
+    "s3_bucket",
+    "s3_endpoint",
+    "s3_region",
+    "postgres_host",
+    "postgres_user",
+    "postgres_password",
+    "postgres_db",
+    "postgres_pool_size",
+    "postgres_connect_timeout_seconds",
+    "postgres_wait_timeout_seconds",
+    "postgres_recycle_timeout_seconds",
+    "postgres_recycling_method",
+    "postgres_ssl_mode",
+    "postgres_ssl_root_cert",
+    "postgres_ssl_cert",
+    "postgres_ssl_key",
+    "postgres_migrate",
+    "with_catalog",
+    "compression_level",
+    "with_dedup",
+    "durability_mode",
+    "durability_mode_overrides",
+    "group_commit_interval_ms",
+    "rotation_max_bytes",
+    "rotation_window",
+    "idempotency_window_seconds",
+    "with_recovery",
+    "with_scrubber",
+    "scrub_interval_seconds",
+    "shutdown_timeout_seconds",
+    "otel_exporter",
+    "otel_endpoint",
+    "otel_file",
+    "otel_service_name",
+    "log_headers_allow",
+    "log_headers_deny",
+    "min_free_bytes",
+    "health_cache_seconds",
+    "disk_high_watermark",
+    "disk_critical_watermark",
+    "disk_check_interval_seconds",
+    "retry_after_seconds",
+    "admin_token",
+];
+
+//Always redacted in the request logs, LOG_HEADERS_DENY adds to them
+const REDACTED_HEADERS: [&str; 6] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-amz-security-token",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageKind {
+    Efs,
+    Memory,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostgresConfig {
+    pub host: String,
+    pub user: String,
+    pub password: String,
+    pub db: String,
+    pub pool_size: usize,
+    //Opening a connection, TCP and TLS included
+    pub connect_timeout: Option<Duration>,
+    //Waiting for a free connection of the pool
+    pub wait_timeout: Option<Duration>,
+    //Checking a connection before it is handed out again
+    pub recycle_timeout: Option<Duration>,
+    pub recycling_method: PostgresRecycling,
+    pub tls: PostgresTls,
+}
+
+//How a pooled connection is checked before it is reused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PostgresRecycling {
+    //Only checks that the connection isn't closed
+    Fast,
+    //Runs a query
+    Verified,
+    //Also resets the session (prepared statements, settings, temporary tables)
+    Clean,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PostgresSslMode {
+    Disable,
+    //Encrypted, the server certificate isn't checked
+    Require,
+    //Encrypted, the certificate must be signed by the CA bundle and match the host
+    VerifyFull,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostgresTls {
+    pub mode: PostgresSslMode,
+    //PEM CA bundle, the webpki roots when unset
+    pub root_cert: Option<String>,
+    //PEM certificate chain and key for client certificate authentication
+    pub client_cert: Option<(String, String)>,
+}
+
+impl Default for PostgresTls {
+    fn default() -> PostgresTls {
+        PostgresTls {
+            mode: PostgresSslMode::Disable,
+            root_cert: None,
+            client_cert: None,
+        }
+    }
+}
+
+//Where the OpenTelemetry spans go
+#[derive(Debug, Clone, PartialEq)]
+pub enum TelemetryExporter {
+    //OTLP over gRPC to a collector
+    Otlp(String),
+    //JSON lines, for offline testing
+    Stdout,
+    File(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryConfig {
+    pub exporter: TelemetryExporter,
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub addr: SocketAddr,
+    pub with_logs: bool,
+    pub base_path: String,
+    //Archives of the old EFS format, migrated into base_path at startup
+    pub legacy_path: Option<String>,
+    pub storage_backend: StorageKind,
+    //Identity of this writer in file names, replicas sharing BASE_PATH need their own
+    pub node_id: Option<String>,
+    //Where the generated node ID is kept across restarts when NODE_ID is unset
+    pub node_id_file: String,
+    pub s3_bucket: Option<String>,
+    //S3 compatible endpoint like MinIO, AWS when unset
+    pub s3_endpoint: Option<String>,
+    pub s3_region: Option<String>,
+    pub postgres: Option<PostgresConfig>,
+    //Apply the pending migrations at startup, otherwise only check the schema version
+    pub postgres_migrate: bool,
+    //Segments are recorded in Postgres and GET looks them up there first
+    pub with_catalog: bool,
+    //gzip level, 0 (none) to 9 (best)
+    pub compression_level: u32,
+    //A payload already stored today returns the reference of the first one
+    pub with_dedup: bool,
+    pub durability_mode: DurabilityMode,
+    //Collection -> mode, for the collections that don't use durability_mode
+    pub durability_overrides: HashMap<String, DurabilityMode>,
+    //How long a group commit waits for other writes before the fsync
+    pub group_commit_interval: Duration,
+    //Segments are sealed once they reach this size, None rotates by time only
+    pub rotation_max_bytes: Option<u64>,
+    pub rotation_window: RotationWindow,
+    //How long an Idempotency-Key returns the reference of its first request
+    pub idempotency_window: Duration,
+    pub with_recovery: bool,
+    pub with_scrubber: bool,
+    pub scrub_interval: Duration,
+    //How long in-flight requests, writes and archiving get once SIGTERM/SIGINT is received
+    pub shutdown_timeout: Duration,
+    //None when spans aren't exported
+    pub telemetry: Option<TelemetryConfig>,
+    //Headers written to the request logs, all of them when None
+    pub log_headers_allow: Option<Vec<String>>,
+    //Headers whose value is redacted in the request logs, lowercase
+    pub log_headers_deny: Vec<String>,
+    //BASE_PATH with less free space than this is not ready
+    pub min_free_bytes: u64,
+    //How long the Postgres and S3 checks of /health/ready are reused
+    pub health_cache: Duration,
+    //Percent of BASE_PATH used (space or inodes) from which sealed segments are archived early
+    pub disk_high_watermark: u8,
+    //Percent of BASE_PATH used from which writes are rejected
+    pub disk_critical_watermark: u8,
+    pub disk_check_interval: Duration,
+    //Sent in Retry-After when a write is rejected
+    pub retry_after: Duration,
+    //Bearer token of the /admin routes, they answer 404 when unset
+    pub admin_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            addr: SocketAddr::from(([0, 0, 0, 0], 5000)),
+            with_logs: true,
+            base_path: '/'.to_string(),
+            legacy_path: None,
+            storage_backend: StorageKind::Efs,
+            node_id: None,
+            node_id_file: "/.node-id".to_string(),
+            s3_bucket: None,
+            s3_endpoint: None,
+            s3_region: None,
+            postgres: None,
+            postgres_migrate: true,
+            with_catalog: false,
+            compression_level: 6,
+            with_dedup: false,
+            durability_mode: DurabilityMode::None,
+            durability_overrides: HashMap::new(),
+            group_commit_interval: Duration::from_millis(10),
+            rotation_max_bytes: None,
+            rotation_window: RotationWindow::Daily,
+            idempotency_window: Duration::from_secs(86_400),
+            with_recovery: true,
+            with_scrubber: true,
+            scrub_interval: Duration::from_secs(3600),
+            shutdown_timeout: Duration::from_secs(30),
+            telemetry: None,
+            log_headers_allow: None,
+            log_headers_deny: REDACTED_HEADERS.iter().map(|h| h.to_string()).collect(),
+            min_free_bytes: 1 << 30,
+            health_cache: Duration::from_secs(5),
+            disk_high_watermark: 80,
+            disk_critical_watermark: 95,
+            disk_check_interval: Duration::from_secs(10),
+            retry_after: Duration::from_secs(30),
+            admin_token: None,
+        }
+    }
+}
+
+pub fn create_addr(host: &str, port: &str) -> Result<SocketAddr, String> {
+    let format = format!("{}:{}", host, port);
+    format
+        .parse::<SocketAddr>()
+        .map_err(|_| format!("{} is not a valid app address", format))
+}
+
+impl Config {
+    //.env first, then the TOML file at CONFIG_FILE (if set), then the environment
+    pub fn load() -> Result<Config, String> {
+        dotenv().ok();
+        let file = match env::var("CONFIG_FILE") {
+            Ok(path) => Some(
+                fs::read_to_string(&path).map_err(|e| format!("unable to read {}: {}", path, e))?,
+            ),
+            Err(_) => None,
+        };
+        Config::from_sources(file.as_deref(), |key| env::var(key).ok())
+    }
+
+    /*Steps
+    1. Parse the config file, unknown keys are an error
+    2. Look up every key in the environment, then in the file
+    3. Validate the values, the first invalid one is returned as the error
+    */
+    pub fn from_sources(
+        file: Option<&str>,
+        lookup_env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, String> {
+        let file = match file {
+            Some(content) => parse_file(content)?,
+            None => HashMap::new(),
+        };
+        let get = |key: &str| {
+            lookup_env(&key.to_uppercase())
+                .or_else(|| file.get(key).cloned())
+                .filter(|value| !value.is_empty())
+        };
+        let default = Config::default();
+
+        let addr = match (get("app_host"), get("app_port")) {
+            (None, None) => default.addr,
+            (host, port) => create_addr(
+                &host.unwrap_or(default.addr.ip().to_string()),
+                &port.unwrap_or(default.addr.port().to_string()),
+            )?,
+        };
+
+        let storage_backend = match get("storage_backend").as_deref() {
+            None | Some("efs") => StorageKind::Efs,
+            Some("memory") => StorageKind::Memory,
+            Some(other) => {
+                return Err(format!(
+                    "invalid STORAGE_BACKEND {:?}, expected efs or memory",
+                    other
+                ))
+            }
+        };
+
+        let base_path = get("base_path").unwrap_or(default.base_path);
+        if storage_backend == StorageKind::Efs && !Path::new(&base_path).is_dir() {
+            return Err(format!("BASE_PATH {} is not a directory", base_path));
+        }
+
+        let node_id = get("node_id");
+        if let Some(node_id) = &node_id {
+            if !is_valid_node_id(node_id) {
+                return Err(format!(
+                    "invalid NODE_ID {:?}, only letters and digits are allowed",
+                    node_id
+                ));
+            }
+        }
+        let node_id_file = get("node_id_file").unwrap_or(
+            Path::new(&base_path)
+                .join(".node-id")
+                .to_string_lossy()
+                .to_string(),
+        );
+
+        let s3_region = get("s3_region");
+        if let Some(region) = &s3_region {
+            //Any name goes for a custom endpoint
+            if get("s3_endpoint").is_none() && region.parse::<Region>().is_err() {
+                return Err(format!("invalid S3_REGION {:?}", region));
+            }
+        }
+
+        let legacy_path = get("legacy_path");
+        if let Some(legacy_path) = &legacy_path {
+            if !Path::new(legacy_path).is_dir() {
+                return Err(format!("LEGACY_PATH {} is not a directory", legacy_path));
+            }
+        }
+
+        let postgres = parse_postgres(&get)?;
+
+        let with_catalog = parse_value(get("with_catalog"), "WITH_CATALOG", default.with_catalog)?;
+        if with_catalog && postgres.is_none() {
+            return Err("WITH_CATALOG needs POSTGRES_HOST, POSTGRES_USER, POSTGRES_PASSWORD and POSTGRES_DB".to_string());
+        }
+
+        let compression_level = parse_value(
+            get("compression_level"),
+            "COMPRESSION_LEVEL",
+            default.compression_level,
+        )?;
+        if compression_level > 9 {
+            return Err(format!(
+                "invalid COMPRESSION_LEVEL {}, expected 0 to 9",
+                compression_level
+            ));
+        }
+
+        let durability_mode = match get("durability_mode") {
+            Some(mode) => parse_durability_mode(&mode, "DURABILITY_MODE")?,
+            None => default.durability_mode,
+        };
+        //"collection=mode,other=mode", collection names keep their case
+        let mut durability_overrides = HashMap::new();
+        let overrides = get("durability_mode_overrides").unwrap_or_default();
+        for pair in overrides.split(',').filter(|pair| !pair.trim().is_empty()) {
+            match pair.split_once('=') {
+                Some((collection, mode)) if !collection.trim().is_empty() => {
+                    let name = format!("DURABILITY_MODE_OVERRIDES {}", collection.trim());
+                    let mode = parse_durability_mode(mode, &name)?;
+                    durability_overrides.insert(collection.trim().to_string(), mode);
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid DURABILITY_MODE_OVERRIDES {:?}, expected collection=mode",
+                        pair
+                    ))
+                }
+            }
+        }
+
+        let group_commit_interval_ms = parse_value(
+            get("group_commit_interval_ms"),
+            "GROUP_COMMIT_INTERVAL_MS",
+            default.group_commit_interval.as_millis() as u64,
+        )?;
+        if group_commit_interval_ms == 0 {
+            return Err("GROUP_COMMIT_INTERVAL_MS must be greater than 0".to_string());
+        }
+
+        let rotation_max_bytes: u64 =
+            parse_value(get("rotation_max_bytes"), "ROTATION_MAX_BYTES", 0)?;
+        let rotation_window = match get("rotation_window").as_deref() {
+            None => default.rotation_window,
+            Some(window) => RotationWindow::parse(window).ok_or(format!(
+                "invalid ROTATION_WINDOW {:?}, expected daily or hourly",
+                window
+            ))?,
+        };
+
+        let idempotency_window_seconds = parse_value(
+            get("idempotency_window_seconds"),
+            "IDEMPOTENCY_WINDOW_SECONDS",
+            default.idempotency_window.as_secs(),
+        )?;
+        if idempotency_window_seconds == 0 {
+            return Err("IDEMPOTENCY_WINDOW_SECONDS must be greater than 0".to_string());
+        }
+
+        let scrub_interval_seconds = parse_value(
+            get("scrub_interval_seconds"),
+            "SCRUB_INTERVAL_SECONDS",
+            default.scrub_interval.as_secs(),
+        )?;
+        if scrub_interval_seconds == 0 {
+            return Err("SCRUB_INTERVAL_SECONDS must be greater than 0".to_string());
+        }
+
+        let shutdown_timeout_seconds = parse_value(
+            get("shutdown_timeout_seconds"),
+            "SHUTDOWN_TIMEOUT_SECONDS",
+            default.shutdown_timeout.as_secs(),
+        )?;
+        if shutdown_timeout_seconds == 0 {
+            return Err("SHUTDOWN_TIMEOUT_SECONDS must be greater than 0".to_string());
+        }
+
+        let exporter = match get("otel_exporter").as_deref() {
+            None | Some("none") => None,
+            Some("otlp") => Some(TelemetryExporter::Otlp(
+                get("otel_endpoint").unwrap_or("http://localhost:4317".to_string()),
+            )),
+            Some("stdout") => Some(TelemetryExporter::Stdout),
+            Some("file") => match get("otel_file") {
+                Some(path) => Some(TelemetryExporter::File(path)),
+                None => return Err("OTEL_FILE must be set for the file exporter".to_string()),
+            },
+            Some(other) => {
+                return Err(format!(
+                    "invalid OTEL_EXPORTER {:?}, expected none, otlp, stdout or file",
+                    other
+                ))
+            }
+        };
+        let telemetry = exporter.map(|exporter| TelemetryConfig {
+            exporter,
+            service_name: get("otel_service_name").unwrap_or(env!("CARGO_PKG_NAME").to_string()),
+        });
+
+        let disk_high_watermark = parse_value(
+            get("disk_high_watermark"),
+            "DISK_HIGH_WATERMARK",
+            default.disk_high_watermark,
+        )?;
+        let disk_critical_watermark = parse_value(
+            get("disk_critical_watermark"),
+            "DISK_CRITICAL_WATERMARK",
+            default.disk_critical_watermark,
+        )?;
+        if disk_high_watermark >= disk_critical_watermark || disk_critical_watermark > 100 {
+            return Err(format!(
+                "invalid disk watermarks {}/{}, expected DISK_HIGH_WATERMARK < DISK_CRITICAL_WATERMARK <= 100",
+                disk_high_watermark, disk_critical_watermark
+            ));
+        }
+
+        let disk_check_interval_seconds = parse_value(
+            get("disk_check_interval_seconds"),
+            "DISK_CHECK_INTERVAL_SECONDS",
+            default.disk_check_interval.as_secs(),
+        )?;
+        if disk_check_interval_seconds == 0 {
+            return Err("DISK_CHECK_INTERVAL_SECONDS must be greater than 0".to_string());
+        }
+
+        let mut log_headers_deny = default.log_headers_deny;
+        for header in parse_list(get("log_headers_deny")).unwrap_or_default() {
+            if !log_headers_deny.contains(&header) {
+                log_headers_deny.push(header);
+            }
+        }
+
+        Ok(Config {
+            addr,
+            with_logs: parse_value(get("with_logs"), "WITH_LOGS", default.with_logs)?,
+            base_path,
+            legacy_path,
+            storage_backend,
+            node_id,
+            node_id_file,
+            s3_bucket: get("s3_bucket"),
+            s3_endpoint: get("s3_endpoint"),
+            s3_region,
+            postgres,
+            postgres_migrate: parse_value(
+                get("postgres_migrate"),
+                "POSTGRES_MIGRATE",
+                default.postgres_migrate,
+            )?,
+            with_catalog,
+            compression_level,
+            with_dedup: parse_value(get("with_dedup"), "WITH_DEDUP", default.with_dedup)?,
+            durability_mode,
+            durability_overrides,
+            group_commit_interval: Duration::from_millis(group_commit_interval_ms),
+            rotation_max_bytes: Some(rotation_max_bytes).filter(|max| *max > 0),
+            rotation_window,
+            idempotency_window: Duration::from_secs(idempotency_window_seconds),
+            with_recovery: parse_value(
+                get("with_recovery"),
+                "WITH_RECOVERY",
+                default.with_recovery,
+            )?,
+            with_scrubber: parse_value(
+                get("with_scrubber"),
+                "WITH_SCRUBBER",
+                default.with_scrubber,
+            )?,
+            scrub_interval: Duration::from_secs(scrub_interval_seconds),
+            shutdown_timeout: Duration::from_secs(shutdown_timeout_seconds),
+            telemetry,
+            log_headers_allow: parse_list(get("log_headers_allow")),
+            log_headers_deny,
+            min_free_bytes: parse_value(
+                get("min_free_bytes"),
+                "MIN_FREE_BYTES",
+                default.min_free_bytes,
+            )?,
+            health_cache: Duration::from_secs(parse_value(
+                get("health_cache_seconds"),
+                "HEALTH_CACHE_SECONDS",
+                default.health_cache.as_secs(),
+            )?),
+            disk_high_watermark,
+            disk_critical_watermark,
+            disk_check_interval: Duration::from_secs(disk_check_interval_seconds),
+            retry_after: Duration::from_secs(parse_value(
+                get("retry_after_seconds"),
+                "RETRY_AFTER_SECONDS",
+                default.retry_after.as_secs(),
+            )?),
+            admin_token: get("admin_token"),
+        })
+    }
+}
+
+/*Steps
+1. The connection settings go together, none of them means no Postgres
+2. Pool size, timeouts (0 or unset waits forever) and recycling method
+3. TLS mode, the certificate files must exist
+*/
+fn parse_postgres(get: &impl Fn(&str) -> Option<String>) -> Result<Option<PostgresConfig>, String> {
+    let (host, user, password, db) = match (
+        get("postgres_host"),
+        get("postgres_user"),
+        get("postgres_password"),
+        get("postgres_db"),
+    ) {
+        (Some(host), Some(user), Some(password), Some(db)) => (host, user, password, db),
+        (None, None, None, None) => return Ok(None),
+        _ => return Err(
+            "POSTGRES_HOST, POSTGRES_USER, POSTGRES_PASSWORD and POSTGRES_DB must be set together"
+                .to_string(),
+        ),
+    };
+
+    let pool_size = parse_value(get("postgres_pool_size"), "POSTGRES_POOL_SIZE", 16)?;
+    if pool_size == 0 {
+        return Err("POSTGRES_POOL_SIZE must be greater than 0".to_string());
+    }
+    let timeout = |key: &str| -> Result<Option<Duration>, String> {
+        let seconds: u64 = parse_value(get(key), &key.to_uppercase(), 0)?;
+        Ok(Some(Duration::from_secs(seconds)).filter(|timeout| !timeout.is_zero()))
+    };
+
+    let recycling_method = match get("postgres_recycling_method").as_deref() {
+        None | Some("fast") => PostgresRecycling::Fast,
+        Some("verified") => PostgresRecycling::Verified,
+        Some("clean") => PostgresRecycling::Clean,
+        Some(other) => {
+            return Err(format!(
+                "invalid POSTGRES_RECYCLING_METHOD {:?}, expected fast, verified or clean",
+                other
+            ))
+        }
+    };
+
+    let mode = match get("postgres_ssl_mode").as_deref() {
+        None | Some("disable") => PostgresSslMode::Disable,
+        Some("require") => PostgresSslMode::Require,
+        Some("verify-full") => PostgresSslMode::VerifyFull,
+        Some(other) => {
+            return Err(format!(
+                "invalid POSTGRES_SSL_MODE {:?}, expected disable, require or verify-full",
+                other
+            ))
+        }
+    };
+    let root_cert = get("postgres_ssl_root_cert");
+    let client_cert = match (get("postgres_ssl_cert"), get("postgres_ssl_key")) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => return Err("POSTGRES_SSL_CERT and POSTGRES_SSL_KEY must be set together".to_string()),
+    };
+    let files = root_cert
+        .iter()
+        .chain(client_cert.iter().flat_map(|(cert, key)| [cert, key]));
+    for file in files {
+        if mode == PostgresSslMode::Disable {
+            return Err(
+                "the POSTGRES_SSL_* files need POSTGRES_SSL_MODE require or verify-full"
+                    .to_string(),
+            );
+        }
+        if !Path::new(file).is_file() {
+            return Err(format!("{} is not a file", file));
+        }
+    }
+
+    Ok(Some(PostgresConfig {
+        host,
+        user,
+        password,
+        db,
+        pool_size,
+        connect_timeout: timeout("postgres_connect_timeout_seconds")?,
+        wait_timeout: timeout("postgres_wait_timeout_seconds")?,
+        recycle_timeout: timeout("postgres_recycle_timeout_seconds")?,
+        recycling_method,
+        tls: PostgresTls {
+            mode,
+            root_cert,
+            client_cert,
+        },
+    }))
+}
+
+fn parse_durability_mode(mode: &str, name: &str) -> Result<DurabilityMode, String> {
+    DurabilityMode::parse(mode).ok_or(format!(
+        "invalid {} {:?}, expected none, fsync-per-write or group-commit",
+        name, mode
+    ))
+}
+
+fn parse_value<T: FromStr>(value: Option<String>, name: &str, default: T) -> Result<T, String> {
+    match value {
+        Some(value) => value
+            .trim()
+            .parse::<T>()
+            .map_err(|_| format!("invalid {} {:?}", name, value)),
+        None => Ok(default),
+    }
+}
+
+//Comma separated header names, compared in lowercase
+fn parse_list(value: Option<String>) -> Option<Vec<String>> {
+    value.map(|value| {
+        value
+            .split(',')
+            .map(|item| item.trim().to_lowercase())
+            .filter(|item| !item.is_empty())
+            .collect()
+    })
+}
+
+fn parse_file(content: &str) -> Result<HashMap<String, String>, String> {
+    let table: toml::Table = content
+        .parse()
+        .map_err(|e| format!("invalid config file: {}", e))?;
+
+    let mut values = HashMap::new();
+    for (key, value) in table {
+        if !KEYS.contains(&key.as_str()) {
+            return Err(format!("unknown key {} in config file", key));
+        }
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            _ => return Err(format!("invalid value for {} in config file", key)),
+        };
+        values.insert(key, value);
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_env(vars: &[(&str, &str)]) -> Result<Config, String> {
+        from_file_and_env(None, vars)
+    }
+
+    fn from_file_and_env(file: Option<&str>, vars: &[(&str, &str)]) -> Result<Config, String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::from_sources(file, |key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn create_valid_addr() {
+        let addr = create_addr("127.0.0.1", "5000");
+        assert!(addr.is_ok());
+    }
+
+    #[test]
+    fn create_invalid_addr() {
+        let addr = create_addr("123.456.789", "ab99");
+        assert!(addr.is_err());
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(from_env(&[]), Ok(Config::default()));
+    }
+
+    #[test]
+    fn env_wins_over_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap();
+        let file = format!(
+            "app_port = 6000\nbase_path = \"{}\"\ncompression_level = 9\nwith_logs = false\n",
+            base
+        );
+
+        let config = from_file_and_env(Some(&file), &[("COMPRESSION_LEVEL", "1")]).unwrap();
+        assert_eq!(config.addr.port(), 6000);
+        assert_eq!(config.base_path, base);
+        assert_eq!(config.compression_level, 1);
+        assert!(!config.with_logs);
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(from_env(&[("APP_PORT", "abc")]).is_err());
+        assert!(from_env(&[("BASE_PATH", "/does/not/exist")]).is_err());
+        assert!(from_env(&[("STORAGE_BACKEND", "floppy")]).is_err());
+        assert!(from_env(&[("NODE_ID", "replica-1")]).is_err());
+        assert!(from_env(&[("COMPRESSION_LEVEL", "10")]).is_err());
+        assert!(from_env(&[("WITH_LOGS", "yes")]).is_err());
+        assert!(from_env(&[("POSTGRES_HOST", "localhost")]).is_err());
+        assert!(from_env(&[("POSTGRES_MIGRATE", "maybe")]).is_err());
+        assert!(from_env(&[("WITH_CATALOG", "true")]).is_err());
+        assert!(from_env(&[("S3_REGION", "moon-1")]).is_err());
+        assert!(from_env(&[("LEGACY_PATH", "/does/not/exist")]).is_err());
+        assert!(from_env(&[("SHUTDOWN_TIMEOUT_SECONDS", "0")]).is_err());
+        assert!(from_env(&[("MIN_FREE_BYTES", "-1")]).is_err());
+        assert!(from_env(&[("DISK_HIGH_WATERMARK", "95")]).is_err());
+        assert!(from_env(&[("DISK_CRITICAL_WATERMARK", "101")]).is_err());
+        assert!(from_env(&[("DISK_CHECK_INTERVAL_SECONDS", "0")]).is_err());
+        assert!(from_env(&[("OTEL_EXPORTER", "jaeger")]).is_err());
+        assert!(from_env(&[("OTEL_EXPORTER", "file")]).is_err());
+        assert!(from_env(&[("WITH_DEDUP", "on")]).is_err());
+        assert!(from_env(&[("DURABILITY_MODE", "fsync")]).is_err());
+        assert!(from_env(&[("DURABILITY_MODE_OVERRIDES", "orders")]).is_err());
+        assert!(from_env(&[("DURABILITY_MODE_OVERRIDES", "orders=always")]).is_err());
+        assert!(from_env(&[("GROUP_COMMIT_INTERVAL_MS", "0")]).is_err());
+        assert!(from_env(&[("ROTATION_MAX_BYTES", "-1")]).is_err());
+        assert!(from_env(&[("ROTATION_WINDOW", "weekly")]).is_err());
+        assert!(from_env(&[("IDEMPOTENCY_WINDOW_SECONDS", "0")]).is_err());
+        assert!(from_file_and_env(Some("secret = \"abc\""), &[]).is_err());
+
+        //Memory storage doesn't need a mount
+        let config = from_env(&[
+            ("STORAGE_BACKEND", "memory"),
+            ("BASE_PATH", "/does/not/exist"),
+        ]);
+        assert_eq!(config.unwrap().storage_backend, StorageKind::Memory);
+    }
+
+    #[test]
+    fn node_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap();
+
+        let config = from_env(&[("BASE_PATH", base)]).unwrap();
+        assert_eq!(config.node_id, None);
+        assert_eq!(config.node_id_file, format!("{}/.node-id", base));
+
+        let config = from_env(&[
+            ("BASE_PATH", base),
+            ("NODE_ID", "replica1"),
+            ("NODE_ID_FILE", "/var/lib/crate/node-id"),
+        ])
+        .unwrap();
+        assert_eq!(config.node_id.as_deref(), Some("replica1"));
+        assert_eq!(config.node_id_file, "/var/lib/crate/node-id");
+    }
+
+    #[test]
+    fn write_path_config() {
+        let config = from_env(&[
+            ("WITH_DEDUP", "true"),
+            ("DURABILITY_MODE", "group-commit"),
+            (
+                "DURABILITY_MODE_OVERRIDES",
+                "Orders=fsync-per-write, logs=none",
+            ),
+            ("GROUP_COMMIT_INTERVAL_MS", "25"),
+            ("ROTATION_MAX_BYTES", "1024"),
+            ("ROTATION_WINDOW", "hourly"),
+            ("IDEMPOTENCY_WINDOW_SECONDS", "60"),
+        ])
+        .unwrap();
+        assert!(config.with_dedup);
+        assert_eq!(config.durability_mode, DurabilityMode::GroupCommit);
+        assert_eq!(
+            config.durability_overrides.get("Orders"),
+            Some(&DurabilityMode::FsyncPerWrite)
+        );
+        assert_eq!(
+            config.durability_overrides.get("logs"),
+            Some(&DurabilityMode::None)
+        );
+        assert_eq!(config.group_commit_interval, Duration::from_millis(25));
+        assert_eq!(config.rotation_max_bytes, Some(1024));
+        assert_eq!(config.rotation_window, RotationWindow::Hourly);
+        assert_eq!(config.idempotency_window, Duration::from_secs(60));
+
+        //0 turns size based rotation off
+        let config = from_env(&[("ROTATION_MAX_BYTES", "0")]).unwrap();
+        assert_eq!(config.rotation_max_bytes, None);
+    }
+
+    #[test]
+    fn s3_endpoint_config() {
+        let config = from_env(&[
+            ("S3_ENDPOINT", "http://localhost:9000"),
+            ("S3_REGION", "minio"),
+        ])
+        .unwrap();
+        assert_eq!(config.s3_endpoint.as_deref(), Some("http://localhost:9000"));
+        assert_eq!(config.s3_region.as_deref(), Some("minio"));
+    }
+
+    #[test]
+    fn telemetry_config() {
+        let config = from_env(&[("OTEL_EXPORTER", "otlp")]).unwrap();
+        assert_eq!(
+            config.telemetry,
+            Some(TelemetryConfig {
+                exporter: TelemetryExporter::Otlp("http://localhost:4317".to_string()),
+                service_name: "proxy_cache_aws".to_string(),
+            })
+        );
+
+        let config = from_env(&[
+            ("OTEL_EXPORTER", "file"),
+            ("OTEL_FILE", "/tmp/spans.jsonl"),
+            ("OTEL_SERVICE_NAME", "cache"),
+        ])
+        .unwrap();
+        let telemetry = config.telemetry.unwrap();
+        assert_eq!(
+            telemetry.exporter,
+            TelemetryExporter::File("/tmp/spans.jsonl".to_string())
+        );
+        assert_eq!(telemetry.service_name, "cache");
+    }
+
+    #[test]
+    fn log_headers_config() {
+        let config = from_env(&[
+            ("LOG_HEADERS_ALLOW", "Content-Type, Host,Authorization"),
+            ("LOG_HEADERS_DENY", "X-Tenant-Token,cookie"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.log_headers_allow,
+            Some(vec![
+                "content-type".to_string(),
+                "host".to_string(),
+                "authorization".to_string()
+            ])
+        );
+        //The defaults can't be lifted
+        assert!(config
+            .log_headers_deny
+            .contains(&"authorization".to_string()));
+        assert!(config
+            .log_headers_deny
+            .contains(&"x-tenant-token".to_string()));
+        assert_eq!(config.log_headers_deny.len(), REDACTED_HEADERS.len() + 1);
+    }
+
+    #[test]
+    fn postgres_config() {
+        let config = from_env(&[
+            ("POSTGRES_HOST", "localhost"),
+            ("POSTGRES_USER", "guest"),
+            ("POSTGRES_PASSWORD", "guest"),
+            ("POSTGRES_DB", "test"),
+        ])
+        .unwrap();
+        let pg = config.postgres.unwrap();
+        assert_eq!(pg.pool_size, 16);
+        assert_eq!(pg.wait_timeout, None);
+        assert_eq!(pg.recycling_method, PostgresRecycling::Fast);
+        assert_eq!(pg.tls, PostgresTls::default());
+    }
+
+    #[test]
+    fn postgres_tls_and_pool_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = dir.path().join("ca.pem");
+        fs::write(&ca, "").unwrap();
+        let ca = ca.to_str().unwrap();
+        let connection = [
+            ("POSTGRES_HOST", "db.example.com"),
+            ("POSTGRES_USER", "guest"),
+            ("POSTGRES_PASSWORD", "guest"),
+            ("POSTGRES_DB", "test"),
+        ];
+        let with = |vars: &[(&str, &str)]| from_env(&[&connection[..], vars].concat());
+
+        let pg = with(&[
+            ("POSTGRES_SSL_MODE", "verify-full"),
+            ("POSTGRES_SSL_ROOT_CERT", ca),
+            ("POSTGRES_WAIT_TIMEOUT_SECONDS", "3"),
+            ("POSTGRES_CONNECT_TIMEOUT_SECONDS", "0"),
+            ("POSTGRES_RECYCLING_METHOD", "verified"),
+        ])
+        .unwrap()
+        .postgres
+        .unwrap();
+        assert_eq!(pg.tls.mode, PostgresSslMode::VerifyFull);
+        assert_eq!(pg.tls.root_cert.as_deref(), Some(ca));
+        assert_eq!(pg.wait_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(pg.connect_timeout, None);
+        assert_eq!(pg.recycling_method, PostgresRecycling::Verified);
+
+        assert!(with(&[("POSTGRES_SSL_MODE", "prefer")]).is_err());
+        assert!(with(&[("POSTGRES_RECYCLING_METHOD", "never")]).is_err());
+        assert!(with(&[("POSTGRES_POOL_SIZE", "0")]).is_err());
+        //Certificates without TLS, missing files, a certificate without its key
+        assert!(with(&[("POSTGRES_SSL_ROOT_CERT", ca)]).is_err());
+        assert!(with(&[
+            ("POSTGRES_SSL_MODE", "require"),
+            ("POSTGRES_SSL_ROOT_CERT", "/does/not/exist.pem")
+        ])
+        .is_err());
+        assert!(with(&[("POSTGRES_SSL_MODE", "require"), ("POSTGRES_SSL_CERT", ca)]).is_err());
+    }
+}
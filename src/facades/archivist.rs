@@ -1,17 +1,183 @@
-use serde::{Deserialize, Serialize};
+use chrono::{NaiveDate, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use serde::Serialize;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::path::Path;
+use std::sync::Mutex;
 use tokio::fs::{self, File, OpenOptions};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
-use tracing_subscriber::filter::Directive;
+use tokio::sync::Notify;
+use tracing::{info, warn};
 
+use super::catalog;
 use super::efs_facade::{self, Metadata};
+use super::node::get_node_id;
+use super::postgres_facade::get_client;
+use super::rotation;
 use super::s3::{self};
+use super::scrubber;
+use super::shutdown;
+use deadpool_postgres::Pool;
+use rusoto_s3::S3Client;
+
+lazy_static! {
+    static ref ARCHIVED_FILES: IntCounterVec = register_int_counter_vec!(
+        "archived_files_total",
+        "Number of sealed segments archived to S3",
+        &["result"]
+    )
+    .unwrap();
+    static ref ARCHIVED_BYTES: IntCounter = register_int_counter!(
+        "archived_bytes_total",
+        "Bytes of sealed segments archived to S3"
+    )
+    .unwrap();
+    static ref JOURNAL: Mutex<ArchiveJournal> = Mutex::new(ArchiveJournal::default());
+    //Wakes the archiver when files are queued outside of rotation
+    static ref QUEUED: Notify = Notify::new();
+}
+
+//Results kept in the journal, the oldest are dropped first
+const JOURNAL_SIZE: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JournalEntry {
+    pub file: String,
+    pub node: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub finished_at: String,
+}
+
+//What the sealed archiver has left to do and what it did lately
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ArchiveJournal {
+    pub pending: VecDeque<String>,
+    pub in_progress: Option<String>,
+    pub recent: VecDeque<JournalEntry>,
+}
+
+impl ArchiveJournal {
+    fn start_next(&mut self) -> Option<String> {
+        self.in_progress = self.pending.pop_front();
+        self.in_progress.clone()
+    }
+
+    fn finish(&mut self, file: String, result: Result<(), String>) {
+        self.in_progress = None;
+        if self.recent.len() == JOURNAL_SIZE {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(JournalEntry {
+            file,
+            node: get_node_id().to_string(),
+            error: result.err(),
+            finished_at: Utc::now().to_rfc3339(),
+        });
+    }
+}
+
+pub fn get_journal() -> ArchiveJournal {
+    JOURNAL.lock().unwrap().clone()
+}
+
+//With Postgres the results of every node are kept in "ArchiveJournal", restarts included
+pub async fn insert_result(pool: &Pool, entry: &JournalEntry) -> Result<(), String> {
+    let client = get_client(pool).await?;
+    client
+        .execute(
+            r#"INSERT INTO public."ArchiveJournal" (file, node, error) VALUES ($1, $2, $3)"#,
+            &[&entry.file, &entry.node, &entry.error],
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+//Latest results, oldest first like the in memory journal
+pub async fn read_results(pool: &Pool) -> Result<VecDeque<JournalEntry>, String> {
+    let client = get_client(pool).await?;
+    let rows = client
+        .query(
+            r#"SELECT file, node, error,
+                to_char(finished_at AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US"+00:00"') AS finished_at
+                FROM public."ArchiveJournal" ORDER BY id DESC LIMIT $1"#,
+            &[&(JOURNAL_SIZE as i64)],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(rows
+        .iter()
+        .rev()
+        .map(|row| JournalEntry {
+            file: row.get("file"),
+            node: row.get("node"),
+            error: row.get("error"),
+            finished_at: row.get("finished_at"),
+        })
+        .collect())
+}
+
+//Pending work is always local, the results come from Postgres when there is a pool
+pub async fn read_journal(pool: Option<&Pool>) -> ArchiveJournal {
+    let mut journal = get_journal();
+    if let Some(pool) = pool {
+        match read_results(pool).await {
+            Ok(recent) => journal.recent = recent,
+            Err(err) => warn!(error = %err, "Archive journal not read from Postgres"),
+        }
+    }
+    journal
+}
+
+//Queues a file for the sealed archiver, false if it is already pending or being archived
+pub fn queue_file(file_path: String) -> bool {
+    let mut journal = JOURNAL.lock().unwrap();
+    if journal.pending.contains(&file_path) || journal.in_progress.as_ref() == Some(&file_path) {
+        return false;
+    }
+    journal.pending.push_back(file_path);
+    QUEUED.notify_one();
+    true
+}
+
+//Collection files of a past day, the current day is still being written to
+pub async fn list_day_files(
+    base: &str,
+    date: NaiveDate,
+    collection: Option<&str>,
+) -> Result<Vec<String>, String> {
+    if date >= Utc::now().date_naive() {
+        return Err(format!(
+            "{} is still being written to, only past days can be archived",
+            date
+        ));
+    }
+    let files = efs_facade::list_collection_files(base)
+        .await?
+        .into_iter()
+        .map(|(file, _)| file)
+        .filter(|file| {
+            efs_facade::parse_file_path(file)
+                .map(|(file_collection, _, file_date)| {
+                    file_date == date.to_string()
+                        && collection.map(|c| c == file_collection).unwrap_or(true)
+                })
+                .unwrap_or(false)
+        })
+        .collect();
+    Ok(files)
+}
 
 //Read from EFS and write to an S3 bucket
-pub async fn archive_to_s3(master_directory_path: &str, bucket_name: &str) -> Result<(), String> {
+pub async fn archive_to_s3(
+    master_directory_path: &str,
+    bucket_name: &str,
+    s3_client: S3Client,
+) -> Result<(), String> {
     let directories_list = efs_facade::get_directories_list(master_directory_path).await;
-    let s3_client = s3::init_client();
 
     let directories_list = match directories_list {
         Ok(directories_list) => directories_list,
@@ -25,12 +191,12 @@ pub async fn archive_to_s3(master_directory_path: &str, bucket_name: &str) -> Re
         let file_size = get_file_size(&directory_path.clone()).await;
         let part_size = calculate_part_size(file_size).await;
 
-        let output_file_name = format!("{}", directory);
+        let output_file_name = directory.to_string();
         let output_file_path = format!("{}/{}", directory_path, directory);
 
         if let Ok(_metadata) = fs::metadata(output_file_path.clone()).await {
             match s3::upload_file_multipart(
-                &bucket_name,
+                bucket_name,
                 &output_file_path.clone(),
                 &output_file_name,
                 part_size,
@@ -46,13 +212,13 @@ pub async fn archive_to_s3(master_directory_path: &str, bucket_name: &str) -> Re
                 }
             }
         } else {
-            return Err(format!("Bytes file does not exist"));
+            return Err("Bytes file does not exist".to_string());
         }
 
         let manifest_file_name = format!("{}.manifest", directory);
         let manifest_file_path = format!("{}/{}", directory_path, manifest_file_name);
 
-        if let Ok(metadata) = fs::metadata(manifest_file_path.clone()).await {
+        if let Ok(_metadata) = fs::metadata(manifest_file_path.clone()).await {
             let manifest_bytes = read_from_manifest(&manifest_file_path).await;
 
             let json_manifest_name = format!("{}-manifest.json", directory);
@@ -60,15 +226,11 @@ pub async fn archive_to_s3(master_directory_path: &str, bucket_name: &str) -> Re
 
             let serialized_manifest = serde_json::to_string_pretty(&manifest_bytes.unwrap());
 
-            _ = write_file(
-                &json_manifest_path,
-                &serialized_manifest.unwrap().as_bytes(),
-            )
-            .await;
+            _ = write_file(&json_manifest_path, serialized_manifest.unwrap().as_bytes()).await;
 
             match s3::upload_file_multipart(
                 bucket_name,
-                &&json_manifest_path.clone(),
+                &json_manifest_path.clone(),
                 &json_manifest_name,
                 part_size,
                 s3_client.clone(),
@@ -83,7 +245,7 @@ pub async fn archive_to_s3(master_directory_path: &str, bucket_name: &str) -> Re
                 }
             }
         } else {
-            return Err(format!("Manifest file does not exist"));
+            return Err("Manifest file does not exist".to_string());
         }
 
         let directory_path_for_delete = format!("{}/{}", master_directory_path, directory);
@@ -94,6 +256,131 @@ pub async fn archive_to_s3(master_directory_path: &str, bucket_name: &str) -> Re
     Ok(())
 }
 
+//Archives every segment as soon as rotation seals it, and the files queued with queue_file.
+//On shutdown the file being archived is finished, the ones still queued stay on EFS.
+//With Postgres the results go to the journal table, and with the catalog the archived
+//segments are moved to the S3 tier there too.
+pub fn spawn_sealed_archiver(
+    base: String,
+    bucket_name: String,
+    s3_client: S3Client,
+    pg_pool: Option<Pool>,
+    with_catalog: bool,
+) -> tokio::task::JoinHandle<()> {
+    let catalog = pg_pool.clone().filter(|_| with_catalog);
+    let mut sealed = rotation::subscribe_sealed();
+    tokio::spawn(async move {
+        loop {
+            if shutdown::is_triggered() {
+                break;
+            }
+            //Sealed segments show up in the journal while earlier files are archived
+            while let Ok(file_path) = sealed.try_recv() {
+                queue_file(file_path);
+            }
+            let next = JOURNAL.lock().unwrap().start_next();
+            let file_path = match next {
+                Some(file_path) => file_path,
+                None => {
+                    tokio::select! {
+                        biased;
+                        _ = shutdown::wait() => break,
+                        _ = QUEUED.notified() => {},
+                        file_path = sealed.recv() => match file_path {
+                            Some(file_path) => _ = queue_file(file_path),
+                            None => break,
+                        },
+                    }
+                    continue;
+                }
+            };
+            let result = archive_file(&base, &file_path, &bucket_name, s3_client.clone()).await;
+            match &result {
+                Ok(_) => {
+                    ARCHIVED_FILES.with_label_values(&["ok"]).inc();
+                    info!(file = %file_path, "Sealed segment archived");
+                    if let Some(pool) = &catalog {
+                        catalog::record_archived(pool, &file_path, &file_path).await;
+                    }
+                }
+                Err(err) => {
+                    ARCHIVED_FILES.with_label_values(&["error"]).inc();
+                    warn!(file = %file_path, error = %err, "Archiving sealed segment failed")
+                }
+            }
+            let entry = {
+                let mut journal = JOURNAL.lock().unwrap();
+                journal.finish(file_path, result);
+                journal.recent.back().cloned()
+            };
+            if let (Some(pool), Some(entry)) = (&pg_pool, entry) {
+                if let Err(err) = insert_result(pool, &entry).await {
+                    warn!(file = %entry.file, error = %err, "Archive result not journaled");
+                }
+            }
+        }
+    })
+}
+
+/*Steps
+1. Refuse files with quarantined segments, GET only knows about them while they are on EFS
+2. Upload the data file of the segment under its file path
+3. Upload its manifest as JSON next to it
+4. Delete the local files, reads fall back to S3 from now on
+*/
+pub async fn archive_file(
+    base: &str,
+    file_path: &str,
+    bucket_name: &str,
+    s3_client: S3Client,
+) -> Result<(), String> {
+    let quarantined = scrubber::read_quarantine(base, file_path).await?;
+    if !quarantined.is_empty() {
+        return Err(format!(
+            "{} has {} quarantined segments, it stays on EFS",
+            file_path,
+            quarantined.len()
+        ));
+    }
+    let data_path = format!("{}/{}.gzip", base, file_path);
+
+    let file_size = get_file_size(&data_path).await;
+    let part_size = calculate_part_size(file_size).await;
+    s3::upload_file_multipart(
+        bucket_name,
+        &data_path,
+        file_path,
+        part_size,
+        s3_client.clone(),
+    )
+    .await
+    .map_err(|e| format!("Error uploading to S3: {}", e))?;
+
+    let manifest = efs_facade::read_manifest(base, file_path.to_string())
+        .await?
+        .unwrap_or_default();
+    let json_manifest_name = format!("{}{}", file_path, s3::S3_MANIFEST_SUFFIX);
+    let json_manifest_path = format!("{}/{}", base, json_manifest_name);
+    let serialized_manifest = serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())?;
+    fs::write(&json_manifest_path, serialized_manifest)
+        .await
+        .map_err(|e| e.to_string())?;
+    let uploaded = s3::upload_file(
+        bucket_name,
+        &json_manifest_path,
+        &json_manifest_name,
+        s3_client,
+    )
+    .await
+    .map_err(|e| format!("Error uploading to S3: {}", e));
+    _ = fs::remove_file(&json_manifest_path).await;
+    uploaded?;
+
+    ARCHIVED_BYTES.inc_by(file_size);
+
+    efs_facade::delete_collection_file(base, file_path).await
+}
+
 async fn read_from_manifest(file_path: &str) -> Result<Vec<Metadata>, Box<dyn std::error::Error>> {
     let path = Path::new(file_path);
     let file = File::open(path).await?;
@@ -116,6 +403,8 @@ async fn write_file(file_path: &String, bytes: &[u8]) -> Result<(), Box<dyn Erro
     match output_options.open(file_path).await {
         Ok(mut file) => {
             file.write_all(bytes).await?;
+            //tokio writes in the background, the upload reads the file right after
+            file.flush().await?;
             println!("Bytes were written into file: {}", file_path);
         }
 
@@ -149,14 +438,123 @@ async fn calculate_part_size(file_size: u64) -> usize {
 #[cfg(test)]
 mod archivist_test {
     use super::*;
-    use tokio::fs;
+    use crate::facades::efs_facade::EfsBackend;
+    use crate::facades::fake_s3::FakeS3;
+    use crate::facades::migrations::migrate;
+    use crate::facades::postgres_facade::{create_config, create_pool};
+    use crate::facades::storage::StorageBackend;
+
+    const BUCKET: &str = "archive-bucket";
+
+    fn metadata(start: u64, end: u64) -> Metadata {
+        Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            start,
+            end,
+            String::new(),
+        )
+    }
 
     #[tokio::test]
-    #[ignore = "tests need to be ran with a defined bucket name and directory name"]
     async fn test_archive_to_s3() {
-        let directory_name = "";
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        let master = tempfile::tempdir().unwrap();
+        let master_path = master.path().to_str().unwrap();
+
+        let directory_path = format!("{}/legacy", master_path);
+        fs::create_dir(&directory_path).await.unwrap();
+        fs::write(format!("{}/legacy", directory_path), b"legacy bytes")
+            .await
+            .unwrap();
+        let manifest_line = serde_json::to_string(&metadata(0, 12)).unwrap();
+        fs::write(format!("{}/legacy.manifest", directory_path), manifest_line)
+            .await
+            .unwrap();
+
+        let archivist = archive_to_s3(master_path, BUCKET, fake.client()).await;
+        assert_eq!(archivist, Ok(()));
+
+        assert_eq!(
+            fake.object(BUCKET, "legacy"),
+            Some(b"legacy bytes".to_vec())
+        );
+        let manifest = s3::get_manifest(BUCKET, "legacy", fake.client())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((manifest[0].start, manifest[0].end), (0, 12));
+        assert!(fs::metadata(&directory_path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn archive_to_s3_without_manifest() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        let master = tempfile::tempdir().unwrap();
+        let master_path = master.path().to_str().unwrap();
+
+        let directory_path = format!("{}/legacy", master_path);
+        fs::create_dir(&directory_path).await.unwrap();
+        fs::write(format!("{}/legacy", directory_path), b"legacy bytes")
+            .await
+            .unwrap();
+
+        let archivist = archive_to_s3(master_path, BUCKET, fake.client()).await;
+        assert!(archivist.is_err());
+        //Nothing is deleted until both files are archived
+        assert!(fs::metadata(&directory_path).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn archive_sealed_segment() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap();
+
+        let efs = EfsBackend::new(base);
+        let (file, _, _) = efs
+            .append("archived_collection", vec![7; 20], metadata(0, 0))
+            .await
+            .unwrap();
+
+        archive_file(base, &file, BUCKET, fake.client())
+            .await
+            .unwrap();
+
+        assert_eq!(fake.object(BUCKET, &file), Some(vec![7; 20]));
+        let manifest = s3::get_manifest(BUCKET, &file, fake.client())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((manifest[0].start, manifest[0].end), (0, 20));
+        assert_eq!(efs.read_range(&file, 0, 20).await, Ok(None));
+    }
+
+    #[tokio::test]
+    #[ignore = "postgres"]
+    async fn journal_in_postgres() {
+        let pool = create_pool(create_config("127.0.0.1", "guest", "guest", "test"), 1).unwrap();
+        let mut client = get_client(&pool).await.unwrap();
+        migrate(&mut client).await.unwrap();
+
+        let entry = JournalEntry {
+            file: format!(
+                "journal_collection-node1-2023-08-01_{}",
+                rand::random::<u32>()
+            ),
+            node: "node1".to_string(),
+            error: Some("upload failed".to_string()),
+            finished_at: String::new(),
+        };
+        insert_result(&pool, &entry).await.unwrap();
 
-        let archivist = archive_to_s3(directory_name, "").await;
-        assert!(archivist.is_ok());
+        let journal = read_journal(Some(&pool)).await;
+        let last = journal.recent.back().unwrap();
+        assert_eq!((&last.file, &last.error), (&entry.file, &entry.error));
+        assert!(last.finished_at.ends_with("+00:00"));
     }
 }
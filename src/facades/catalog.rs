@@ -0,0 +1,259 @@
+use deadpool_postgres::{Object, Pool};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::collections::HashMap;
+use tokio_postgres::Row;
+use tracing::{instrument, warn};
+
+use super::efs_facade::{parse_file_path, Metadata};
+use super::postgres_facade::get_client;
+use super::storage::Tier;
+
+lazy_static! {
+    static ref CATALOG_OPERATIONS: IntCounterVec = register_int_counter_vec!(
+        "catalog_operations_total",
+        "Number of segment catalog lookups and writes by result",
+        &["operation", "result"]
+    )
+    .unwrap();
+}
+
+//Where a segment lives, one row of "SegmentIndex" by (file, start)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    pub collection: String,
+    pub date: String,
+    pub file: String,
+    pub start: u64,
+    pub end: u64,
+    pub codec: String,
+    pub checksum: Option<String>,
+    pub tier: Tier,
+    //Object key in S3_BUCKET once archived
+    pub s3_key: Option<String>,
+}
+
+impl CatalogEntry {
+    //start & end are where the backend put the bytes, the metadata is the one sent to it
+    pub fn new(
+        collection: &str,
+        (file, start, end): (&str, u64, u64),
+        meta: &Metadata,
+        tier: Tier,
+    ) -> CatalogEntry {
+        let date = parse_file_path(file)
+            .map(|(_, _, date)| date)
+            .unwrap_or(meta.creation_date.get(..10).unwrap_or_default().to_string());
+        CatalogEntry {
+            collection: collection.to_string(),
+            date,
+            file: file.to_string(),
+            start,
+            end,
+            codec: meta.compression.clone(),
+            checksum: meta.checksum.clone(),
+            tier,
+            s3_key: None,
+        }
+    }
+
+    fn from_row(row: &Row) -> Result<CatalogEntry, String> {
+        let tier: String = row.get("tier");
+        let checksum: String = row.get("checksum");
+        Ok(CatalogEntry {
+            collection: row.get("collection"),
+            date: row.get("date"),
+            file: row.get("file"),
+            start: row.get::<_, i64>("start") as u64,
+            end: row.get::<_, i64>("end") as u64,
+            codec: row.get("codec"),
+            checksum: Some(checksum).filter(|c| !c.is_empty()),
+            tier: Tier::from_label(&tier).ok_or(format!("unknown tier {:?} in catalog", tier))?,
+            s3_key: row.get("s3_key"),
+        })
+    }
+}
+
+fn count<T>(operation: &str, result: &Result<T, String>) {
+    let label = if result.is_ok() { "ok" } else { "error" };
+    CATALOG_OPERATIONS
+        .with_label_values(&[operation, label])
+        .inc();
+}
+
+//A retried write keeps the first row
+#[instrument(name = "catalog.insert", skip_all, fields(file = %entry.file))]
+pub async fn insert(client: &Object, entry: &CatalogEntry) -> Result<(), String> {
+    let result = client
+        .execute(
+            r#"INSERT INTO public."SegmentIndex"
+                (file, start, "end", collection, date, checksum, codec, tier, s3_key)
+                VALUES ($1, $2, $3, $4, ($5::text)::date, $6, $7, $8, $9)
+                ON CONFLICT (file, start) DO NOTHING"#,
+            &[
+                &entry.file,
+                &(entry.start as i64),
+                &(entry.end as i64),
+                &entry.collection,
+                &entry.date,
+                &entry.checksum.clone().unwrap_or_default(),
+                &entry.codec,
+                &entry.tier.label(),
+                &entry.s3_key,
+            ],
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+    count("insert", &result);
+    result
+}
+
+//One indexed lookup, None when the segment was written without the catalog
+#[instrument(name = "catalog.find", skip(client))]
+pub async fn find(
+    client: &Object,
+    file: &str,
+    start: u64,
+    end: u64,
+) -> Result<Option<CatalogEntry>, String> {
+    let result = client
+        .query_opt(
+            r#"SELECT file, start, "end", collection, date::text AS date, checksum, codec, tier, s3_key
+                FROM public."SegmentIndex" WHERE file = $1 AND start = $2 AND "end" = $3"#,
+            &[&file, &(start as i64), &(end as i64)],
+        )
+        .await
+        .map_err(|e| e.to_string());
+    count("find", &result);
+    result?.map(|row| CatalogEntry::from_row(&row)).transpose()
+}
+
+//Every segment of the file now reads from S3, returns how many were moved
+#[instrument(name = "catalog.archived", skip(client))]
+pub async fn mark_archived(client: &Object, file: &str, s3_key: &str) -> Result<u64, String> {
+    let result = client
+        .execute(
+            r#"UPDATE public."SegmentIndex" SET tier = $2, s3_key = $3 WHERE file = $1"#,
+            &[&file, &Tier::S3.label(), &s3_key],
+        )
+        .await
+        .map_err(|e| e.to_string());
+    count("archived", &result);
+    result
+}
+
+//Number of segments of each file, the files without any are left out
+#[instrument(name = "catalog.count", skip_all, fields(files = files.len()))]
+pub async fn count_by_file(
+    client: &Object,
+    files: &[String],
+) -> Result<HashMap<String, usize>, String> {
+    let result = client
+        .query(
+            r#"SELECT file, COUNT(*) AS segments FROM public."SegmentIndex"
+                WHERE file = ANY($1) GROUP BY file"#,
+            &[&files],
+        )
+        .await
+        .map_err(|e| e.to_string());
+    count("count", &result);
+    Ok(result?
+        .iter()
+        .map(|row| (row.get("file"), row.get::<_, i64>("segments") as usize))
+        .collect())
+}
+
+//The catalog is a shortcut, a failure is logged and the caller goes on without it
+pub async fn record_segment(pool: &Pool, entry: &CatalogEntry) {
+    let inserted = match get_client(pool).await {
+        Ok(client) => insert(&client, entry).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = inserted {
+        warn!(file = %entry.file, error = %err, "Segment not recorded in the catalog");
+    }
+}
+
+//Same as record_segment, the segment is still found by looking through the tiers
+pub async fn lookup(pool: &Pool, file: &str, start: u64, end: u64) -> Option<CatalogEntry> {
+    let found = match get_client(pool).await {
+        Ok(client) => find(&client, file, start, end).await,
+        Err(err) => Err(err),
+    };
+    found.unwrap_or_else(|err| {
+        warn!(file = %file, error = %err, "Catalog lookup failed");
+        None
+    })
+}
+
+//Same as lookup, the caller counts another way when it gets None
+pub async fn segment_counts(pool: &Pool, files: &[String]) -> Option<HashMap<String, usize>> {
+    let counted = match get_client(pool).await {
+        Ok(client) => count_by_file(&client, files).await,
+        Err(err) => Err(err),
+    };
+    counted
+        .map_err(|err| warn!(error = %err, "Catalog segment count failed"))
+        .ok()
+}
+
+pub async fn record_archived(pool: &Pool, file: &str, s3_key: &str) {
+    let updated = match get_client(pool).await {
+        Ok(client) => mark_archived(&client, file, s3_key).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = updated {
+        warn!(file = %file, error = %err, "Catalog not updated after archiving");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::facades::migrations::migrate;
+    use crate::facades::postgres_facade::{create_config, create_pool};
+
+    fn metadata() -> Metadata {
+        Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            0,
+            0,
+            "abc".to_string(),
+        )
+    }
+
+    #[test]
+    fn entry_from_metadata() {
+        let file = "catalog_collection-node1-2023-08-01_2";
+        let entry = CatalogEntry::new("catalog_collection", (file, 10, 20), &metadata(), Tier::Efs);
+        assert_eq!(entry.date, "2023-08-01");
+        assert_eq!((entry.start, entry.end), (10, 20));
+        assert_eq!(entry.codec, "gzip");
+        assert_eq!(entry.s3_key, None);
+    }
+
+    #[tokio::test]
+    #[ignore = "postgres"]
+    async fn insert_find_and_archive() {
+        let pool = create_pool(create_config("127.0.0.1", "guest", "guest", "test"), 1).unwrap();
+        let mut client = get_client(&pool).await.unwrap();
+        migrate(&mut client).await.unwrap();
+
+        let file = format!(
+            "catalog_collection-node1-2023-08-01_{}",
+            rand::random::<u32>()
+        );
+        let entry = CatalogEntry::new("catalog_collection", (&file, 0, 20), &metadata(), Tier::Efs);
+        insert(&client, &entry).await.unwrap();
+        assert_eq!(find(&client, &file, 0, 20).await, Ok(Some(entry.clone())));
+        assert_eq!(find(&client, &file, 0, 10).await, Ok(None));
+
+        assert_eq!(mark_archived(&client, &file, &file).await, Ok(1));
+        let archived = find(&client, &file, 0, 20).await.unwrap().unwrap();
+        assert_eq!(archived.tier, Tier::S3);
+        assert_eq!(archived.s3_key, Some(file));
+    }
+}
@@ -4,6 +4,7 @@ use flate2::write::GzEncoder;
 use flate2::read::GzDecoder;
 use prometheus::{register_histogram_vec, register_int_counter_vec, register_int_counter, HistogramVec, IntCounterVec, IntCounter};
 use lazy_static::lazy_static;
+use tracing::instrument;
 
 
 lazy_static! {
@@ -27,22 +28,29 @@ lazy_static! {
 }
 
 pub fn gzip_compress(bytes: Vec<u8>) -> Result<Vec<u8>, String> {
-    // let timer = COMPRESSION_DURATION
-    //     .with_label_values(&["compress"])
-    //     .start_timer();
-    // COMPRESSION_COUNT.with_label_values(&["compress"]).inc();
-    // FILE_SIZE.inc_by(bytes.len() as u64);
+    gzip_compress_level(bytes, Compression::default().level())
+}
+
+//level goes from 0 (no compression) to 9 (best compression)
+#[instrument(name = "compress", skip_all, fields(bytes = bytes.len(), level = level))]
+pub fn gzip_compress_level(bytes: Vec<u8>, level: u32) -> Result<Vec<u8>, String> {
+    let timer = COMPRESSION_DURATION
+        .with_label_values(&["compress"])
+        .start_timer();
+    COMPRESSION_COUNT.with_label_values(&["compress"]).inc();
+    FILE_SIZE.inc_by(bytes.len() as u64);
 
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
     encoder.write_all(&bytes).map_err(|op| op.to_string())?;
     let result = encoder.finish().map_err(|op| op.to_string());
 
-    //timer.observe_duration();
+    timer.observe_duration();
     result
 }
 
 
 //This is only built when we run the unit tests
+#[instrument(name = "decompress", skip_all, fields(bytes = bytes.len()))]
 pub fn gzip_decompress(bytes: Vec<u8>) -> Result<Vec<u8>, String> {
     let timer = COMPRESSION_DURATION
         .with_label_values(&["decompress"])
@@ -0,0 +1,311 @@
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+use super::efs_facade::{get_file_path, parse_file_path};
+use crate::config::{Config, StorageKind};
+
+lazy_static! {
+    static ref DEDUP_HITS: IntCounterVec = register_int_counter_vec!(
+        "dedup_hits_total",
+        "Number of posted payloads that were already stored for the day",
+        &["collection"]
+    )
+    .unwrap();
+    static ref DEDUP_SAVED_BYTES: IntCounterVec = register_int_counter_vec!(
+        "dedup_saved_bytes_total",
+        "Uncompressed bytes that were not written again thanks to deduplication",
+        &["collection"]
+    )
+    .unwrap();
+    //"base/collection" (or "memory/collection") -> index of the current day
+    static ref INDEXES: Mutex<HashMap<String, DayIndex>> = Mutex::new(HashMap::new());
+}
+
+//(file path of the day, hash -> reference)
+type DayIndex = (String, HashMap<String, String>);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    hash: String,
+    reference: String,
+}
+
+//SHA-256 of the uncompressed payload, hex encoded
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+//Only EFS keeps the index on disk, other backends have no base path to write to
+fn get_base(config: &Config) -> Option<&str> {
+    match config.storage_backend {
+        StorageKind::Efs => Some(&config.base_path),
+        _ => None,
+    }
+}
+
+fn get_index_key(config: &Config, collection: &str) -> String {
+    format!("{}/{}", get_base(config).unwrap_or("memory"), collection)
+}
+
+/*Steps
+1. Make sure the index of the current day is loaded (from the .dedup file next to the manifest)
+2. On a new day, delete the .dedup files of the previous days
+3. Look for the hash
+4. Count the saved bytes if it was found
+*/
+pub async fn find_reference(
+    config: &Config,
+    collection: &str,
+    hash: &str,
+    len_bytes: usize,
+) -> Result<Option<String>, String> {
+    let file_path = get_file_path(collection.to_string());
+    let key = get_index_key(config, collection);
+    let mut indexes = INDEXES.lock().await;
+
+    if indexes
+        .get(&key)
+        .map(|(path, _)| path != &file_path)
+        .unwrap_or(true)
+    {
+        let index = match get_base(config) {
+            Some(base) => {
+                prune_old_days(base, &file_path).await?;
+                load_index(base, &file_path).await?
+            }
+            None => HashMap::new(),
+        };
+        indexes.insert(key.clone(), (file_path, index));
+    }
+
+    let reference = indexes
+        .get(&key)
+        .and_then(|(_, index)| index.get(hash).cloned());
+
+    if reference.is_some() {
+        DEDUP_HITS.with_label_values(&[collection]).inc();
+        DEDUP_SAVED_BYTES
+            .with_label_values(&[collection])
+            .inc_by(len_bytes as u64);
+    }
+
+    Ok(reference)
+}
+
+//Remember the reference of a newly stored payload for the rest of the day
+pub async fn record_reference(
+    config: &Config,
+    collection: &str,
+    hash: String,
+    reference: String,
+) -> Result<(), String> {
+    let file_path = get_file_path(collection.to_string());
+    let entry = IndexEntry { hash, reference };
+    let entry_str = format!(
+        "{}\n",
+        serde_json::to_string(&entry).map_err(|e| e.to_string())?
+    );
+
+    let mut indexes = INDEXES.lock().await;
+
+    let base = get_base(config);
+    if let Some(base) = base {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&format!(
+                "{base}/{file_path}.dedup",
+                base = base,
+                file_path = file_path
+            ))
+            .await
+            .map_err(|e| e.to_string())?;
+        file.write_all(entry_str.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let key = get_index_key(config, collection);
+    match indexes.get_mut(&key) {
+        Some((path, index)) if *path == file_path => {
+            index.insert(entry.hash, entry.reference);
+        }
+        _ if base.is_none() => {
+            //Nothing to reload from, start the index of the day with this entry
+            let index = HashMap::from([(entry.hash, entry.reference)]);
+            indexes.insert(key, (file_path, index));
+        }
+        _ => {
+            //The day changed (or nothing was loaded yet), reload from disk on the next lookup
+            indexes.remove(&key);
+        }
+    }
+
+    Ok(())
+}
+
+//Payloads are only deduplicated within their day, older .dedup files of the collection are dead weight
+async fn prune_old_days(base: &str, file_path: &str) -> Result<(), String> {
+    let (collection, node, date) = match parse_file_path(file_path) {
+        Some(parts) => parts,
+        None => return Ok(()),
+    };
+
+    let mut dir = fs::read_dir(base).await.map_err(|e| e.to_string())?;
+    while let Some(entry) = dir.next_entry().await.map_err(|e| e.to_string())? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let old_day = name
+            .strip_suffix(".dedup")
+            .and_then(parse_file_path)
+            .map(|(c, n, d)| c == collection && n == node && d < date)
+            .unwrap_or(false);
+        if old_day {
+            match fs::remove_file(entry.path()).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn load_index(base: &str, file_path: &str) -> Result<HashMap<String, String>, String> {
+    let mut index = HashMap::new();
+
+    match OpenOptions::new()
+        .read(true)
+        .open(&format!("{base}/{}.dedup", file_path, base = base))
+        .await
+    {
+        Ok(file) => {
+            let mut lines = BufReader::new(file).lines();
+            while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+                //A torn last line only costs us one duplicate, skip it
+                if let Ok(entry) = serde_json::from_str::<IndexEntry>(&line) {
+                    index.insert(entry.hash, entry.reference);
+                }
+            }
+            Ok(index)
+        }
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => Ok(index),
+            _ => Err(err.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_bytes_same_hash() {
+        assert_eq!(hash_bytes(b"payload"), hash_bytes(b"payload"));
+        assert_ne!(hash_bytes(b"payload"), hash_bytes(b"payload2"));
+        assert_eq!(hash_bytes(b"").len(), 64);
+    }
+
+    fn efs_config(base: &str) -> Config {
+        Config {
+            base_path: base.to_string(),
+            ..Config::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn find_recorded_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = efs_config(dir.path().to_str().unwrap());
+
+        let collection = "dedup_collection";
+        let hash = hash_bytes(b"payload");
+
+        assert_eq!(
+            find_reference(&config, collection, &hash, 7).await,
+            Ok(None)
+        );
+
+        record_reference(
+            &config,
+            collection,
+            hash.clone(),
+            "file?start=0&end=10".to_string(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            find_reference(&config, collection, &hash, 7).await,
+            Ok(Some("file?start=0&end=10".to_string()))
+        );
+
+        //The index survives a restart
+        INDEXES
+            .lock()
+            .await
+            .remove(&get_index_key(&config, collection));
+        assert_eq!(
+            find_reference(&config, collection, &hash, 7).await,
+            Ok(Some("file?start=0&end=10".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn old_days_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = efs_config(dir.path().to_str().unwrap());
+
+        let collection = "dedup_prune_collection";
+        let (_, node, _) = parse_file_path(&get_file_path(collection.to_string())).unwrap();
+        let old_day = dir
+            .path()
+            .join(format!("{}-{}-2000-01-01.dedup", collection, node));
+        let other_collection = dir
+            .path()
+            .join(format!("dedup_other_collection-{}-2000-01-01.dedup", node));
+        fs::write(&old_day, "").await.unwrap();
+        fs::write(&other_collection, "").await.unwrap();
+
+        let hash = hash_bytes(b"payload");
+        assert_eq!(
+            find_reference(&config, collection, &hash, 7).await,
+            Ok(None)
+        );
+
+        assert!(!old_day.exists());
+        assert!(other_collection.exists());
+    }
+
+    #[tokio::test]
+    async fn index_in_memory_without_base_path() {
+        let config = Config {
+            base_path: "/nonexistent".to_string(),
+            storage_backend: StorageKind::Memory,
+            ..Config::default()
+        };
+
+        let collection = "dedup_memory_collection";
+        let hash = hash_bytes(b"payload");
+        record_reference(
+            &config,
+            collection,
+            hash.clone(),
+            "file?start=0&end=10".to_string(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            find_reference(&config, collection, &hash, 7).await,
+            Ok(Some("file?start=0&end=10".to_string()))
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge, register_int_counter, register_int_gauge, register_int_gauge_vec, Gauge,
+    IntCounter, IntGauge, IntGaugeVec,
+};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Mutex};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use super::archivist;
+use super::efs_facade::{self, DiskUsage};
+use super::rotation;
+use super::shutdown;
+use crate::config::Config;
+use crate::state::SharedConfig;
+
+lazy_static! {
+    static ref FREE_BYTES: IntGauge =
+        register_int_gauge!("efs_free_bytes", "Bytes left on the BASE_PATH filesystem").unwrap();
+    static ref TOTAL_BYTES: IntGauge =
+        register_int_gauge!("efs_total_bytes", "Size of the BASE_PATH filesystem").unwrap();
+    static ref FREE_INODES: IntGauge =
+        register_int_gauge!("efs_free_inodes", "Inodes left on the BASE_PATH filesystem").unwrap();
+    static ref COLLECTION_FILES: IntGauge = register_int_gauge!(
+        "efs_collection_files",
+        "Number of collection files in BASE_PATH"
+    )
+    .unwrap();
+    static ref USAGE_RATIO: Gauge = register_gauge!(
+        "efs_usage_ratio",
+        "Used share of the BASE_PATH filesystem, space or inodes whichever is higher"
+    )
+    .unwrap();
+    static ref PRESSURE_LEVEL: IntGaugeVec = register_int_gauge_vec!(
+        "efs_disk_pressure",
+        "0 normal, 1 above the high watermark, 2 above the critical watermark",
+        &["base"]
+    )
+    .unwrap();
+    static ref EARLY_ARCHIVALS: IntCounter = register_int_counter!(
+        "efs_early_archivals_total",
+        "Number of times open segments were sealed early because of the high watermark"
+    )
+    .unwrap();
+    //Last pressure by base path, writes to a base that was never sampled are accepted
+    static ref PRESSURE: Mutex<HashMap<String, Pressure>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pressure {
+    Normal,
+    //Above the high watermark, segments are archived early
+    High,
+    //Above the critical watermark or below MIN_FREE_BYTES, writes are rejected
+    Critical,
+}
+
+impl Pressure {
+    fn level(&self) -> i64 {
+        match self {
+            Pressure::Normal => 0,
+            Pressure::High => 1,
+            Pressure::Critical => 2,
+        }
+    }
+}
+
+//Used share of the filesystem, the inodes count as much as the space
+pub fn usage_ratio(usage: &DiskUsage) -> f64 {
+    let used = |free: u64, total: u64| match total {
+        0 => 0.0,
+        total => 1.0 - free as f64 / total as f64,
+    };
+    used(usage.free_bytes, usage.total_bytes).max(used(usage.free_files, usage.total_files))
+}
+
+pub fn evaluate(usage: &DiskUsage, config: &Config) -> Pressure {
+    let percent = usage_ratio(usage) * 100.0;
+    if percent >= config.disk_critical_watermark as f64 || usage.free_bytes < config.min_free_bytes
+    {
+        Pressure::Critical
+    } else if percent >= config.disk_high_watermark as f64 {
+        Pressure::High
+    } else {
+        Pressure::Normal
+    }
+}
+
+pub fn get_pressure(base: &str) -> Pressure {
+    PRESSURE
+        .lock()
+        .unwrap()
+        .get(base)
+        .copied()
+        .unwrap_or(Pressure::Normal)
+}
+
+//Returns the previous pressure
+pub fn set_pressure(base: &str, pressure: Pressure) -> Pressure {
+    PRESSURE_LEVEL
+        .with_label_values(&[base])
+        .set(pressure.level());
+    PRESSURE
+        .lock()
+        .unwrap()
+        .insert(base.to_string(), pressure)
+        .unwrap_or(Pressure::Normal)
+}
+
+/*Steps
+1. Measure the free space and inodes of BASE_PATH and count its collection files
+2. Work out the pressure from the watermarks
+3. Seal the open segments when crossing the high watermark, the archiver moves them to S3
+4. For as long as the pressure stays, queue the sealed files still on EFS (the previous days included)
+*/
+pub async fn sample(config: &Config) -> Result<Pressure, String> {
+    let base = &config.base_path;
+    let usage = efs_facade::disk_usage(base)?;
+    FREE_BYTES.set(usage.free_bytes as i64);
+    TOTAL_BYTES.set(usage.total_bytes as i64);
+    FREE_INODES.set(usage.free_files as i64);
+    USAGE_RATIO.set(usage_ratio(&usage));
+    if let Ok(files) = efs_facade::list_collection_files(base).await {
+        COLLECTION_FILES.set(files.len() as i64);
+    }
+
+    let pressure = evaluate(&usage, config);
+    let previous = set_pressure(base, pressure);
+    if pressure != previous {
+        info!(base = %base, pressure = ?pressure, usage = usage_ratio(&usage), "Disk pressure changed");
+    }
+    if pressure == Pressure::Normal {
+        return Ok(pressure);
+    }
+    if config.s3_bucket.is_none() {
+        if previous == Pressure::Normal {
+            warn!(base = %base, pressure = ?pressure, "Disk pressure but no S3_BUCKET to archive to");
+        }
+        return Ok(pressure);
+    }
+
+    if previous == Pressure::Normal {
+        EARLY_ARCHIVALS.inc();
+        let sealed = rotation::seal_segments(base).await;
+        info!(segments = sealed.len(), "Segments sealed early");
+    }
+    //Already pending ones are not queued twice
+    let queued = rotation::list_sealed_files(base)
+        .await?
+        .into_iter()
+        .filter(|file| archivist::queue_file(file.clone()))
+        .count();
+    if queued > 0 {
+        info!(files = queued, "Sealed files queued for archival");
+    }
+    Ok(pressure)
+}
+
+//Samples BASE_PATH every interval until the shutdown, with the config of the moment
+pub fn spawn_disk_monitor(shared: SharedConfig) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let config = shared.get();
+            if let Err(err) = sample(&config).await {
+                warn!(error = %err, "Disk check failed");
+            }
+            tokio::select! {
+                _ = sleep(config.disk_check_interval) => {},
+                _ = shutdown::wait() => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(free_bytes: u64, free_files: u64) -> DiskUsage {
+        DiskUsage {
+            free_bytes,
+            total_bytes: 1000,
+            free_files,
+            total_files: 100,
+        }
+    }
+
+    #[test]
+    fn pressure_from_watermarks() {
+        let config = Config {
+            min_free_bytes: 10,
+            ..Config::default()
+        };
+
+        assert_eq!(evaluate(&usage(500, 50), &config), Pressure::Normal);
+        assert_eq!(evaluate(&usage(150, 50), &config), Pressure::High);
+        assert_eq!(evaluate(&usage(40, 50), &config), Pressure::Critical);
+        //Out of inodes with space left
+        assert_eq!(evaluate(&usage(500, 3), &config), Pressure::Critical);
+
+        let config = Config {
+            min_free_bytes: 600,
+            ..config
+        };
+        assert_eq!(evaluate(&usage(500, 50), &config), Pressure::Critical);
+    }
+
+    #[tokio::test]
+    async fn sample_records_the_pressure() {
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap().to_string();
+        assert_eq!(get_pressure(&base_path), Pressure::Normal);
+
+        let config = Config {
+            base_path: base_path.clone(),
+            min_free_bytes: u64::MAX,
+            ..Config::default()
+        };
+        assert_eq!(sample(&config).await, Ok(Pressure::Critical));
+        assert_eq!(get_pressure(&base_path), Pressure::Critical);
+
+        let config = Config {
+            min_free_bytes: 0,
+            disk_high_watermark: 0,
+            disk_critical_watermark: 100,
+            ..config
+        };
+        assert_eq!(sample(&config).await, Ok(Pressure::High));
+        assert_eq!(
+            PRESSURE_LEVEL.with_label_values(&[&base_path]).get(),
+            Pressure::High.level()
+        );
+    }
+}
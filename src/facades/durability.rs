@@ -0,0 +1,191 @@
+use lazy_static::lazy_static;
+use prometheus::{register_histogram_vec, HistogramVec};
+use std::collections::HashMap;
+use tokio::{
+    fs::File,
+    sync::{watch, Mutex},
+    time::{sleep, Duration},
+};
+
+use crate::config::Config;
+
+lazy_static! {
+    static ref WRITE_DURATION: HistogramVec = register_histogram_vec!(
+        "efs_write_duration_seconds",
+        "Time taken to append to a collection file until it is as durable as the mode requires",
+        &["mode"]
+    )
+    .unwrap();
+    //file path -> batch waiting for its fsync
+    static ref BATCHES: Mutex<HashMap<String, Batch>> = Mutex::new(HashMap::new());
+}
+
+//Result of the fsync of a batch, None until it is done
+type Batch = watch::Receiver<Option<Result<(), String>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DurabilityMode {
+    //Return once the bytes are handed to the OS
+    None,
+    //fsync after every write
+    FsyncPerWrite,
+    //Writes arriving within the same window share one fsync
+    GroupCommit,
+}
+
+impl DurabilityMode {
+    pub fn parse(mode: &str) -> Option<DurabilityMode> {
+        match mode.trim() {
+            "none" => Some(DurabilityMode::None),
+            "fsync-per-write" => Some(DurabilityMode::FsyncPerWrite),
+            "group-commit" => Some(DurabilityMode::GroupCommit),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            DurabilityMode::None => "none",
+            DurabilityMode::FsyncPerWrite => "fsync-per-write",
+            DurabilityMode::GroupCommit => "group-commit",
+        }
+    }
+}
+
+//Mode of a collection, its DURABILITY_MODE_OVERRIDES entry or DURABILITY_MODE
+pub fn get_mode(config: &Config, collection: &str) -> DurabilityMode {
+    config
+        .durability_overrides
+        .get(collection)
+        .copied()
+        .unwrap_or(config.durability_mode)
+}
+
+pub fn start_timer(mode: DurabilityMode) -> prometheus::HistogramTimer {
+    WRITE_DURATION
+        .with_label_values(&[mode.label()])
+        .start_timer()
+}
+
+//Returns once what was written to the files is durable according to the mode.
+//The files of one write (data and manifest) are flushed together, in the batch of file_path.
+pub async fn sync(
+    files: &[&File],
+    file_path: &str,
+    mode: DurabilityMode,
+    group_commit_interval: Duration,
+) -> Result<(), String> {
+    match mode {
+        DurabilityMode::None => Ok(()),
+        DurabilityMode::FsyncPerWrite => sync_files(files).await,
+        DurabilityMode::GroupCommit => group_commit(files, file_path, group_commit_interval).await,
+    }
+}
+
+async fn sync_files(files: &[&File]) -> Result<(), String> {
+    for file in files {
+        file.sync_data().await.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/*Steps
+1. Join the batch of the file, or open one if there is none
+2. The writer that opened the batch waits for the window, closes it and fsyncs its files once
+3. Everybody in the batch gets the result of that fsync
+*/
+async fn group_commit(files: &[&File], file_path: &str, interval: Duration) -> Result<(), String> {
+    let mut batches = BATCHES.lock().await;
+
+    if let Some(batch) = batches.get(file_path) {
+        let mut batch = batch.clone();
+        drop(batches);
+        return wait_for_batch(&mut batch).await;
+    }
+
+    let (sender, receiver) = watch::channel(None);
+    batches.insert(file_path.to_string(), receiver);
+    drop(batches);
+
+    sleep(interval).await;
+
+    //Writes that come after this point go to the next batch
+    BATCHES.lock().await.remove(file_path);
+
+    //fsync applies to the whole file, not only what went through this handle
+    let res = sync_files(files).await;
+    sender.send_replace(Some(res.clone()));
+    res
+}
+
+async fn wait_for_batch(batch: &mut Batch) -> Result<(), String> {
+    loop {
+        if let Some(res) = batch.borrow().as_ref() {
+            return res.clone();
+        }
+        if batch.changed().await.is_err() {
+            return Err("group commit was interrupted".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn mode_by_collection() {
+        let config = Config {
+            durability_mode: DurabilityMode::GroupCommit,
+            durability_overrides: HashMap::from([
+                ("a".to_string(), DurabilityMode::FsyncPerWrite),
+                ("b".to_string(), DurabilityMode::None),
+            ]),
+            ..Config::default()
+        };
+        assert_eq!(get_mode(&config, "a"), DurabilityMode::FsyncPerWrite);
+        assert_eq!(get_mode(&config, "b"), DurabilityMode::None);
+        assert_eq!(get_mode(&config, "c"), DurabilityMode::GroupCommit);
+        assert_eq!(get_mode(&Config::default(), "a"), DurabilityMode::None);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writers_share_a_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("group.gzip");
+        let manifest_path = dir.path().join("group.manifest");
+        let path_str = path.to_str().unwrap().to_string();
+
+        let open = |path: std::path::PathBuf| async move {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&path)
+                .await
+                .unwrap()
+        };
+        let mut handles = Vec::new();
+        for i in 0..8u8 {
+            let (path, manifest_path) = (path.clone(), manifest_path.clone());
+            let path_str = path_str.clone();
+            handles.push(tokio::spawn(async move {
+                let mut file = open(path).await;
+                file.write_all(&[i; 16]).await.unwrap();
+                let mut manifest = open(manifest_path).await;
+                manifest.write_all(&[i; 4]).await.unwrap();
+                let interval = Duration::from_millis(10);
+                //Data and manifest wait for the same window
+                let files = [&file, &manifest];
+                sync(&files, &path_str, DurabilityMode::GroupCommit, interval).await
+            }));
+        }
+
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), Ok(()));
+        }
+        assert!(BATCHES.lock().await.get(&path_str).is_none());
+        assert_eq!(std::fs::read(&path).unwrap().len(), 8 * 16);
+        assert_eq!(std::fs::read(&manifest_path).unwrap().len(), 8 * 4);
+    }
+}
@@ -1,14 +1,40 @@
-use chrono::{Datelike, Utc};
+use async_trait::async_trait;
+use chrono::{Datelike, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, register_int_counter_vec, Histogram, IntCounterVec};
 use serde::{Deserialize, Serialize};
-use std::{alloc::System, env, process::id};
+
+use super::durability;
+use super::node::{get_node_id, is_valid_node_id};
+use super::rotation::{self, SegmentGuard};
+use super::storage::{StorageBackend, Tier};
+use crate::config::Config;
+use crate::state::SharedConfig;
 use tokio::{
-    fs::{self, OpenOptions},
-    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    fs::{self, File, OpenOptions},
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
 };
+use tracing::instrument;
+
+//Prefix of the append errors caused by a full filesystem
+pub const DISK_FULL: &str = "disk full";
 
-use super::compression;
+//Append latency is efs_write_duration_seconds, measured by durability
+lazy_static! {
+    static ref EFS_READ_DURATION: Histogram = register_histogram!(
+        "efs_read_duration_seconds",
+        "Time taken to read a byte range of a collection file"
+    )
+    .unwrap();
+    static ref EFS_BYTES: IntCounterVec = register_int_counter_vec!(
+        "efs_bytes_total",
+        "Bytes appended to or read from collection files",
+        &["operation"]
+    )
+    .unwrap();
+}
 
-fn get_current_date() -> String {
+pub fn get_current_date() -> String {
     let current_date = Utc::now();
     format!(
         "{:04}-{:02}-{:02}",
@@ -18,15 +44,71 @@ fn get_current_date() -> String {
     )
 }
 
+//File path of a collection for the current day on this node. Rotated segments add a _N suffix to it.
 pub fn get_file_path(collection: String) -> String {
     format!(
-        "{collection}-{pid}-{date}",
+        "{collection}-{node}-{date}",
         collection = collection,
-        pid = id(),
+        node = get_node_id(),
         date = get_current_date()
     )
 }
 
+//Splits a segment file path into the file path of the day and the segment number
+pub fn split_segment(file_path: &str) -> (&str, u32) {
+    if let Some((day_path, segment)) = file_path.rsplit_once('_') {
+        let is_day_path = day_path.len() >= 10
+            && day_path.is_char_boundary(day_path.len() - 10)
+            && NaiveDate::parse_from_str(&day_path[day_path.len() - 10..], "%Y-%m-%d").is_ok();
+        if let (true, Ok(segment)) = (is_day_path, segment.parse::<u32>()) {
+            return (day_path, segment);
+        }
+    }
+    (file_path, 0)
+}
+
+pub fn get_segment_number(file_path: &str) -> u32 {
+    split_segment(file_path).1
+}
+
+//Splits a file path created by get_file_path back into (collection, node, date).
+//Files written before node IDs have the process id as node.
+pub fn parse_file_path(file_path: &str) -> Option<(String, String, String)> {
+    let (file_path, _) = split_segment(file_path);
+    if file_path.len() < 11 || !file_path.is_char_boundary(file_path.len() - 11) {
+        return None;
+    }
+    let (rest, date) = file_path.split_at(file_path.len() - 11);
+    let date = date.strip_prefix('-')?;
+    let (collection, node) = rest.rsplit_once('-')?;
+
+    if collection.is_empty() || !is_valid_node_id(node) {
+        return None;
+    }
+
+    Some((collection.to_string(), node.to_string(), date.to_string()))
+}
+
+//Lists every collection file (without extension) in base with its size in bytes
+pub async fn list_collection_files(base: &str) -> Result<Vec<(String, u64)>, String> {
+    let mut files = Vec::new();
+
+    let mut dir = fs::read_dir(base).await.map_err(|err| err.to_string())?;
+
+    while let Some(entry) = dir.next_entry().await.map_err(|err| err.to_string())? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(file_path) = name.strip_suffix(".gzip") {
+            let metadata = entry.metadata().await.map_err(|err| err.to_string())?;
+            if metadata.is_file() {
+                files.push((file_path.to_string(), metadata.len()));
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
 pub async fn get_directories_list(directory_path: &str) -> Result<Vec<String>, String> {
     let mut directories = Vec::new();
 
@@ -48,44 +130,154 @@ pub async fn get_directories_list(directory_path: &str) -> Result<Vec<String>, S
     Ok(directories)
 }
 
-pub async fn append_bytes_collection(
+//Creates a directory and its parents, an existing one is fine
+pub async fn create_dir(directory_path: &str) -> Result<(), String> {
+    fs::create_dir_all(directory_path)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+//Deletes a directory and everything in it, a missing one is fine
+pub async fn delete_dir(directory_path: &str) -> Result<(), String> {
+    match fs::remove_dir_all(directory_path).await {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+//Space and inodes left on the filesystem holding a directory
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DiskUsage {
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+    pub free_files: u64,
+    pub total_files: u64,
+}
+
+//The statvfs field widths differ between platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn disk_usage(directory_path: &str) -> Result<DiskUsage, String> {
+    let path = std::ffi::CString::new(directory_path).map_err(|err| err.to_string())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!(
+            "unable to stat {}: {}",
+            directory_path,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(DiskUsage {
+        free_bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total_bytes: stat.f_blocks as u64 * stat.f_frsize as u64,
+        free_files: stat.f_favail as u64,
+        total_files: stat.f_files as u64,
+    })
+}
+
+//Writes and deletes a probe file, a read-only or full mount fails here before a payload does
+pub async fn check_writable(directory_path: &str) -> Result<(), String> {
+    let probe = format!("{}/.health-{}", directory_path, get_node_id());
+    fs::write(&probe, b"ok")
+        .await
+        .map_err(|err| format!("unable to write {}: {}", probe, err))?;
+    fs::remove_file(&probe)
+        .await
+        .map_err(|err| format!("unable to delete {}: {}", probe, err))
+}
+
+//Appends only the data, like a crash before the manifest entry. Writes go through append_segment.
+#[cfg(test)]
+pub(crate) async fn append_bytes_collection(
+    config: &Config,
+    collection: String,
+    bytes: Vec<u8>,
+) -> Result<(String, u64, u64), String> {
+    let mode = durability::get_mode(config, &collection);
+    let _timer = durability::start_timer(mode);
+    let segment = rotation::acquire_segment(config, &collection).await?;
+    let (file, written) = append_to_segment(config, &collection, &segment, bytes).await?;
+    let full_path = format!("{}/{}.gzip", config.base_path, written.0);
+    durability::sync(&[&file], &full_path, mode, config.group_commit_interval).await?;
+    Ok(written)
+}
+
+/*Steps
+1. Append the bytes and their manifest entry to the same segment, it can't rotate in between
+2. Make both durable at once, a write doesn't wait for a group commit window twice
+*/
+pub async fn append_segment(
+    config: &Config,
     collection: String,
     bytes: Vec<u8>,
+    create_metadata: impl FnOnce(u64, u64) -> Metadata,
 ) -> Result<(String, u64, u64), String> {
-    let file_path = get_file_path(collection);
-    let base = env::var("BASE_PATH").unwrap_or('/'.to_string()).to_string();
+    let mode = durability::get_mode(config, &collection);
+    let _timer = durability::start_timer(mode);
+    let segment = rotation::acquire_segment(config, &collection).await?;
+    let (file, (file_path, start, end)) =
+        append_to_segment(config, &collection, &segment, bytes).await?;
+    let full_path = format!("{}/{}.gzip", config.base_path, file_path);
+    let manifest = append_metadata(config, &file_path, create_metadata(start, end)).await?;
+    durability::sync(
+        &[&file, &manifest],
+        &full_path,
+        mode,
+        config.group_commit_interval,
+    )
+    .await?;
+    Ok((file_path, start, end))
+}
+
+//Returns the open data file so the caller syncs it once it is done writing
+#[instrument(name = "efs.append", skip_all, fields(collection = %collection, bytes = bytes.len()))]
+async fn append_to_segment(
+    config: &Config,
+    collection: &str,
+    segment: &SegmentGuard,
+    bytes: Vec<u8>,
+) -> Result<(File, (String, u64, u64)), String> {
+    let file_path = segment.file_path();
+    let full_path = format!(
+        "{base}/{file_path}.gzip",
+        base = config.base_path,
+        file_path = file_path
+    );
     //We append to a file. If file doesn't exists, we create it.
     match OpenOptions::new()
         .append(true)
         .create(true)
-        .open(&format!(
-            "{base}/{file_path}.gzip",
-            base = base,
-            file_path = file_path
-        ))
+        .open(&full_path)
         .await
     {
         Ok(mut file) => {
             file.write_all(bytes.as_slice())
                 .await
-                .map_err(|e| e.to_string())?;
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::StorageFull => format!("{}: {}", DISK_FULL, e),
+                    _ => e.to_string(),
+                })?;
             let after_size = file.seek(io::SeekFrom::Current(0)).await.unwrap();
             let before_size = after_size - bytes.len() as u64;
             //println!("BEFORE => {}\nAFTER=> {}", before_size, after_size);
-            Ok((file_path, before_size, after_size))
+            segment.record_size(after_size);
+            EFS_BYTES
+                .with_label_values(&["append"])
+                .inc_by(bytes.len() as u64);
+            Ok((file, (file_path, before_size, after_size)))
         }
-        Err(error) => match error.kind() {
-            _ => Err(error.to_string()),
-        },
+        Err(error) => Err(error.to_string()),
     }
 }
 
+#[instrument(name = "efs.read", skip(base))]
 pub async fn get_collection_byte_range(
+    base: &str,
     file_path: String,
     start: u64,
     end: u64,
 ) -> Result<Option<Vec<u8>>, String> {
-    let base = env::var("BASE_PATH").unwrap_or('/'.to_string()).to_string();
+    let _timer = EFS_READ_DURATION.start_timer();
     match OpenOptions::new()
         .read(true)
         .open(&format!("{base}/{}.gzip", file_path, base = base))
@@ -101,6 +293,9 @@ pub async fn get_collection_byte_range(
                 .read_to_end(&mut buffer)
                 .await
                 .map_err(|e| e.to_string())?;
+            EFS_BYTES
+                .with_label_values(&["read"])
+                .inc_by(buffer.len() as u64);
             Ok(Some(buffer))
         }
         Err(err) => match err.kind() {
@@ -110,14 +305,17 @@ pub async fn get_collection_byte_range(
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Metadata {
-    creation_date: String,
-    content_type: String,
-    compression: String,
-    source: String,
-    start: u64,
-    end: u64,
+    pub creation_date: String,
+    pub content_type: String,
+    pub compression: String,
+    pub source: String,
+    pub start: u64,
+    pub end: u64,
+    //Entries written before checksums were introduced don't have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
 }
 
 impl Metadata {
@@ -127,43 +325,376 @@ impl Metadata {
         source: String,
         start: u64,
         end: u64,
+        checksum: String,
     ) -> Metadata {
         Metadata {
             creation_date: Utc::now().format("%Y-%m-%d %H:%M:%S UTC").to_string(),
-            content_type: content_type,
-            compression: compression,
-            source: source,
-            start: start,
-            end: end,
+            content_type,
+            compression,
+            source,
+            start,
+            end,
+            checksum: Some(checksum),
         }
     }
 }
 
-pub async fn write_metadata(collection: String, meta: Metadata) -> Result<(), String> {
-    let file_path = get_file_path(collection);
-    let base = env::var("BASE_PATH").unwrap_or('/'.to_string()).to_string();
+//Appends only a manifest entry, to point it at bytes that aren't there
+#[cfg(test)]
+pub(crate) async fn write_metadata(
+    config: &Config,
+    file_path: String,
+    meta: Metadata,
+) -> Result<(), String> {
+    let collection = parse_file_path(&file_path)
+        .map(|(collection, _, _)| collection)
+        .unwrap_or_default();
+    let mode = durability::get_mode(config, &collection);
+    let file = append_metadata(config, &file_path, meta).await?;
+    let full_path = format!("{}/{}.manifest", config.base_path, file_path);
+    durability::sync(&[&file], &full_path, mode, config.group_commit_interval).await
+}
+
+//Appends the entry without syncing, returns the open manifest
+async fn append_metadata(config: &Config, file_path: &str, meta: Metadata) -> Result<File, String> {
     let meta_str = format!(
         "{}\n",
         serde_json::to_string(&meta).map_err(|e| e.to_string())?
     );
 
     //We append to a file. If file doesn't exists, we create it.
+    let full_path = format!(
+        "{base}/{file_path}.manifest",
+        base = config.base_path,
+        file_path = file_path
+    );
     match OpenOptions::new()
         .append(true)
         .create(true)
-        .open(&format!(
-            "{base}/{file_path}.manifest",
-            base = base,
-            file_path = file_path
-        ))
+        .open(&full_path)
         .await
     {
         Ok(mut file) => {
             file.write_all(meta_str.as_bytes())
                 .await
                 .map_err(|e| e.to_string())?;
-            Ok(())
+            file.flush().await.map_err(|e| e.to_string())?;
+            Ok(file)
         }
         Err(e) => Err(e.to_string()),
     }
 }
+
+pub async fn read_manifest(base: &str, file_path: String) -> Result<Option<Vec<Metadata>>, String> {
+    match OpenOptions::new()
+        .read(true)
+        .open(&format!("{base}/{}.manifest", file_path, base = base))
+        .await
+    {
+        Ok(file) => {
+            let mut segments = Vec::new();
+            let mut lines = BufReader::new(file).lines();
+            while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let meta: Metadata = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+                segments.push(meta);
+            }
+            Ok(Some(segments))
+        }
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => Ok(None),
+            _ => Err(err.to_string()),
+        },
+    }
+}
+
+//fsyncs the data file and the manifest of a collection file, whatever the durability mode
+pub async fn sync_collection_file(base: &str, file_path: &str) -> Result<(), String> {
+    for extension in ["gzip", "manifest"] {
+        match OpenOptions::new()
+            .read(true)
+            .open(format!("{}/{}.{}", base, file_path, extension))
+            .await
+        {
+            Ok(file) => file.sync_all().await.map_err(|e| e.to_string())?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    Ok(())
+}
+
+//Deletes the data file of a collection and everything written next to it
+pub async fn delete_collection_file(base: &str, file_path: &str) -> Result<(), String> {
+    for extension in ["gzip", "manifest", "quarantine"] {
+        match fs::remove_file(format!("{}/{}.{}", base, file_path, extension)).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    Ok(())
+}
+
+//Collection files on a local directory or an EFS mount
+pub struct EfsBackend {
+    config: SharedConfig,
+}
+
+impl EfsBackend {
+    //Default write settings, a directory is all the tools and tests need
+    pub fn new(base_path: impl Into<String>) -> EfsBackend {
+        EfsBackend::with_config(SharedConfig::new(Config {
+            base_path: base_path.into(),
+            ..Config::default()
+        }))
+    }
+
+    //Follows the running config, a reload applies from the next append
+    pub fn with_config(config: SharedConfig) -> EfsBackend {
+        EfsBackend { config }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for EfsBackend {
+    fn tier(&self) -> Tier {
+        Tier::Efs
+    }
+
+    async fn append(
+        &self,
+        collection: &str,
+        bytes: Vec<u8>,
+        meta: Metadata,
+    ) -> Result<(String, u64, u64), String> {
+        append_segment(
+            &self.config.get(),
+            collection.to_string(),
+            bytes,
+            |start, end| Metadata { start, end, ..meta },
+        )
+        .await
+    }
+
+    async fn read_range(
+        &self,
+        file_path: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let base = &self.config.get().base_path;
+        get_collection_byte_range(base, file_path.to_string(), start, end).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, String> {
+        let mut files = list_collection_files(&self.config.get().base_path).await?;
+        files.retain(|(file, _)| file.starts_with(prefix));
+        Ok(files)
+    }
+
+    async fn delete(&self, file_path: &str) -> Result<(), String> {
+        delete_collection_file(&self.config.get().base_path, file_path).await
+    }
+
+    async fn metadata(&self, file_path: &str) -> Result<Option<Vec<Metadata>>, String> {
+        read_manifest(&self.config.get().base_path, file_path.to_string()).await
+    }
+
+    //One manifest line per segment, counted without parsing them
+    async fn count_segments(&self, file_path: &str) -> Result<Option<usize>, String> {
+        let path = format!("{}/{}.manifest", self.config.get().base_path, file_path);
+        match fs::read(&path).await {
+            Ok(manifest) => Ok(Some(manifest.iter().filter(|b| **b == b'\n').count())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn parse_valid_file_path() {
+        let parsed = parse_file_path("my-collection-42-2023-08-01");
+        assert_eq!(
+            parsed,
+            Some((
+                "my-collection".to_string(),
+                "42".to_string(),
+                "2023-08-01".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_node_file_path() {
+        let parsed = parse_file_path("my-collection-3f2a9c1b-2023-08-01");
+        assert_eq!(
+            parsed,
+            Some((
+                "my-collection".to_string(),
+                "3f2a9c1b".to_string(),
+                "2023-08-01".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_file_path_round_trip() {
+        let file_path = get_file_path("collection".to_string());
+        let (collection, node, date) = parse_file_path(&file_path).unwrap();
+        assert_eq!(collection, "collection");
+        assert_eq!(node, get_node_id());
+        assert_eq!(date, get_current_date());
+    }
+
+    #[test]
+    fn read_manifest_without_checksum() {
+        let line = r#"{"creation_date":"2023-08-01 00:00:00 UTC","content_type":"text/plain","compression":"gzip","source":"localhost","start":0,"end":10}"#;
+        let meta: Metadata = serde_json::from_str(line).unwrap();
+        assert_eq!(meta.checksum, None);
+        assert!(!serde_json::to_string(&meta).unwrap().contains("checksum"));
+    }
+
+    #[test]
+    fn parse_segment_file_path() {
+        let parsed = parse_file_path("my_collection-42-2023-08-01_12");
+        assert_eq!(
+            parsed,
+            Some((
+                "my_collection".to_string(),
+                "42".to_string(),
+                "2023-08-01".to_string()
+            ))
+        );
+        assert_eq!(get_segment_number("my_collection-42-2023-08-01_12"), 12);
+        assert_eq!(get_segment_number("my_collection-42-2023-08-01"), 0);
+        assert_eq!(get_segment_number("my_1"), 0);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            base_path: dir.path().to_str().unwrap().to_string(),
+            rotation_max_bytes: Some(30),
+            ..Config::default()
+        };
+        let base = config.base_path.as_str();
+        let mut sealed = rotation::subscribe_sealed();
+
+        let collection = "rotation_collection".to_string();
+        let mut files = Vec::new();
+        for i in 0..5u8 {
+            let (file, _, _) =
+                append_segment(&config, collection.clone(), vec![i; 20], |start, end| {
+                    Metadata::new(
+                        "text/plain".to_string(),
+                        "gzip".to_string(),
+                        "localhost".to_string(),
+                        start,
+                        end,
+                        String::new(),
+                    )
+                })
+                .await
+                .unwrap();
+            files.push(file);
+        }
+
+        let day_path = get_file_path(collection.clone());
+        assert_eq!(files[0], day_path);
+        assert_eq!(files[1], day_path);
+        assert_eq!(files[2], format!("{}_1", day_path));
+        assert_eq!(files[3], format!("{}_1", day_path));
+        assert_eq!(files[4], format!("{}_2", day_path));
+
+        assert_eq!(sealed.recv().await, Some(day_path.clone()));
+        assert_eq!(sealed.recv().await, Some(format!("{}_1", day_path)));
+        assert_eq!(
+            read_manifest(base, day_path).await.unwrap().unwrap().len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn disk_usage_and_writable() {
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap();
+
+        let usage = disk_usage(base_path).unwrap();
+        assert!(usage.free_bytes <= usage.total_bytes);
+        assert!(usage.total_bytes > 0);
+        assert!(disk_usage(&format!("{}/missing", base_path)).is_err());
+
+        assert_eq!(check_writable(base_path).await, Ok(()));
+        //The probe doesn't stay behind
+        assert_eq!(std::fs::read_dir(base_path).unwrap().count(), 0);
+        assert!(check_writable(&format!("{}/missing", base_path))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn create_list_and_delete_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap();
+
+        for name in ["test3", "test1", "test2/nested"] {
+            create_dir(&format!("{}/{}", base, name)).await.unwrap();
+        }
+        assert!(create_dir(&format!("{}/test1", base)).await.is_ok());
+
+        let mut directories = get_directories_list(base).await.unwrap();
+        directories.sort();
+        assert_eq!(directories, vec!["test1", "test2", "test3"]);
+
+        delete_dir(&format!("{}/test2", base)).await.unwrap();
+        assert!(delete_dir(&format!("{}/test2", base)).await.is_ok());
+        assert_eq!(get_directories_list(base).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn delete_collection_file_and_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap();
+        let backend = EfsBackend::new(base);
+        let meta = Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            0,
+            0,
+            String::new(),
+        );
+
+        let (file, _, _) = backend
+            .append("delete_collection", vec![1; 10], meta)
+            .await
+            .unwrap();
+        assert_eq!(list_collection_files(base).await.unwrap().len(), 1);
+        assert_eq!(backend.list("delete_collection-").await.unwrap().len(), 1);
+        assert!(backend.list("other-").await.unwrap().is_empty());
+        assert_eq!(backend.count_segments(&file).await, Ok(Some(1)));
+
+        delete_collection_file(base, &file).await.unwrap();
+        assert_eq!(backend.count_segments(&file).await, Ok(None));
+        assert!(list_collection_files(base).await.unwrap().is_empty());
+        assert_eq!(read_manifest(base, file.clone()).await, Ok(None));
+        assert!(delete_collection_file(base, &file).await.is_ok());
+    }
+
+    #[test]
+    fn parse_invalid_file_path() {
+        assert!(parse_file_path("2023-08-01").is_none());
+        assert!(parse_file_path("collection-a.b-2023-08-01").is_none());
+        assert!(parse_file_path("collection").is_none());
+    }
+}
@@ -0,0 +1,329 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tokio::{
+    fs,
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+use crate::config::Config;
+
+use super::efs_facade::{append_segment, create_dir, delete_dir, get_directories_list, Metadata};
+use super::integrity;
+
+//File next to the collection files mapping every legacy reference to its new one
+pub const LEGACY_REFERENCES_FILE: &str = ".legacy-references";
+
+lazy_static! {
+    //base -> legacy reference -> new reference, loaded on the first legacy GET
+    static ref REFERENCES: Mutex<HashMap<String, Arc<HashMap<String, String>>>> =
+        Mutex::new(HashMap::new());
+}
+
+//Manifest written by the old facade next to each {start}-{end} data file.
+//Its i64 offsets repeat the file name, so only the rest is kept.
+#[derive(Debug, Deserialize)]
+struct LegacyMetadata {
+    compression: String,
+    creation_date: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MigratedReference {
+    pub legacy: String,
+    pub reference: String,
+}
+
+//Old archives are one directory per collection with a {start}-{end} file per payload
+fn parse_legacy_segment(name: &str) -> Option<(i64, i64)> {
+    let (start, end) = name.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?))
+}
+
+/*Steps
+1. Find the archive directories of the old format in legacy_path
+2. Append every payload of an archive to its collection file in base, in offset order
+3. Record the legacy reference -> new reference mapping and fsync it
+4. Delete each legacy payload once its mapping is durable, then its directory
+*/
+pub async fn migrate_legacy_archives(
+    legacy_path: &str,
+    config: &Config,
+) -> Result<Vec<MigratedReference>, String> {
+    let base = config.base_path.as_str();
+    create_dir(base).await?;
+    let mut migrated = Vec::new();
+    //Mapped by a run that crashed before deleting them
+    let recorded = load_references(base).await?;
+
+    let mut archives = get_directories_list(legacy_path).await?;
+    archives.sort();
+    for archive in archives {
+        let archive_path = format!("{}/{}", legacy_path, archive);
+        let mut segments = Vec::new();
+        let mut entries = fs::read_dir(&archive_path)
+            .await
+            .map_err(|e| e.to_string())?;
+        while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(range) = parse_legacy_segment(&name) {
+                segments.push((range, name));
+            }
+        }
+
+        //Not an archive of the old facade
+        if segments.is_empty() {
+            continue;
+        }
+        segments.sort();
+
+        for ((legacy_start, legacy_end), name) in segments {
+            let legacy = format!("{}?start={}&end={}", archive, legacy_start, legacy_end);
+            if !recorded.contains_key(&legacy) {
+                let reference = migrate_segment(config, &archive, &archive_path, &name).await?;
+                info!(legacy = %legacy, reference = %reference, "Legacy payload migrated");
+                record_reference(base, &legacy, &reference).await?;
+                migrated.push(MigratedReference { legacy, reference });
+            }
+
+            let data_path = format!("{}/{}", archive_path, name);
+            for path in [data_path.clone(), format!("{}.manifest", data_path)] {
+                match fs::remove_file(&path).await {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.to_string()),
+                }
+            }
+        }
+
+        delete_dir(&archive_path).await?;
+    }
+
+    Ok(migrated)
+}
+
+async fn migrate_segment(
+    config: &Config,
+    collection: &str,
+    archive_path: &str,
+    name: &str,
+) -> Result<String, String> {
+    let data_path = format!("{}/{}", archive_path, name);
+    let manifest_path = format!("{}.manifest", data_path);
+
+    let bytes = fs::read(&data_path).await.map_err(|e| e.to_string())?;
+    let legacy_meta = match fs::read_to_string(&manifest_path).await {
+        Ok(manifest) => Some(
+            serde_json::from_str::<LegacyMetadata>(&manifest)
+                .map_err(|e| format!("invalid legacy manifest {}: {}", manifest_path, e))?,
+        ),
+        Err(err) => {
+            warn!(file = %data_path, error = %err, "Legacy payload without manifest");
+            None
+        }
+    };
+
+    //The old facade only ever wrote gzip ("GZ")
+    let checksum = integrity::checksum(&bytes);
+    let (file_path, start, end) =
+        append_segment(config, collection.to_string(), bytes, |start, end| {
+            let mut meta = Metadata::new(
+                "application/octet-stream".to_string(),
+                "gzip".to_string(),
+                "legacy".to_string(),
+                start,
+                end,
+                checksum,
+            );
+            if let Some(legacy_meta) = &legacy_meta {
+                meta.creation_date = legacy_meta.creation_date.clone();
+                if legacy_meta.compression != "GZ" {
+                    meta.compression = legacy_meta.compression.to_lowercase();
+                }
+            }
+            meta
+        })
+        .await?;
+
+    Ok(format!("{}?start={}&end={}", file_path, start, end))
+}
+
+async fn record_reference(base: &str, legacy: &str, reference: &str) -> Result<(), String> {
+    let line = serde_json::to_string(&MigratedReference {
+        legacy: legacy.to_string(),
+        reference: reference.to_string(),
+    })
+    .map_err(|e| e.to_string())?;
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(format!("{}/{}", base, LEGACY_REFERENCES_FILE))
+        .await
+        .map_err(|e| e.to_string())?;
+    file.write_all(format!("{}\n", line).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    file.sync_all().await.map_err(|e| e.to_string())?;
+
+    //Loaded again on the next legacy GET
+    REFERENCES.lock().await.remove(base);
+    Ok(())
+}
+
+async fn load_references(base: &str) -> Result<HashMap<String, String>, String> {
+    let mut references = HashMap::new();
+    let path = format!("{}/{}", base, LEGACY_REFERENCES_FILE);
+    let file = match fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(references),
+        Err(err) => return Err(err.to_string()),
+    };
+
+    let mut lines = BufReader::new(file).lines();
+    while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+        //A line cut by a crash was never followed by the deletion of its payload
+        match serde_json::from_str::<MigratedReference>(&line) {
+            Ok(migrated) => _ = references.insert(migrated.legacy, migrated.reference),
+            Err(err) => warn!(file = %path, error = %err, "Skipping invalid legacy reference"),
+        }
+    }
+    Ok(references)
+}
+
+//Where a reference handed out by the old facade ({archive}?start=..&end=..) lives now
+pub async fn resolve_reference(
+    base: &str,
+    legacy: &str,
+) -> Result<Option<(String, u64, u64)>, String> {
+    let references = {
+        let mut cache = REFERENCES.lock().await;
+        match cache.get(base) {
+            Some(references) => references.clone(),
+            None => {
+                let references = Arc::new(load_references(base).await?);
+                cache.insert(base.to_string(), references.clone());
+                references
+            }
+        }
+    };
+
+    Ok(references.get(legacy).and_then(|reference| {
+        let (file, range) = reference.split_once("?start=")?;
+        let (start, end) = range.split_once("&end=")?;
+        Some((file.to_string(), start.parse().ok()?, end.parse().ok()?))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::facades::efs_facade::{get_collection_byte_range, read_manifest};
+
+    async fn write_legacy(legacy_path: &str, archive: &str, start: i64, end: i64, bytes: &[u8]) {
+        let archive_path = format!("{}/{}", legacy_path, archive);
+        create_dir(&archive_path).await.unwrap();
+        fs::write(format!("{}/{}-{}", archive_path, start, end), bytes)
+            .await
+            .unwrap();
+        let manifest = format!(
+            r#"{{"start":{},"end":{},"compression":"GZ","creation_date":"2023-08-01 10:00:00 UTC"}}"#,
+            start, end
+        );
+        fs::write(
+            format!("{}/{}-{}.manifest", archive_path, start, end),
+            manifest,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrate_legacy_archive() {
+        let legacy = tempfile::tempdir().unwrap();
+        let legacy_path = legacy.path().to_str().unwrap();
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap();
+        let config = Config {
+            base_path: base_path.to_string(),
+            ..Config::default()
+        };
+
+        write_legacy(legacy_path, "legacy_collection", 100, 105, b"world").await;
+        write_legacy(legacy_path, "legacy_collection", 0, 5, b"hello").await;
+        //Directories of another layout are left alone
+        create_dir(&format!("{}/other/other", legacy_path))
+            .await
+            .unwrap();
+
+        let migrated = migrate_legacy_archives(legacy_path, &config).await.unwrap();
+        assert_eq!(migrated.len(), 2);
+        assert_eq!(migrated[0].legacy, "legacy_collection?start=0&end=5");
+        assert_eq!(migrated[1].legacy, "legacy_collection?start=100&end=105");
+
+        let (file, _) = migrated[1].reference.split_once('?').unwrap();
+        let bytes = get_collection_byte_range(base_path, file.to_string(), 5, 10).await;
+        assert_eq!(bytes, Ok(Some(b"world".to_vec())));
+        let manifest = read_manifest(base_path, file.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(manifest[0].creation_date, "2023-08-01 10:00:00 UTC");
+        assert_eq!(manifest[0].compression, "gzip");
+
+        let references = fs::read_to_string(format!("{}/{}", base_path, LEGACY_REFERENCES_FILE))
+            .await
+            .unwrap();
+        assert_eq!(references.lines().count(), 2);
+
+        assert_eq!(
+            get_directories_list(legacy_path).await.unwrap(),
+            vec!["other"]
+        );
+        //Nothing left to migrate
+        assert_eq!(
+            migrate_legacy_archives(legacy_path, &config).await,
+            Ok(Vec::new())
+        );
+
+        let resolved = resolve_reference(base_path, "legacy_collection?start=100&end=105").await;
+        assert_eq!(resolved, Ok(Some((file.to_string(), 5, 10))));
+        assert_eq!(
+            resolve_reference(base_path, "legacy_collection?start=1&end=2").await,
+            Ok(None)
+        );
+    }
+
+    #[tokio::test]
+    async fn rerun_after_a_crash_before_the_deletion() {
+        let legacy = tempfile::tempdir().unwrap();
+        let legacy_path = legacy.path().to_str().unwrap();
+        let base = tempfile::tempdir().unwrap();
+        let config = Config {
+            base_path: base.path().to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+
+        write_legacy(legacy_path, "crashed_collection", 0, 5, b"hello").await;
+        let migrated = migrate_legacy_archives(legacy_path, &config).await.unwrap();
+        assert_eq!(migrated.len(), 1);
+
+        //The mapping was written, the legacy payload is still there
+        write_legacy(legacy_path, "crashed_collection", 0, 5, b"hello").await;
+        assert_eq!(
+            migrate_legacy_archives(legacy_path, &config).await,
+            Ok(Vec::new())
+        );
+        assert!(get_directories_list(legacy_path).await.unwrap().is_empty());
+
+        //Not appended twice
+        let (file, _) = migrated[0].reference.split_once('?').unwrap();
+        let manifest = read_manifest(&config.base_path, file.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(manifest.len(), 1);
+    }
+}
@@ -0,0 +1,365 @@
+//In-process S3 compatible server for the tests, no credentials or network needed.
+//Only what the facades use is implemented, with path style addressing.
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use rusoto_core::{credential::StaticProvider, HttpClient, Region};
+use rusoto_s3::S3Client;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+//Small so the paginated listings get exercised
+const PAGE_SIZE: usize = 2;
+
+#[derive(Default)]
+struct FakeState {
+    //bucket -> key -> object
+    buckets: BTreeMap<String, BTreeMap<String, Vec<u8>>>,
+    uploads: HashMap<String, Upload>,
+    next_upload_id: u64,
+}
+
+struct Upload {
+    bucket: String,
+    key: String,
+    parts: BTreeMap<i64, Vec<u8>>,
+}
+
+pub struct FakeS3 {
+    endpoint: String,
+    state: Arc<Mutex<FakeState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl FakeS3 {
+    //Listens on a random local port until dropped
+    pub async fn start() -> FakeS3 {
+        let state = Arc::new(Mutex::new(FakeState::default()));
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| handle(state.clone(), request))) }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        let (sender, receiver) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            _ = receiver.await;
+        }));
+
+        FakeS3 {
+            endpoint,
+            state,
+            shutdown: Some(sender),
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn client(&self) -> S3Client {
+        let region = Region::Custom {
+            name: "us-east-1".to_string(),
+            endpoint: self.endpoint.clone(),
+        };
+        let credentials = StaticProvider::new_minimal("fake".to_string(), "fake".to_string());
+        S3Client::new_with(HttpClient::new().unwrap(), credentials, region)
+    }
+
+    pub fn create_bucket(&self, bucket: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.buckets.entry(bucket.to_string()).or_default();
+    }
+
+    pub fn object(&self, bucket: &str, key: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state.buckets.get(bucket)?.get(key).cloned()
+    }
+
+    pub fn keys(&self, bucket: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .buckets
+            .get(bucket)
+            .map(|objects| objects.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn pending_uploads(&self) -> usize {
+        self.state.lock().unwrap().uploads.len()
+    }
+}
+
+impl Drop for FakeS3 {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            _ = shutdown.send(());
+        }
+    }
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (pair.to_string(), String::new()),
+        })
+        .collect()
+}
+
+fn xml(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/xml")
+        .body(Body::from(format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>{}",
+            body
+        )))
+        .unwrap()
+}
+
+fn error(status: StatusCode, code: &str) -> Response<Body> {
+    xml(
+        status,
+        format!(
+            "<Error><Code>{code}</Code><Message>{code}</Message></Error>",
+            code = code
+        ),
+    )
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn with_etag(etag: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("ETag", etag)
+        .body(Body::empty())
+        .unwrap()
+}
+
+//"bytes=start-end" with an inclusive end, clamped to the object
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse::<usize>().ok()?;
+    let end = match end {
+        "" => len.checked_sub(1)?,
+        end => end.parse::<usize>().ok()?.min(len.checked_sub(1)?),
+    };
+    (start <= end).then_some((start, end + 1))
+}
+
+async fn handle(
+    state: Arc<Mutex<FakeState>>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().trim_start_matches('/').to_string();
+    let query = parse_query(request.uri().query());
+    let range = request
+        .headers()
+        .get("Range")
+        .and_then(|range| range.to_str().ok())
+        .map(|range| range.to_string());
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .map(|bytes| bytes.to_vec())
+        .unwrap_or_default();
+
+    let (bucket, key) = match path.split_once('/') {
+        Some((bucket, key)) if !key.is_empty() => (bucket.to_string(), Some(key.to_string())),
+        Some((bucket, _)) => (bucket.to_string(), None),
+        None => (path, None),
+    };
+
+    let mut state = state.lock().unwrap();
+    let response = match (method, key) {
+        (Method::GET, None) if bucket.is_empty() => list_buckets(&state),
+        (Method::HEAD, None) => match state.buckets.contains_key(&bucket) {
+            true => empty(StatusCode::OK),
+            false => empty(StatusCode::NOT_FOUND),
+        },
+        (Method::PUT, None) => {
+            state.buckets.entry(bucket).or_default();
+            empty(StatusCode::OK)
+        }
+        (Method::DELETE, None) => match state.buckets.get(&bucket) {
+            None => error(StatusCode::NOT_FOUND, "NoSuchBucket"),
+            Some(objects) if !objects.is_empty() => error(StatusCode::CONFLICT, "BucketNotEmpty"),
+            Some(_) => {
+                state.buckets.remove(&bucket);
+                empty(StatusCode::NO_CONTENT)
+            }
+        },
+        (Method::GET, None) => list_objects(&state, &bucket, &query),
+        (method, Some(_)) if !state.buckets.contains_key(&bucket) => match method {
+            Method::GET => error(StatusCode::NOT_FOUND, "NoSuchKey"),
+            _ => error(StatusCode::NOT_FOUND, "NoSuchBucket"),
+        },
+        (Method::POST, Some(key)) if query.contains_key("uploads") => {
+            state.next_upload_id += 1;
+            let upload_id = format!("upload-{}", state.next_upload_id);
+            state.uploads.insert(
+                upload_id.clone(),
+                Upload {
+                    bucket: bucket.clone(),
+                    key: key.clone(),
+                    parts: BTreeMap::new(),
+                },
+            );
+            xml(
+                StatusCode::OK,
+                format!(
+                    "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    bucket, key, upload_id
+                ),
+            )
+        }
+        (Method::PUT, Some(_)) if query.contains_key("uploadId") => {
+            let part_number = query.get("partNumber").and_then(|n| n.parse::<i64>().ok());
+            match (state.uploads.get_mut(&query["uploadId"]), part_number) {
+                (Some(upload), Some(part_number)) => {
+                    upload.parts.insert(part_number, body);
+                    with_etag(format!("\"part-{}\"", part_number))
+                }
+                _ => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+            }
+        }
+        (Method::POST, Some(_)) if query.contains_key("uploadId") => {
+            match state.uploads.remove(&query["uploadId"]) {
+                Some(upload) => {
+                    let object: Vec<u8> = upload.parts.values().flatten().copied().collect();
+                    let etag = format!("\"{}-{}\"", query["uploadId"], upload.parts.len());
+                    state
+                        .buckets
+                        .entry(upload.bucket.clone())
+                        .or_default()
+                        .insert(upload.key.clone(), object);
+                    xml(
+                        StatusCode::OK,
+                        format!(
+                            "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>",
+                            upload.bucket, upload.key, etag
+                        ),
+                    )
+                }
+                None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+            }
+        }
+        (Method::DELETE, Some(_)) if query.contains_key("uploadId") => {
+            match state.uploads.remove(&query["uploadId"]) {
+                Some(_) => empty(StatusCode::NO_CONTENT),
+                None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+            }
+        }
+        (Method::PUT, Some(key)) => {
+            state.buckets.get_mut(&bucket).unwrap().insert(key, body);
+            with_etag("\"object\"".to_string())
+        }
+        (Method::GET, Some(key)) => match state.buckets[&bucket].get(&key) {
+            None => error(StatusCode::NOT_FOUND, "NoSuchKey"),
+            Some(object) => match range {
+                None => Response::new(Body::from(object.clone())),
+                Some(range) => match parse_range(&range, object.len()) {
+                    Some((start, end)) => Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header(
+                            "Content-Range",
+                            format!("bytes {}-{}/{}", start, end - 1, object.len()),
+                        )
+                        .body(Body::from(object[start..end].to_vec()))
+                        .unwrap(),
+                    None => error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange"),
+                },
+            },
+        },
+        (Method::DELETE, Some(key)) => {
+            state.buckets.get_mut(&bucket).unwrap().remove(&key);
+            empty(StatusCode::NO_CONTENT)
+        }
+        _ => error(StatusCode::NOT_IMPLEMENTED, "NotImplemented"),
+    };
+
+    Ok(response)
+}
+
+fn list_buckets(state: &FakeState) -> Response<Body> {
+    let buckets: String = state
+        .buckets
+        .keys()
+        .map(|bucket| format!("<Bucket><Name>{}</Name></Bucket>", bucket))
+        .collect();
+    xml(
+        StatusCode::OK,
+        format!(
+            "<ListAllMyBucketsResult><Buckets>{}</Buckets></ListAllMyBucketsResult>",
+            buckets
+        ),
+    )
+}
+
+//The continuation token is the last key of the previous page
+fn list_objects(
+    state: &FakeState,
+    bucket: &str,
+    query: &HashMap<String, String>,
+) -> Response<Body> {
+    let objects = match state.buckets.get(bucket) {
+        Some(objects) => objects,
+        None => return error(StatusCode::NOT_FOUND, "NoSuchBucket"),
+    };
+
+    let after = query.get("continuation-token");
+    let prefix = query.get("prefix").map(String::as_str).unwrap_or_default();
+    let remaining: Vec<(&String, &Vec<u8>)> = objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .filter(|(key, _)| after.map(|after| *key > after).unwrap_or(true))
+        .collect();
+    let page = &remaining[..remaining.len().min(PAGE_SIZE)];
+    let truncated = remaining.len() > PAGE_SIZE;
+
+    let contents: String = page
+        .iter()
+        .map(|(key, object)| {
+            format!(
+                "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                key,
+                object.len()
+            )
+        })
+        .collect();
+    let next = match (truncated, page.last()) {
+        (true, Some((key, _))) => format!("<NextContinuationToken>{}</NextContinuationToken>", key),
+        _ => String::new(),
+    };
+
+    xml(
+        StatusCode::OK,
+        format!(
+            "<ListBucketResult><Name>{}</Name><KeyCount>{}</KeyCount><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>{}{}</ListBucketResult>",
+            bucket,
+            page.len(),
+            PAGE_SIZE,
+            truncated,
+            next,
+            contents
+        ),
+    )
+}
@@ -0,0 +1,348 @@
+use chrono::Utc;
+use deadpool_postgres::Pool;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::{Mutex, OwnedMutexGuard},
+};
+
+use super::postgres_facade::get_client;
+use crate::config::{Config, StorageKind};
+
+pub const MAX_KEY_LENGTH: usize = 255;
+
+lazy_static! {
+    //index path (or "memory/collection") -> key -> entry
+    static ref INDEXES: Mutex<HashMap<String, HashMap<String, IndexEntry>>> =
+        Mutex::new(HashMap::new());
+    //"collection/key" -> lock held while a request with that key is in flight
+    static ref IN_FLIGHT: Mutex<HashMap<String, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    key: String,
+    reference: String,
+    created_at: i64,
+}
+
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH
+}
+
+fn get_index_path(base: &str, collection: &str) -> String {
+    format!(
+        "{base}/{collection}.idempotency",
+        base = base,
+        collection = collection
+    )
+}
+
+//Only EFS keeps the index on disk, other backends have no base path to write to
+fn get_index_file(config: &Config, collection: &str) -> Option<String> {
+    match config.storage_backend {
+        StorageKind::Efs => Some(get_index_path(&config.base_path, collection)),
+        _ => None,
+    }
+}
+
+fn get_index_key(config: &Config, collection: &str) -> String {
+    get_index_file(config, collection).unwrap_or_else(|| format!("memory/{}", collection))
+}
+
+//Serializes requests sharing the same key so a retry waits for the original to finish
+pub async fn lock_key(collection: &str, key: &str) -> OwnedMutexGuard<()> {
+    let lock = {
+        let mut in_flight = IN_FLIGHT.lock().await;
+        //Forget the locks nobody is holding anymore
+        in_flight.retain(|_, lock| Arc::strong_count(lock) > 1);
+        in_flight
+            .entry(format!("{}/{}", collection, key))
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone()
+    };
+
+    lock.lock_owned().await
+}
+
+//Reference recorded for the key within the window (IDEMPOTENCY_WINDOW_SECONDS)
+pub async fn find_reference(
+    config: &Config,
+    collection: &str,
+    key: &str,
+) -> Result<Option<String>, String> {
+    let window = config.idempotency_window;
+    let index_key = get_index_key(config, collection);
+    let mut indexes = INDEXES.lock().await;
+
+    if !indexes.contains_key(&index_key) {
+        let index = match get_index_file(config, collection) {
+            Some(path) => load_index(&path, window).await?,
+            None => HashMap::new(),
+        };
+        indexes.insert(index_key.clone(), index);
+    }
+
+    let oldest = Utc::now().timestamp() - window.as_secs() as i64;
+    Ok(indexes
+        .get(&index_key)
+        .and_then(|index| index.get(key))
+        .filter(|entry| entry.created_at >= oldest)
+        .map(|entry| entry.reference.clone()))
+}
+
+pub async fn record_reference(
+    config: &Config,
+    collection: &str,
+    key: &str,
+    reference: String,
+) -> Result<(), String> {
+    let index_key = get_index_key(config, collection);
+    let entry = IndexEntry {
+        key: key.to_string(),
+        reference,
+        created_at: Utc::now().timestamp(),
+    };
+    let entry_str = format!(
+        "{}\n",
+        serde_json::to_string(&entry).map_err(|e| e.to_string())?
+    );
+
+    let mut indexes = INDEXES.lock().await;
+
+    match get_index_file(config, collection) {
+        Some(path) => {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&path)
+                .await
+                .map_err(|e| e.to_string())?;
+            file.write_all(entry_str.as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+
+            if let Some(index) = indexes.get_mut(&index_key) {
+                index.insert(entry.key.clone(), entry);
+            }
+        }
+        None => {
+            //Nothing on disk to compact, expired keys are dropped here instead
+            let oldest = entry.created_at - config.idempotency_window.as_secs() as i64;
+            let index = indexes.entry(index_key).or_default();
+            index.retain(|_, entry| entry.created_at >= oldest);
+            index.insert(entry.key.clone(), entry);
+        }
+    }
+
+    Ok(())
+}
+
+//With Postgres the keys live in "IdempotencyKeys", so a retry sent to another node is caught too
+pub async fn find_reference_pg(
+    pool: &Pool,
+    collection: &str,
+    key: &str,
+    window: Duration,
+) -> Result<Option<String>, String> {
+    let client = get_client(pool).await?;
+    let row = client
+        .query_opt(
+            r#"SELECT reference FROM public."IdempotencyKeys"
+                WHERE collection = $1 AND key = $2
+                AND created_at >= now() - $3 * interval '1 second'"#,
+            &[&collection, &key, &window.as_secs_f64()],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(row.map(|row| row.get("reference")))
+}
+
+//An expired key is taken over, one still in its window is kept
+pub async fn record_reference_pg(
+    pool: &Pool,
+    collection: &str,
+    key: &str,
+    reference: &str,
+    window: Duration,
+) -> Result<(), String> {
+    let client = get_client(pool).await?;
+    client
+        .execute(
+            r#"INSERT INTO public."IdempotencyKeys" (collection, key, reference)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (collection, key) DO UPDATE
+                SET reference = EXCLUDED.reference, created_at = now()
+                WHERE "IdempotencyKeys".created_at < now() - $4 * interval '1 second'"#,
+            &[&collection, &key, &reference, &window.as_secs_f64()],
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/*Steps
+1. Read the index file of the collection
+2. Drop the keys older than the window
+3. Rewrite the file if something expired so it doesn't grow forever
+*/
+async fn load_index(path: &str, window: Duration) -> Result<HashMap<String, IndexEntry>, String> {
+    let oldest = Utc::now().timestamp() - window.as_secs() as i64;
+    let mut index = HashMap::new();
+    let mut expired = false;
+
+    match OpenOptions::new().read(true).open(path).await {
+        Ok(file) => {
+            let mut lines = BufReader::new(file).lines();
+            while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+                match serde_json::from_str::<IndexEntry>(&line) {
+                    Ok(entry) if entry.created_at >= oldest => {
+                        index.insert(entry.key.clone(), entry);
+                    }
+                    _ => expired = true,
+                }
+            }
+        }
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => return Ok(index),
+            _ => return Err(err.to_string()),
+        },
+    }
+
+    if expired {
+        let mut content = String::new();
+        for entry in index.values() {
+            content.push_str(&serde_json::to_string(entry).map_err(|e| e.to_string())?);
+            content.push('\n');
+        }
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, content)
+            .await
+            .map_err(|e| e.to_string())?;
+        fs::rename(&tmp_path, path)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::facades::migrations::migrate;
+    use crate::facades::postgres_facade::{create_config, create_pool};
+
+    const WINDOW: Duration = Duration::from_secs(86_400);
+
+    fn efs_config(base: &str) -> Config {
+        Config {
+            base_path: base.to_string(),
+            idempotency_window: WINDOW,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn validate_key() {
+        assert!(is_valid_key("retry-1"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key(&"a".repeat(MAX_KEY_LENGTH + 1)));
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_forgotten() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap();
+
+        let collection = "idempotency_collection";
+        let old = IndexEntry {
+            key: "old".to_string(),
+            reference: "file?start=0&end=1".to_string(),
+            created_at: Utc::now().timestamp() - WINDOW.as_secs() as i64 - 1,
+        };
+        fs::write(
+            get_index_path(base, collection),
+            format!("{}\n", serde_json::to_string(&old).unwrap()),
+        )
+        .await
+        .unwrap();
+
+        let config = efs_config(base);
+        record_reference(&config, collection, "new", "file?start=1&end=2".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(find_reference(&config, collection, "old").await, Ok(None));
+        assert_eq!(
+            find_reference(&config, collection, "new").await,
+            Ok(Some("file?start=1&end=2".to_string()))
+        );
+
+        //The expired key was compacted away
+        let content = fs::read_to_string(get_index_path(base, collection))
+            .await
+            .unwrap();
+        assert_eq!(content.lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_in_memory_without_base_path() {
+        let config = Config {
+            base_path: "/nonexistent".to_string(),
+            storage_backend: StorageKind::Memory,
+            idempotency_window: WINDOW,
+            ..Config::default()
+        };
+
+        let collection = "idempotency_memory_collection";
+        assert_eq!(find_reference(&config, collection, "key").await, Ok(None));
+        record_reference(&config, collection, "key", "file?start=0&end=1".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            find_reference(&config, collection, "key").await,
+            Ok(Some("file?start=0&end=1".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "postgres"]
+    async fn keys_in_postgres() {
+        let pool = create_pool(create_config("127.0.0.1", "guest", "guest", "test"), 1).unwrap();
+        let mut client = get_client(&pool).await.unwrap();
+        migrate(&mut client).await.unwrap();
+
+        let key = format!("key-{}", rand::random::<u32>());
+        let collection = "idempotency_pg_collection";
+        assert_eq!(
+            find_reference_pg(&pool, collection, &key, WINDOW).await,
+            Ok(None)
+        );
+
+        record_reference_pg(&pool, collection, &key, "file?start=0&end=1", WINDOW)
+            .await
+            .unwrap();
+        //Still in its window, the first reference stays
+        record_reference_pg(&pool, collection, &key, "file?start=1&end=2", WINDOW)
+            .await
+            .unwrap();
+        assert_eq!(
+            find_reference_pg(&pool, collection, &key, WINDOW).await,
+            Ok(Some("file?start=0&end=1".to_string()))
+        );
+
+        //Expired
+        let no_window = Duration::from_secs(0);
+        record_reference_pg(&pool, collection, &key, "file?start=1&end=2", no_window)
+            .await
+            .unwrap();
+        assert_eq!(
+            find_reference_pg(&pool, collection, &key, WINDOW).await,
+            Ok(Some("file?start=1&end=2".to_string()))
+        );
+    }
+}
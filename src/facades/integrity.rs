@@ -0,0 +1,66 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use sha2::{Digest, Sha256};
+
+const CHECKSUM_PREFIX: &str = "sha256:";
+
+lazy_static! {
+    static ref CHECKSUM_MISMATCH: IntCounterVec = register_int_counter_vec!(
+        "segment_checksum_mismatch_total",
+        "Number of segments read back with a checksum different from the manifest",
+        &["tier"]
+    )
+    .unwrap();
+}
+
+//Checksum of a stored (compressed) segment as written in the manifest: "sha256:<hex>"
+pub fn checksum(bytes: &[u8]) -> String {
+    format!("{}{}", CHECKSUM_PREFIX, hex::encode(Sha256::digest(bytes)))
+}
+
+pub fn verify(bytes: &[u8], expected: &str) -> bool {
+    checksum(bytes) == expected
+}
+
+pub fn record_mismatch(tier: &str) {
+    CHECKSUM_MISMATCH.with_label_values(&[tier]).inc();
+}
+
+//Value of the ETag header for a checksum
+pub fn etag_header(checksum: &str) -> String {
+    format!("\"{}\"", checksum.trim_start_matches(CHECKSUM_PREFIX))
+}
+
+//Value of the Digest header (RFC 3230) for a checksum
+pub fn digest_header(checksum: &str) -> Option<String> {
+    let bytes = hex::decode(checksum.strip_prefix(CHECKSUM_PREFIX)?).ok()?;
+    Some(format!("sha-256={}", STANDARD.encode(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_checksum() {
+        let sum = checksum(b"segment");
+        assert!(sum.starts_with(CHECKSUM_PREFIX));
+        assert!(verify(b"segment", &sum));
+        assert!(!verify(b"segmenT", &sum));
+    }
+
+    #[test]
+    fn format_headers() {
+        let sum = checksum(b"");
+        assert_eq!(
+            etag_header(&sum),
+            "\"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\""
+        );
+        assert_eq!(
+            digest_header(&sum),
+            Some("sha-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".to_string())
+        );
+        assert!(digest_header("md5:abc").is_none());
+    }
+}
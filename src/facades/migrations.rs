@@ -0,0 +1,169 @@
+use deadpool_postgres::Object;
+use tracing::{info, instrument};
+
+//Embedded in the binary, applied in order. A migration is never edited once released, add one instead.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: [Migration; 5] = [
+    Migration {
+        version: 1,
+        name: "cache_offset_table",
+        sql: include_str!("../../migrations/0001_cache_offset_table.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "segment_index",
+        sql: include_str!("../../migrations/0002_segment_index.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "idempotency_keys",
+        sql: include_str!("../../migrations/0003_idempotency_keys.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "archive_journal",
+        sql: include_str!("../../migrations/0004_archive_journal.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "segment_catalog",
+        sql: include_str!("../../migrations/0005_segment_catalog.sql"),
+    },
+];
+
+//Nodes starting together wait for each other instead of applying the same migration twice
+const MIGRATION_LOCK: i64 = 0x70726f78795f6361;
+
+const SCHEMA_VERSION_SQL: &str = r#"CREATE TABLE IF NOT EXISTS public."SchemaVersion"
+(
+    version integer NOT NULL,
+    name text NOT NULL,
+    applied_at timestamptz NOT NULL DEFAULT now(),
+    CONSTRAINT "SchemaVersion_pkey" PRIMARY KEY (version)
+)"#;
+
+const VERSION_SQL: &str =
+    r#"SELECT COALESCE(MAX(version), 0) AS version FROM public."SchemaVersion""#;
+
+//Schema version this binary was built for
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+//Last applied migration, 0 on a database that was never migrated
+pub async fn current_version(client: &Object) -> Result<i32, String> {
+    let row = client
+        .query_one(
+            r#"SELECT to_regclass('public."SchemaVersion"') IS NOT NULL AS migrated"#,
+            &[],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    if !row.get::<_, bool>("migrated") {
+        return Ok(0);
+    }
+    let row = client
+        .query_one(VERSION_SQL, &[])
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(row.get("version"))
+}
+
+/*Steps
+1. Take the migration lock for the length of the transaction
+2. Refuse a database migrated by a newer binary
+3. Apply the pending migrations and record their version, all or nothing
+*/
+#[instrument(name = "postgres.migrate", skip_all)]
+pub async fn migrate(client: &mut Object) -> Result<Vec<i32>, String> {
+    let transaction = client.transaction().await.map_err(|e| e.to_string())?;
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+        .await
+        .map_err(|e| e.to_string())?;
+    transaction
+        .batch_execute(SCHEMA_VERSION_SQL)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let row = transaction
+        .query_one(VERSION_SQL, &[])
+        .await
+        .map_err(|e| e.to_string())?;
+    let current: i32 = row.get("version");
+    if current > latest_version() {
+        return Err(format!(
+            "database schema is at version {}, newer than {} supported by this binary",
+            current,
+            latest_version()
+        ));
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        transaction
+            .batch_execute(migration.sql)
+            .await
+            .map_err(|e| format!("migration {} failed: {}", migration.name, e))?;
+        transaction
+            .execute(
+                r#"INSERT INTO public."SchemaVersion" (version, name) VALUES ($1, $2)"#,
+                &[&migration.version, &migration.name],
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        info!(
+            version = migration.version,
+            name = migration.name,
+            "Migration applied"
+        );
+        applied.push(migration.version);
+    }
+
+    transaction.commit().await.map_err(|e| e.to_string())?;
+    Ok(applied)
+}
+
+//The app only starts on the schema it was built for
+pub async fn check_version(client: &Object) -> Result<(), String> {
+    match current_version(client).await? {
+        version if version == latest_version() => Ok(()),
+        version => Err(format!(
+            "database schema is at version {}, expected {}, run migrate-db or set POSTGRES_MIGRATE",
+            version,
+            latest_version()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::facades::postgres_facade::{create_config, create_pool, get_client};
+
+    #[test]
+    fn versions_follow_each_other() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i32 + 1);
+            assert!(!migration.sql.trim().is_empty());
+        }
+        assert_eq!(latest_version(), MIGRATIONS.len() as i32);
+    }
+
+    #[tokio::test]
+    #[ignore = "postgres"]
+    async fn migrate_is_idempotent() {
+        let pool = create_pool(create_config("127.0.0.1", "guest", "guest", "test"), 1).unwrap();
+        let mut client = get_client(&pool).await.unwrap();
+
+        migrate(&mut client).await.unwrap();
+        assert_eq!(migrate(&mut client).await, Ok(vec![]));
+        assert_eq!(current_version(&client).await, Ok(latest_version()));
+        assert_eq!(check_version(&client).await, Ok(()));
+    }
+}
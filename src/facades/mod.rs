@@ -1,5 +1,22 @@
 pub mod archivist;
+pub mod catalog;
 pub mod compression;
+pub mod dedup;
+pub mod disk_monitor;
+pub mod durability;
 pub mod efs_facade;
+pub mod efs_migration;
+#[cfg(test)]
+pub mod fake_s3;
+pub mod idempotency;
+pub mod integrity;
+pub mod migrations;
+pub mod node;
 pub mod postgres_facade;
+pub mod postgres_tls;
+pub mod recovery;
+pub mod rotation;
 pub mod s3;
+pub mod scrubber;
+pub mod shutdown;
+pub mod storage;
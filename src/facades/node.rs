@@ -0,0 +1,100 @@
+use std::{fs, io, sync::OnceLock};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::config::{Config, StorageKind};
+
+static NODE_ID: OnceLock<String> = OnceLock::new();
+
+//Node IDs end up in file names between dashes, so only letters and digits are allowed
+pub fn is_valid_node_id(node_id: &str) -> bool {
+    !node_id.is_empty() && node_id.len() <= 64 && node_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+//Resolves the node ID once at startup, before anything is written
+pub fn init_node_id(config: &Config) -> Result<&'static str, String> {
+    let resolved = resolve_node_id(config)?;
+    let node_id = NODE_ID.get_or_init(|| resolved.clone());
+    if *node_id != resolved {
+        return Err(format!("node ID {} was already in use", node_id));
+    }
+    info!(node_id = %node_id, "Node ID resolved");
+    Ok(node_id)
+}
+
+//Identity of this writer in file names and references.
+//Without init_node_id (tools, tests) a temporary one is used.
+pub fn get_node_id() -> &'static str {
+    NODE_ID.get_or_init(new_node_id)
+}
+
+fn new_node_id() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/*Steps
+1. NODE_ID if it is set (validated by the config)
+2. Nothing is kept across restarts in memory, so a new UUID
+3. The UUID persisted in NODE_ID_FILE by a previous run
+4. A new UUID, persisted in NODE_ID_FILE for the next runs
+*/
+fn resolve_node_id(config: &Config) -> Result<String, String> {
+    if let Some(node_id) = &config.node_id {
+        return Ok(node_id.clone());
+    }
+    if config.storage_backend != StorageKind::Efs {
+        return Ok(new_node_id());
+    }
+
+    let path = &config.node_id_file;
+    match fs::read_to_string(path) {
+        Ok(node_id) if is_valid_node_id(node_id.trim()) => return Ok(node_id.trim().to_string()),
+        Ok(_) => return Err(format!("invalid node ID in {}", path)),
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(format!("unable to read {}: {}", path, err))
+        }
+        Err(_) => {}
+    }
+
+    let node_id = new_node_id();
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, &node_id).map_err(|e| format!("unable to write {}: {}", path, e))?;
+    fs::rename(&tmp_path, path).map_err(|e| format!("unable to write {}: {}", path, e))?;
+    Ok(node_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_node_id() {
+        assert!(is_valid_node_id("node1"));
+        assert!(is_valid_node_id(&new_node_id()));
+        assert!(!is_valid_node_id(""));
+        assert!(!is_valid_node_id("node-1"));
+        assert!(!is_valid_node_id(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn node_id_is_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            base_path: dir.path().to_str().unwrap().to_string(),
+            node_id_file: dir.path().join("node-id").to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+
+        let node_id = resolve_node_id(&config).unwrap();
+        assert!(is_valid_node_id(&node_id));
+        assert_eq!(resolve_node_id(&config), Ok(node_id));
+
+        config.node_id = Some("replica2".to_string());
+        assert_eq!(resolve_node_id(&config), Ok("replica2".to_string()));
+
+        //The file can't be created, startup fails instead of using a temporary ID
+        config.node_id = None;
+        config.node_id_file = "/does/not/exist/node-id".to_string();
+        assert!(resolve_node_id(&config).is_err());
+    }
+}
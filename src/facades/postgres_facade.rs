@@ -1,7 +1,27 @@
 use std::env;
-use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Object};
-use tokio_postgres::{Config, NoTls};
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Object, Runtime};
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, register_int_counter_vec, Histogram, IntCounterVec};
+use tokio_postgres::{config::SslMode, Config, NoTls};
 use chrono::{Utc, Datelike};
+use tracing::instrument;
+
+use super::postgres_tls::create_tls_connect;
+use crate::config::{PostgresConfig, PostgresRecycling, PostgresSslMode};
+
+lazy_static! {
+    static ref POOL_WAIT_DURATION: Histogram = register_histogram!(
+        "postgres_pool_wait_seconds",
+        "Time spent waiting for a connection from the pool"
+    )
+    .unwrap();
+    static ref POSTGRES_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "postgres_errors_total",
+        "Number of failed pool checkouts and queries",
+        &["operation"]
+    )
+    .unwrap();
+}
 
 pub fn create_config(host: &str, user: &str, pass: &str, db: &str) -> Config {
     let mut configs = Config::new();
@@ -32,11 +52,70 @@ pub fn create_pool(configs: Config, pool_size: usize) -> Result<Pool, String> {
     pool_result.map_err(|e| e.to_string())
 }
 
+/*Steps
+1. Connection settings with the connect timeout and the TLS mode
+2. Rustls connector unless TLS is disabled
+3. Pool with the configured size, timeouts and recycling method
+*/
+pub fn create_pool_from_config(pg: &PostgresConfig) -> Result<Pool, String> {
+    let mut configs = create_config(&pg.host, &pg.user, &pg.password, &pg.db);
+    if let Some(timeout) = pg.connect_timeout {
+        configs.connect_timeout(timeout);
+    }
+
+    let recycling_method = match pg.recycling_method {
+        PostgresRecycling::Fast => RecyclingMethod::Fast,
+        PostgresRecycling::Verified => RecyclingMethod::Verified,
+        PostgresRecycling::Clean => RecyclingMethod::Clean,
+    };
+    let mgr_config = ManagerConfig{recycling_method};
+    let mgr = match pg.tls.mode {
+        PostgresSslMode::Disable => Manager::from_config(configs, NoTls, mgr_config),
+        PostgresSslMode::Require | PostgresSslMode::VerifyFull => {
+            configs.ssl_mode(SslMode::Require);
+            Manager::from_config(configs, create_tls_connect(&pg.tls)?, mgr_config)
+        }
+    };
+
+    Pool::builder(mgr)
+        .max_size(pg.pool_size)
+        .wait_timeout(pg.wait_timeout)
+        .create_timeout(pg.connect_timeout)
+        .recycle_timeout(pg.recycle_timeout)
+        .runtime(Runtime::Tokio1)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+//Connection from the pool, the wait and the failures are measured
+#[instrument(name = "postgres.pool", skip_all)]
+pub async fn get_client(pool: &Pool) -> Result<Object, String> {
+    let timer = POOL_WAIT_DURATION.start_timer();
+    let client = pool.get().await;
+    timer.observe_duration();
+
+    client.map_err(|err| {
+        POSTGRES_ERRORS.with_label_values(&["pool"]).inc();
+        err.to_string()
+    })
+}
+
+//A connection from the pool answers a trivial query
+pub async fn ping(pool: &Pool) -> Result<(), String> {
+    let client = get_client(pool).await?;
+    client.simple_query("SELECT 1").await.map_err(|err| {
+        POSTGRES_ERRORS.with_label_values(&["query"]).inc();
+        err.to_string()
+    })?;
+    Ok(())
+}
+
 fn get_current_date() -> String {
     let current_date = Utc::now();
     format!("{:04}-{:02}-{:02}", current_date.year(), current_date.month(), current_date.day())
 }
 
+#[instrument(name = "postgres.offset", skip(client))]
 pub async fn get_offset(client: Object, collection: String, len_bytes: usize) -> Result<(i64, i64), String>{
     let current_date = get_current_date();
     let table = "\"CacheOffsetTable\"".to_string();
@@ -51,7 +130,7 @@ pub async fn get_offset(client: Object, collection: String, len_bytes: usize) ->
         RETURNING {table}.{offset};\n", 
         table = table, date = date, offset = offset, len_bytes = len_bytes, collection_col = collection_col, collection = collection);
 
-    match client.prepare_cached(&query).await {
+    let offsets = match client.prepare_cached(&query).await {
         Ok(statement) => {
             client
                 .query_one(&statement, &[])
@@ -63,7 +142,11 @@ pub async fn get_offset(client: Object, collection: String, len_bytes: usize) ->
                 .map_err(|err| err.to_string())
         },
         Err(err) => Err(err.to_string())
+    };
+    if offsets.is_err() {
+        POSTGRES_ERRORS.with_label_values(&["query"]).inc();
     }
+    offsets
 }
 #[cfg(test)]
 mod tests {
@@ -85,13 +168,25 @@ mod tests {
         assert!(pool.is_ok());
     }
 
+    #[tokio::test]
+    async fn pool_errors_are_counted() {
+        let mut pg_config = get_test_config();
+        //Nothing listens there
+        pg_config.port(1);
+        let pool = create_pool(pg_config, 1).unwrap();
+
+        let before = POSTGRES_ERRORS.with_label_values(&["pool"]).get();
+        assert!(get_client(&pool).await.is_err());
+        assert_eq!(POSTGRES_ERRORS.with_label_values(&["pool"]).get(), before + 1);
+    }
+
     #[tokio::test]
     #[ignore = "postgres"]
     async fn create_client() {
         let pg_config = get_test_config();
         let pool = create_pool(pg_config, 1);
         assert!(pool.is_ok());
-        let client = pool.unwrap().get().await;
+        let client = get_client(&pool.unwrap()).await;
         assert!(client.is_ok())
     }
 
@@ -126,7 +221,7 @@ mod tests {
 
         match get_offset(client, "test".to_string(), 20).await {
             Ok(offsets) => println!("({},{})", offsets.0, offsets.1),
-            Err(err) => println!("{}", err.to_string())
+            Err(err) => println!("{}", err)
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,147 @@
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::{fs::File, io::BufReader, sync::Arc};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::config::{PostgresSslMode, PostgresTls};
+
+//sslmode=require: the connection is encrypted but the server isn't authenticated
+#[derive(Debug)]
+struct AcceptAnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate in {}: {}", path, e))?;
+    match certs.is_empty() {
+        true => Err(format!("no certificate in {}", path)),
+        false => Ok(certs),
+    }
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("invalid private key in {}: {}", path, e))?
+        .ok_or(format!("no private key in {}", path))
+}
+
+/*Steps
+1. Trust the CA bundle if one is given, the webpki roots otherwise
+2. Skip the server certificate checks for require
+3. Present the client certificate if one is given
+*/
+pub fn create_tls_connect(tls: &PostgresTls) -> Result<MakeRustlsConnect, String> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let builder = match tls.mode {
+        PostgresSslMode::Require => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyServerCert(provider))),
+        PostgresSslMode::VerifyFull | PostgresSslMode::Disable => {
+            let mut roots = RootCertStore::empty();
+            match &tls.root_cert {
+                Some(path) => {
+                    for cert in read_certs(path)? {
+                        roots
+                            .add(cert)
+                            .map_err(|e| format!("invalid certificate in {}: {}", path, e))?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            builder.with_root_certificates(roots)
+        }
+    };
+
+    let config = match &tls.client_cert {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+            .map_err(|e| format!("invalid client certificate {}: {}", cert, e))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(MakeRustlsConnect::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_connectors() {
+        let require = PostgresTls {
+            mode: PostgresSslMode::Require,
+            ..PostgresTls::default()
+        };
+        assert!(create_tls_connect(&require).is_ok());
+
+        let verify = PostgresTls {
+            mode: PostgresSslMode::VerifyFull,
+            ..PostgresTls::default()
+        };
+        assert!(create_tls_connect(&verify).is_ok());
+
+        //Not a PEM bundle
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("ca.pem");
+        std::fs::write(&bundle, "not a certificate").unwrap();
+        let custom_ca = PostgresTls {
+            root_cert: Some(bundle.to_str().unwrap().to_string()),
+            ..verify
+        };
+        assert!(create_tls_connect(&custom_ca).is_err());
+    }
+}
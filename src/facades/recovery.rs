@@ -0,0 +1,253 @@
+use tokio::{
+    fs::{self, OpenOptions},
+    io,
+};
+use tracing::{info, warn};
+
+use super::efs_facade::{list_collection_files, parse_file_path, Metadata};
+use super::node::get_node_id;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    pub files: usize,
+    pub repaired_files: usize,
+    pub truncated_bytes: u64,
+    pub dropped_entries: usize,
+    pub torn_lines: usize,
+}
+
+//Only the files of this node are repaired, the other replicas may still be writing to theirs
+pub async fn recover_all(base: &str) -> Result<RecoveryReport, String> {
+    let mut report = RecoveryReport::default();
+
+    let own_files = list_collection_files(base)
+        .await?
+        .into_iter()
+        .map(|(file_path, _)| file_path)
+        .filter(|file_path| {
+            parse_file_path(file_path)
+                .map(|(_, node, _)| node == get_node_id())
+                .unwrap_or(false)
+        });
+
+    for file_path in own_files {
+        let file_report = recover_file(base, &file_path).await?;
+        report.files += 1;
+        if file_report != RecoveryReport::default() {
+            report.repaired_files += 1;
+        }
+        report.truncated_bytes += file_report.truncated_bytes;
+        report.dropped_entries += file_report.dropped_entries;
+        report.torn_lines += file_report.torn_lines;
+    }
+
+    info!(
+        files = report.files,
+        repaired_files = report.repaired_files,
+        truncated_bytes = report.truncated_bytes,
+        dropped_entries = report.dropped_entries,
+        torn_lines = report.torn_lines,
+        "Recovery completed"
+    );
+
+    Ok(report)
+}
+
+/*Steps
+1. Read the manifest, skipping the lines that don't parse (torn by a crash)
+2. Drop the entries pointing past the end of the data file
+3. Truncate the data bytes no entry points to (data appended, manifest never written)
+4. Rewrite the manifest if anything was dropped
+*/
+pub async fn recover_file(base: &str, file_path: &str) -> Result<RecoveryReport, String> {
+    let data_path = format!("{base}/{}.gzip", file_path, base = base);
+    let manifest_path = format!("{base}/{}.manifest", file_path, base = base);
+    let mut report = RecoveryReport::default();
+
+    let data_len = fs::metadata(&data_path)
+        .await
+        .map_err(|e| e.to_string())?
+        .len();
+    let manifest = match fs::read_to_string(&manifest_path).await {
+        Ok(manifest) => manifest,
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => String::new(),
+            _ => return Err(err.to_string()),
+        },
+    };
+
+    let mut segments: Vec<Metadata> = Vec::new();
+    for line in manifest.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str::<Metadata>(line) {
+            Ok(meta) if meta.start <= meta.end && meta.end <= data_len => segments.push(meta),
+            Ok(meta) => {
+                warn!(
+                    file = %file_path,
+                    start = meta.start,
+                    end = meta.end,
+                    data_len = data_len,
+                    "Dropping manifest entry past the end of the data file"
+                );
+                report.dropped_entries += 1;
+            }
+            Err(_) => {
+                warn!(file = %file_path, "Dropping torn manifest line");
+                report.torn_lines += 1;
+            }
+        }
+    }
+
+    let referenced_len = segments.iter().map(|meta| meta.end).max().unwrap_or(0);
+    if referenced_len < data_len {
+        warn!(
+            file = %file_path,
+            bytes = data_len - referenced_len,
+            "Truncating unreferenced tail of the data file"
+        );
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&data_path)
+            .await
+            .map_err(|e| e.to_string())?;
+        file.set_len(referenced_len)
+            .await
+            .map_err(|e| e.to_string())?;
+        file.sync_all().await.map_err(|e| e.to_string())?;
+        report.truncated_bytes = data_len - referenced_len;
+    }
+
+    //A manifest without its final newline is torn too, rewrite it as well
+    if report.dropped_entries > 0
+        || report.torn_lines > 0
+        || (!manifest.is_empty() && !manifest.ends_with('\n'))
+    {
+        let mut content = String::new();
+        for meta in segments.iter() {
+            content.push_str(&serde_json::to_string(meta).map_err(|e| e.to_string())?);
+            content.push('\n');
+        }
+        let tmp_path = format!("{}.tmp", manifest_path);
+        fs::write(&tmp_path, content)
+            .await
+            .map_err(|e| e.to_string())?;
+        fs::rename(&tmp_path, &manifest_path)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::facades::efs_facade::{append_bytes_collection, append_segment, write_metadata};
+    use crate::facades::integrity::checksum;
+    use tokio::io::AsyncWriteExt;
+
+    fn efs_config(base: &tempfile::TempDir) -> Config {
+        Config {
+            base_path: base.path().to_str().unwrap().to_string(),
+            ..Config::default()
+        }
+    }
+
+    async fn write_segment(
+        config: &Config,
+        collection: &str,
+        bytes: Vec<u8>,
+    ) -> (String, u64, u64) {
+        let sum = checksum(&bytes);
+        append_segment(config, collection.to_string(), bytes, |start, end| {
+            Metadata::new(
+                "text/plain".to_string(),
+                "gzip".to_string(),
+                "localhost".to_string(),
+                start,
+                end,
+                sum,
+            )
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn clean_file_is_untouched() {
+        let base = tempfile::tempdir().unwrap();
+        let config = efs_config(&base);
+
+        let (file, _, _) = write_segment(&config, "clean_collection", vec![1; 20]).await;
+        write_segment(&config, "clean_collection", vec![2; 20]).await;
+
+        assert_eq!(
+            recover_file(&config.base_path, &file).await,
+            Ok(RecoveryReport::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn repair_torn_tails() {
+        let base = tempfile::tempdir().unwrap();
+        let config = efs_config(&base);
+
+        let collection = "torn_collection";
+        let (file, _, _) = write_segment(&config, collection, vec![1; 20]).await;
+        let (_, _, end) = write_segment(&config, collection, vec![2; 20]).await;
+
+        //Crash between the data append and the manifest append
+        append_bytes_collection(&config, collection.to_string(), vec![3; 15])
+            .await
+            .unwrap();
+
+        //An entry past the end of the data and a half written line
+        let past_eof = Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            100,
+            200,
+            checksum(b""),
+        );
+        write_metadata(&config, file.clone(), past_eof)
+            .await
+            .unwrap();
+        let manifest_path = base.path().join(format!("{}.manifest", file));
+        let mut manifest = OpenOptions::new()
+            .append(true)
+            .open(&manifest_path)
+            .await
+            .unwrap();
+        manifest
+            .write_all(b"{\"creation_date\":\"20")
+            .await
+            .unwrap();
+        manifest.flush().await.unwrap();
+
+        let report = recover_file(&config.base_path, &file).await.unwrap();
+        assert_eq!(
+            report,
+            RecoveryReport {
+                files: 0,
+                repaired_files: 0,
+                truncated_bytes: 15,
+                dropped_entries: 1,
+                torn_lines: 1,
+            }
+        );
+
+        let data_len = std::fs::metadata(base.path().join(format!("{}.gzip", file)))
+            .unwrap()
+            .len();
+        assert_eq!(data_len, end);
+        let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+        assert_eq!(manifest.lines().count(), 2);
+
+        //A second pass has nothing left to do
+        assert_eq!(
+            recover_file(&config.base_path, &file).await,
+            Ok(RecoveryReport::default())
+        );
+    }
+}
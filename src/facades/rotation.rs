@@ -0,0 +1,535 @@
+use chrono::Utc;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    fs, io,
+    io::AsyncWriteExt,
+    sync::{mpsc, Mutex, OwnedRwLockReadGuard, RwLock},
+};
+use tracing::{info, warn};
+
+use super::efs_facade::{
+    get_current_date, get_file_path, get_segment_number, list_collection_files, parse_file_path,
+    split_segment,
+};
+use super::node::get_node_id;
+use crate::config::Config;
+
+//Files in base with the highest sealed segment of every day path of today, one per node
+//({SEALED_SEGMENTS_FILE}-{node}) so replicas sharing base never rewrite each other's.
+//Sealed segments are deleted once archived, so the disk alone can't tell which names were used.
+pub const SEALED_SEGMENTS_FILE: &str = ".sealed-segments";
+
+lazy_static! {
+    //Serializes the rewrites of the sealed segments files of this process
+    static ref SEALED_FILE_LOCK: Mutex<()> = Mutex::new(());
+    //"base/collection" -> segment currently written to (None until it is looked up on disk)
+    static ref SEGMENTS: Mutex<HashMap<String, Arc<RwLock<Option<SegmentState>>>>> =
+        Mutex::new(HashMap::new());
+    static ref SEALED: std::sync::Mutex<Option<mpsc::UnboundedSender<String>>> =
+        std::sync::Mutex::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RotationWindow {
+    Daily,
+    Hourly,
+}
+
+impl RotationWindow {
+    pub fn parse(window: &str) -> Option<RotationWindow> {
+        match window.trim() {
+            "daily" => Some(RotationWindow::Daily),
+            "hourly" => Some(RotationWindow::Hourly),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SegmentState {
+    //File path of the day, as returned by get_file_path
+    day_path: String,
+    segment: u32,
+    //Time window the segment was opened in
+    window: String,
+    size: AtomicU64,
+}
+
+impl SegmentState {
+    fn file_path(&self) -> String {
+        get_segment_path(&self.day_path, self.segment)
+    }
+}
+
+//Held by a writer for as long as it appends to a segment, the segment can't be sealed meanwhile
+pub struct SegmentGuard {
+    guard: OwnedRwLockReadGuard<Option<SegmentState>>,
+}
+
+impl SegmentGuard {
+    pub fn file_path(&self) -> String {
+        self.state().file_path()
+    }
+
+    //Called after an append so size based rotation knows where the segment is at
+    pub fn record_size(&self, size: u64) {
+        self.state().size.fetch_max(size, Ordering::SeqCst);
+    }
+
+    fn state(&self) -> &SegmentState {
+        //A guard is only handed out once the state is loaded
+        self.guard.as_ref().unwrap()
+    }
+}
+
+//Segment 0 keeps the name of the day file, the following ones get a _N suffix
+pub fn get_segment_path(day_path: &str, segment: u32) -> String {
+    if segment == 0 {
+        day_path.to_string()
+    } else {
+        format!("{}_{}", day_path, segment)
+    }
+}
+
+fn get_current_window(window: RotationWindow) -> String {
+    match window {
+        RotationWindow::Daily => Utc::now().format("%Y-%m-%d").to_string(),
+        RotationWindow::Hourly => Utc::now().format("%Y-%m-%dT%H").to_string(),
+    }
+}
+
+//Sealed file paths are sent here once nobody writes to them anymore. Only one subscriber is kept.
+pub fn subscribe_sealed() -> mpsc::UnboundedReceiver<String> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    *SEALED.lock().unwrap() = Some(sender);
+    receiver
+}
+
+fn notify_sealed(file_path: String) {
+    info!(file = %file_path, "Segment sealed");
+    if let Some(sender) = SEALED.lock().unwrap().as_ref() {
+        _ = sender.send(file_path);
+    }
+}
+
+fn needs_rotation(state: &SegmentState, collection: &str, config: &Config) -> bool {
+    state.day_path != get_file_path(collection.to_string())
+        || state.window != get_current_window(config.rotation_window)
+        || config
+            .rotation_max_bytes
+            .map(|max| state.size.load(Ordering::SeqCst) >= max)
+            .unwrap_or(false)
+}
+
+/*Steps
+1. Load the last segment of the day from disk the first time a collection is written to
+2. Rotate if the day, the window or the size says so, and seal the previous segment
+3. Hand out a read guard on the current segment
+*/
+pub async fn acquire_segment(config: &Config, collection: &str) -> Result<SegmentGuard, String> {
+    let base = &config.base_path;
+    let lock = SEGMENTS
+        .lock()
+        .await
+        .entry(format!("{}/{}", base, collection))
+        .or_insert_with(|| Arc::new(RwLock::new(None)))
+        .clone();
+
+    loop {
+        let guard = lock.clone().read_owned().await;
+        match guard.as_ref() {
+            Some(state) if !needs_rotation(state, collection, config) => {
+                return Ok(SegmentGuard { guard })
+            }
+            _ => drop(guard),
+        }
+
+        //Waits for the writers of the current segment to be done
+        let mut state = lock.write().await;
+        match state.as_ref() {
+            None => *state = Some(load_latest_segment(config, collection).await?),
+            Some(current) if needs_rotation(current, collection, config) => {
+                let day_path = get_file_path(collection.to_string());
+                let segment = if current.day_path == day_path {
+                    current.segment + 1
+                } else {
+                    0
+                };
+                let sealed = current.file_path();
+                record_sealed(base, &current.day_path, current.segment).await?;
+                *state = Some(SegmentState {
+                    day_path,
+                    segment,
+                    window: get_current_window(config.rotation_window),
+                    size: AtomicU64::new(0),
+                });
+                notify_sealed(sealed);
+            }
+            _ => {}
+        }
+    }
+}
+
+/*Steps
+1. Take the write lock of every segment of base that was written to, waiting for its writers
+2. Move the writers to the next segment of the day
+3. Seal the previous one so the archiver picks it up now rather than at the end of the window
+*/
+pub async fn seal_segments(base: &str) -> Vec<String> {
+    let prefix = format!("{}/", base);
+    let locks: Vec<Arc<RwLock<Option<SegmentState>>>> = SEGMENTS
+        .lock()
+        .await
+        .iter()
+        .filter(|(key, _)| {
+            key.strip_prefix(&prefix)
+                .map(|collection| !collection.contains('/'))
+                .unwrap_or(false)
+        })
+        .map(|(_, lock)| lock.clone())
+        .collect();
+
+    let mut sealed = Vec::new();
+    for lock in locks {
+        let mut state = lock.write().await;
+        if let Some(current) = state.as_mut() {
+            if current.size.load(Ordering::SeqCst) == 0 {
+                continue;
+            }
+            let file_path = current.file_path();
+            //Left open rather than risking its name being reused after a restart
+            if let Err(err) = record_sealed(base, &current.day_path, current.segment).await {
+                warn!(file = %file_path, error = %err, "Unable to record the sealed segment");
+                continue;
+            }
+            current.segment += 1;
+            current.size = AtomicU64::new(0);
+            notify_sealed(file_path.clone());
+            sealed.push(file_path);
+        }
+    }
+    sealed
+}
+
+/*Steps
+1. Take the write lock of every segment, waiting for the writers still appending to it
+2. Forget the segment so the next writer looks it up on disk again
+3. Return (base, file path) of every segment that was open
+*/
+pub async fn close_segments() -> Vec<(String, String)> {
+    let locks: Vec<(String, Arc<RwLock<Option<SegmentState>>>)> = SEGMENTS
+        .lock()
+        .await
+        .iter()
+        .map(|(key, lock)| (key.clone(), lock.clone()))
+        .collect();
+
+    let mut closed = Vec::new();
+    for (key, lock) in locks {
+        let mut state = lock.write().await;
+        if let (Some(current), Some((base, _))) = (state.take(), key.rsplit_once('/')) {
+            closed.push((base.to_string(), current.file_path()));
+        }
+    }
+    closed
+}
+
+/*Steps
+1. Collect the segments of base currently open
+2. Keep the collection files that are not open, and either sealed or of a past day
+*/
+pub async fn list_sealed_files(base: &str) -> Result<Vec<String>, String> {
+    let prefix = format!("{}/", base);
+    let locks: Vec<Arc<RwLock<Option<SegmentState>>>> = SEGMENTS
+        .lock()
+        .await
+        .iter()
+        .filter(|(key, _)| {
+            key.strip_prefix(&prefix)
+                .map(|collection| !collection.contains('/'))
+                .unwrap_or(false)
+        })
+        .map(|(_, lock)| lock.clone())
+        .collect();
+    let mut open = Vec::new();
+    for lock in locks {
+        if let Some(current) = lock.read().await.as_ref() {
+            open.push(current.file_path());
+        }
+    }
+
+    let node = get_node_id();
+    let sealed = read_sealed(base, node).await?;
+    let today = get_current_date();
+    Ok(list_collection_files(base)
+        .await?
+        .into_iter()
+        .map(|(file, _)| file)
+        //The other nodes sharing base archive their own files
+        .filter(|file| get_file_node(file).as_deref() == Some(node))
+        .filter(|file| !open.contains(file))
+        .filter(|file| {
+            let (day_path, segment) = split_segment(file);
+            !day_path.ends_with(&today)
+                || sealed
+                    .get(day_path)
+                    .map(|highest| segment <= *highest)
+                    .unwrap_or(false)
+        })
+        .collect())
+}
+
+fn get_file_node(file: &str) -> Option<String> {
+    parse_file_path(file).map(|(_, node, _)| node)
+}
+
+fn get_sealed_path(base: &str, node: &str) -> String {
+    format!("{}/{}-{}", base, SEALED_SEGMENTS_FILE, node)
+}
+
+async fn read_sealed(base: &str, node: &str) -> Result<HashMap<String, u32>, String> {
+    let path = get_sealed_path(base, node);
+    match fs::read_to_string(&path).await {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| format!("invalid sealed segments file {}: {}", path, e)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/*Steps
+1. Keep the highest sealed segment of the day path in the file of its node, forget the other days
+2. Write it to a temporary file and fsync it
+3. Rename it over the previous file, before the segment can be archived
+*/
+async fn record_sealed(base: &str, day_path: &str, segment: u32) -> Result<(), String> {
+    let _lock = SEALED_FILE_LOCK.lock().await;
+    let node = get_file_node(day_path).unwrap_or(get_node_id().to_string());
+    let today = get_current_date();
+    let mut sealed = read_sealed(base, &node).await?;
+    sealed.retain(|file, _| file.ends_with(&today));
+    let highest = sealed.entry(day_path.to_string()).or_insert(segment);
+    *highest = (*highest).max(segment);
+
+    let path = get_sealed_path(base, &node);
+    let tmp_path = format!("{}.tmp", path);
+    let content = serde_json::to_vec(&sealed).map_err(|e| e.to_string())?;
+    let mut file = fs::File::create(&tmp_path)
+        .await
+        .map_err(|e| e.to_string())?;
+    file.write_all(&content).await.map_err(|e| e.to_string())?;
+    file.sync_all().await.map_err(|e| e.to_string())?;
+    fs::rename(&tmp_path, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/*Steps
+1. Find the last segment of the day on disk
+2. Skip past the sealed ones, they may have been archived and deleted already
+*/
+async fn load_latest_segment(config: &Config, collection: &str) -> Result<SegmentState, String> {
+    let day_path = get_file_path(collection.to_string());
+    let sealed = read_sealed(&config.base_path, get_node_id())
+        .await?
+        .get(&day_path)
+        .copied();
+
+    let latest = list_collection_files(&config.base_path)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(file, _)| {
+            file == &day_path
+                || file
+                    .strip_prefix(&day_path)
+                    .map(|suffix| suffix.starts_with('_'))
+                    .unwrap_or(false)
+        })
+        .map(|(file, size)| (get_segment_number(&file), size))
+        .max();
+
+    let (segment, size) = match (latest, sealed) {
+        (Some((segment, size)), Some(sealed)) if segment > sealed => (segment, size),
+        (_, Some(sealed)) => (sealed + 1, 0),
+        (latest, None) => latest.unwrap_or((0, 0)),
+    };
+    Ok(SegmentState {
+        day_path,
+        segment,
+        window: get_current_window(config.rotation_window),
+        size: AtomicU64::new(size),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use std::time::Duration;
+
+    #[test]
+    fn segment_paths() {
+        assert_eq!(get_segment_path("c-1-2023-08-01", 0), "c-1-2023-08-01");
+        assert_eq!(get_segment_path("c-1-2023-08-01", 3), "c-1-2023-08-01_3");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn close_waits_for_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap().to_string();
+        let config = Config {
+            base_path: base.clone(),
+            ..Config::default()
+        };
+
+        let segment = acquire_segment(&config, "closing_collection")
+            .await
+            .unwrap();
+        let file_path = segment.file_path();
+        let closing = tokio::spawn(close_segments());
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!closing.is_finished());
+
+        drop(segment);
+        let closed = closing.await.unwrap();
+        assert!(closed.contains(&(base, file_path)));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn seal_moves_writers_to_the_next_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap().to_string();
+        let config = Config {
+            base_path: base.clone(),
+            ..Config::default()
+        };
+
+        let segment = acquire_segment(&config, "sealed_collection").await.unwrap();
+        let file_path = segment.file_path();
+        drop(segment);
+        //Nothing written yet, nothing to archive
+        assert!(seal_segments(&base).await.is_empty());
+
+        let segment = acquire_segment(&config, "sealed_collection").await.unwrap();
+        segment.record_size(10);
+        drop(segment);
+        assert_eq!(seal_segments(&base).await, vec![file_path.clone()]);
+
+        let segment = acquire_segment(&config, "sealed_collection").await.unwrap();
+        assert_eq!(segment.file_path(), format!("{}_1", file_path));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn sealed_names_are_not_reused_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap().to_string();
+        let config = Config {
+            base_path: base.clone(),
+            ..Config::default()
+        };
+        let collection = "restarted_collection";
+
+        let segment = acquire_segment(&config, collection).await.unwrap();
+        let day_path = segment.file_path();
+        let written = dir.path().join(format!("{}.gzip", day_path));
+        std::fs::write(&written, [1; 10]).unwrap();
+        segment.record_size(10);
+        drop(segment);
+        assert_eq!(seal_segments(&base).await, vec![day_path.clone()]);
+
+        //Archived (so deleted) then restarted
+        std::fs::remove_file(&written).unwrap();
+        close_segments().await;
+        let segment = acquire_segment(&config, collection).await.unwrap();
+        assert_eq!(segment.file_path(), format!("{}_1", day_path));
+        drop(segment);
+
+        //The segment still open before the restart is appended to again
+        let open = dir.path().join(format!("{}_1.gzip", day_path));
+        std::fs::write(&open, [2; 5]).unwrap();
+        close_segments().await;
+        let segment = acquire_segment(&config, collection).await.unwrap();
+        assert_eq!(segment.file_path(), format!("{}_1", day_path));
+        assert_eq!(segment.state().size.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn sealed_files_exclude_the_open_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap().to_string();
+        let config = Config {
+            base_path: base.clone(),
+            ..Config::default()
+        };
+        let write = |file: &str| std::fs::write(dir.path().join(format!("{}.gzip", file)), [1]);
+
+        let segment = acquire_segment(&config, "pressure_collection")
+            .await
+            .unwrap();
+        let day_path = segment.file_path();
+        write(&day_path).unwrap();
+        segment.record_size(1);
+        drop(segment);
+        assert!(list_sealed_files(&base).await.unwrap().is_empty());
+
+        seal_segments(&base).await;
+        let open = acquire_segment(&config, "pressure_collection")
+            .await
+            .unwrap();
+        write(&open.file_path()).unwrap();
+        //A past day nobody writes to anymore
+        let past = day_path.replace(&get_current_date(), "2023-08-01");
+        write(&past).unwrap();
+        //Today, not open, but never sealed: the next write may reopen it
+        let unsealed = day_path.replace("pressure_collection", "idle_collection");
+        write(&unsealed).unwrap();
+
+        let mut sealed = list_sealed_files(&base).await.unwrap();
+        sealed.sort();
+        let mut expected = vec![day_path, past];
+        expected.sort();
+        assert_eq!(sealed, expected);
+    }
+
+    #[tokio::test]
+    async fn nodes_sharing_base_keep_their_sealed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_str().unwrap().to_string();
+        let today = get_current_date();
+
+        let writers: Vec<_> = (0..8u32)
+            .map(|i| {
+                let base = base.clone();
+                let day_path = format!("shared_collection_{}-replica{}-{}", i, i % 2, today);
+                tokio::spawn(async move { record_sealed(&base, &day_path, i).await })
+            })
+            .collect();
+        for writer in writers {
+            assert_eq!(writer.await.unwrap(), Ok(()));
+        }
+
+        for node in ["replica0", "replica1"] {
+            let sealed = read_sealed(&base, node).await.unwrap();
+            assert_eq!(sealed.len(), 4, "{}", node);
+            for (day_path, segment) in sealed {
+                assert_eq!(
+                    day_path,
+                    format!("shared_collection_{}-{}-{}", segment, node, today)
+                );
+            }
+        }
+    }
+}
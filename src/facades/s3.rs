@@ -1,21 +1,63 @@
-use dotenv::dotenv;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
 use log::info;
+use prometheus::{register_histogram_vec, register_int_counter, register_int_counter_vec, HistogramVec, IntCounter, IntCounterVec};
 use rusoto_core::Region;
-use rusoto_s3::{ListBucketsOutput, PutObjectRequest, S3Client, S3};
-use std::{error::Error, ops::Range};
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectError, ListBucketsOutput, ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+
+use super::efs_facade::Metadata;
+use super::storage::{StorageBackend, Tier};
+use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
 use tokio::time::{sleep, Duration};
 use tokio::io::AsyncReadExt;
-
-
-pub fn init_client() -> S3Client {
-    dotenv().ok();
-
-    let region = Region::default();
+use tracing::instrument;
+
+
+// Client for AWS, or for an S3 compatible endpoint (MinIO, a local fake...) when one is given.
+// Credentials come from the usual AWS chain either way.
+pub fn create_client(endpoint: Option<&str>, region: Option<&str>) -> S3Client {
+    let region = match (endpoint, region) {
+        (Some(endpoint), region) => Region::Custom {
+            name: region.unwrap_or("us-east-1").to_string(),
+            endpoint: endpoint.to_string(),
+        },
+        (None, Some(region)) => region.parse().unwrap_or_default(),
+        (None, None) => Region::default(),
+    };
     S3Client::new(region)
 }
 
+lazy_static! {
+    static ref S3_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "s3_request_duration_seconds",
+        "Time taken by S3 uploads and reads",
+        &["operation"]
+    )
+    .unwrap();
+    static ref S3_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "s3_errors_total",
+        "Number of failed S3 uploads and reads",
+        &["operation"]
+    )
+    .unwrap();
+    static ref S3_PART_RETRIES: IntCounter = register_int_counter!(
+        "s3_part_retries_total",
+        "Number of multipart upload parts that had to be sent again"
+    )
+    .unwrap();
+    static ref S3_UPLOADED_BYTES: IntCounter = register_int_counter!(
+        "s3_uploaded_bytes_total",
+        "Bytes uploaded to S3"
+    )
+    .unwrap();
+}
+
+// Suffix of the JSON manifest uploaded next to an archived file
+pub const S3_MANIFEST_SUFFIX: &str = "-manifest.json";
+
 // Minimum part size for S3 is 5MB
 // Maximmim nuber of parts is 10000
 // Current part size allows for 50 * 10000 = 50GB size
@@ -41,7 +83,27 @@ pub async fn get_item(bucket_name: &str, file_name: &str, client: S3Client) -> R
     Ok(buffer)
 }
 
+// Get the manifest of an archived file, None if it was not archived
+pub async fn get_manifest(
+    bucket_name: &str,
+    file_name: &str,
+    client: S3Client,
+) -> Result<Option<Vec<Metadata>>, String> {
+    let key = if file_name.ends_with(S3_MANIFEST_SUFFIX) {
+        file_name.to_string()
+    } else {
+        format!("{}{}", file_name, S3_MANIFEST_SUFFIX)
+    };
+    match get_object(bucket_name, &key, client, None).await? {
+        Some(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| e.to_string()),
+        None => Ok(None),
+    }
+}
+
 // Upload file to specific bucket
+#[instrument(name = "s3.put", skip(file_path, client))]
 pub async fn upload_file(
     bucket_name: &str,
     file_path: &str,
@@ -52,36 +114,105 @@ pub async fn upload_file(
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
 
+    let size = buffer.len() as u64;
     let put_object_req = PutObjectRequest {
         bucket: bucket_name.to_owned(),
         key: file_name.to_owned(),
         body: Some(buffer.into()),
         ..Default::default()
     };
-    client.put_object(put_object_req).await?;
+    let timer = S3_REQUEST_DURATION.with_label_values(&["put"]).start_timer();
+    let put = client.put_object(put_object_req).await;
+    timer.observe_duration();
+    if put.is_err() {
+        S3_ERRORS.with_label_values(&["put"]).inc();
+    }
+    put?;
+    S3_UPLOADED_BYTES.inc_by(size);
 
     Ok(())
 }
 
-pub async fn read_file(file_name: &str, client: S3Client, start: i64, end: i64) -> Result<Vec<u8>, String> {
-    //read_bytes
-    //let bytes = S3.read()
+// Read the [start, end) byte range of an object, None if the object doesn't exist
+pub async fn read_file(
+    bucket_name: &str,
+    file_name: &str,
+    client: S3Client,
+    start: u64,
+    end: u64,
+) -> Result<Option<Vec<u8>>, String> {
+    if end <= start {
+        return Ok(Some(Vec::new()));
+    }
 
-    //get bytes range
-    //let wanted_bytes = bytes.range(range)
+    // HTTP ranges are inclusive
+    let range = format!("bytes={}-{}", start, end - 1);
+    get_object(bucket_name, file_name, client, Some(range)).await
+}
 
-    //return
-    //Ok(wanted_bytes)
-    todo!()
-} 
+#[instrument(name = "s3.get", skip(client))]
+async fn get_object(
+    bucket_name: &str,
+    file_name: &str,
+    client: S3Client,
+    range: Option<String>,
+) -> Result<Option<Vec<u8>>, String> {
+    let get_obj_req = rusoto_s3::GetObjectRequest {
+        bucket: bucket_name.to_owned(),
+        key: file_name.to_owned(),
+        range,
+        ..Default::default()
+    };
+
+    let timer = S3_REQUEST_DURATION.with_label_values(&["get"]).start_timer();
+    let output = client.get_object(get_obj_req).await;
+    timer.observe_duration();
+
+    match output {
+        Ok(output) => {
+            let mut reader = output.body.ok_or("Missing object body")?.into_async_read();
+            let mut buffer = Vec::new();
+            reader
+                .read_to_end(&mut buffer)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Some(buffer))
+        }
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => Ok(None),
+        Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => Ok(None),
+        // InvalidRange, the range starts past the end of the object so nothing is in it
+        Err(RusotoError::Unknown(response)) if response.status.as_u16() == 416 => Ok(Some(Vec::new())),
+        Err(err) => {
+            S3_ERRORS.with_label_values(&["get"]).inc();
+            Err(err.to_string())
+        }
+    }
+}
 
 // Upload multipart file to specific bucket
+#[instrument(name = "s3.multipart", skip(file_path, client))]
 pub async fn upload_file_multipart(
     bucket_name: &str,
     file_path: &str,
     file_name: &str,
     part_size: usize,
     client: S3Client
+) -> Result<(), Box<dyn Error>> {
+    let timer = S3_REQUEST_DURATION.with_label_values(&["multipart"]).start_timer();
+    let uploaded = multipart_upload(bucket_name, file_path, file_name, part_size, client).await;
+    timer.observe_duration();
+    if uploaded.is_err() {
+        S3_ERRORS.with_label_values(&["multipart"]).inc();
+    }
+    uploaded
+}
+
+async fn multipart_upload(
+    bucket_name: &str,
+    file_path: &str,
+    file_name: &str,
+    part_size: usize,
+    client: S3Client
 ) -> Result<(), Box<dyn Error>> {
     let mut file = File::open(file_path)?;
     let mut buffer = vec![0; part_size];
@@ -125,6 +256,7 @@ pub async fn upload_file_multipart(
                         part_number,
                         part_output.e_tag.clone().unwrap_or_default()
                     );
+                    S3_UPLOADED_BYTES.inc_by(n as u64);
                     part_number += 1;
                     break;
                 }
@@ -142,6 +274,7 @@ pub async fn upload_file_multipart(
                         println!("Upload of file aborted: {}", file_name);
                         return Err(Box::new(e));
                     } else {
+                        S3_PART_RETRIES.inc();
                         // Exponential backoff: Wait for 2^(attempts - 1) seconds
                         sleep(Duration::from_secs(2u64.pow(attempts - 1))).await;
                     }
@@ -200,6 +333,35 @@ pub async fn list_buckets(client: S3Client) -> Result<Vec<String>, Box<dyn Error
     Ok(bucket_names)
 }
 
+// list every object key starting with prefix in a bucket with its size, "" for the whole bucket
+pub async fn list_objects(bucket_name: &str, prefix: &str, client: S3Client) -> Result<Vec<(String, i64)>, Box<dyn Error>> {
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+
+    loop {
+        let list_req = ListObjectsV2Request {
+            bucket: bucket_name.to_owned(),
+            continuation_token: continuation_token.clone(),
+            prefix: Some(prefix.to_owned()).filter(|prefix| !prefix.is_empty()),
+            ..Default::default()
+        };
+        let response = client.list_objects_v2(list_req).await?;
+
+        for object in response.contents.unwrap_or_default() {
+            if let Some(key) = object.key {
+                objects.push((key, object.size.unwrap_or_default()));
+            }
+        }
+
+        match response.next_continuation_token {
+            Some(token) if response.is_truncated.unwrap_or(false) => continuation_token = Some(token),
+            _ => break,
+        }
+    }
+
+    Ok(objects)
+}
+
 // create s3 bucket
 pub async fn create_bucket(bucket_name: &str, client: S3Client) -> Result<(), Box<dyn Error>> {
     let create_bucket_req = rusoto_s3::CreateBucketRequest {
@@ -211,6 +373,30 @@ pub async fn create_bucket(bucket_name: &str, client: S3Client) -> Result<(), Bo
     Ok(())
 }
 
+// check the bucket exists and the credentials can reach it
+pub async fn head_bucket(bucket_name: &str, client: S3Client) -> Result<(), String> {
+    let head_bucket_req = rusoto_s3::HeadBucketRequest {
+        bucket: bucket_name.to_owned(),
+        expected_bucket_owner: None,
+    };
+    client.head_bucket(head_bucket_req).await.map_err(|err| match err {
+        RusotoError::Unknown(response) => format!("bucket {} answered {}", bucket_name, response.status),
+        err => err.to_string(),
+    })
+}
+
+// delete an object, deleting a missing key is not an error
+pub async fn delete_object(bucket_name: &str, file_name: &str, client: S3Client) -> Result<(), Box<dyn Error>> {
+    let delete_object_req = rusoto_s3::DeleteObjectRequest {
+        bucket: bucket_name.to_owned(),
+        key: file_name.to_owned(),
+        ..Default::default()
+    };
+    client.delete_object(delete_object_req).await?;
+
+    Ok(())
+}
+
 pub async fn delete_bucket(bucket_name: &str, client: S3Client) -> Result<(), Box<dyn Error>> {
     let delete_bucket_req = rusoto_s3::DeleteBucketRequest {
         bucket: bucket_name.to_owned(),
@@ -221,307 +407,219 @@ pub async fn delete_bucket(bucket_name: &str, client: S3Client) -> Result<(), Bo
     Ok(())
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use rusoto_core;
-//     use std::io::Write;
-//     use tempfile::NamedTempFile;
-//     use uuid::Uuid;
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use rusoto_core;
-//     use std::io::Write;
-//     use tempfile::NamedTempFile;
-//     use uuid::Uuid;
-//     use serial_test::serial;
-
-// //     // Minimum part size for S3 is 5MB
-// //     // Maximmim nuber of parts is 10000
-// //     // Current part size allows for 50 * 10000 = 50GB size
-// //     const PART_SIZE: usize = 5_242_8800;
-
-
-//     #[tokio::test]
-//     #[serial]
-//     #[ignore = "tests need to be ran with AWS credentials defined in environment"]
-//     async fn test_get_item() {
-//         let s3_facade = S3Facade::new();
-//         let id = Uuid::new_v4();
-//         let bucket_name = format!("test-bucket-{}", id);
-    
-//         // Create a new bucket for the test
-//         let create_result = s3_facade.create_bucket(&bucket_name).await;
-//         assert!(create_result.is_ok(), "Bucket creation failed");
-    
-//         // Create some content
-//         let content = "test file";
-    
-//         // Create a temp file
-//         let mut temp_file = NamedTempFile::new().unwrap();
-//         temp_file.write_all(content.as_bytes()).unwrap();
-//         let file_name = "file.txt";
-//         let file_path = temp_file.path().to_str().unwrap();
-    
-//         // Upload the file
-//         let upload_result = s3_facade
-//             .upload_file(&bucket_name, file_path, file_name)
-//             .await;
-//         assert!(upload_result.is_ok(), "Upload file failed");
-    
-//         // Read the file back from S3
-//         let downloaded_content = s3_facade
-//             .get_item(&bucket_name, file_name)
-//             .await
-//             .expect("Read file failed");
-    
-//         // Compare 
-//         assert_eq!(
-//             String::from_utf8_lossy(&downloaded_content),
-//             content,
-//             "Content of the downloaded file did not match the original file"
-//         );
-    
-//         // Cleanup
-//         let delete_object_req = rusoto_s3::DeleteObjectRequest {
-//             bucket: bucket_name.clone(),
-//             key: file_name.to_owned(),
-//             ..Default::default()
-//         };
-//         let delete_object_result = s3_facade.client.delete_object(delete_object_req).await;
-//         assert!(delete_object_result.is_ok(), "Object deletion failed");
-    
-//         let delete_bucket_result = s3_facade.delete_bucket(&bucket_name).await;
-//         assert!(
-//             delete_bucket_result.is_ok(),
-//             "Bucket deletion assertion failed"
-//         );
-//     }
-
-//     #[tokio::test]
-//     #[serial]
-//     #[ignore = "tests need to be ran with AWS credentials defined in environment"]
-//     async fn test_list_buckets() {
-//         let s3_facade = S3Facade::new();
-//         let id = Uuid::new_v4();
-//         let bucket_name = format!("test-bucket-{}", id);
-
-//         // Create a bucket
-//         let create_result = s3_facade.create_bucket(&bucket_name).await;
-//         assert!(create_result.is_ok());
-
-//         // List buckets
-//         let list_result = s3_facade.list_buckets().await;
-//         if let Err(e) = list_result {
-//             info!("Error: {:?}", e);
-//             panic!();
-//         }
-
-//         // Print buckets
-//         let buckets = list_result.unwrap();
-//         println!("Bucket names:");
-//         for bucket in &buckets {
-//             info!("{}", bucket);
-//         }
-
-//         // Check if the new bucket is in the list
-//         assert!(buckets.contains(&bucket_name));
-
-//         // Cleanup
-//         let delete_result = s3_facade.delete_bucket(&bucket_name).await;
-//         assert!(delete_result.is_ok());
-//     }
-
-    // #[tokio::test]
-    // #[serial]
-    // #[ignore = "tests need to be ran with AWS credentials defined in environment"]
-    // async fn test_create_bucket() {
-    //     let s3_facade = S3Facade::new();
-    //     let id = Uuid::new_v4();
-    //     let bucket_name = format!("test-bucket-{}", id);
-
-//         // Delete bucket if exists
-//         let _ = s3_facade.delete_bucket(&bucket_name).await;
-//         let result = s3_facade.create_bucket(&bucket_name).await;
-
-//         if let Err(e) = result {
-//             println!("Error: {:?}", e);
-//             panic!();
-//         }
-
-//         assert!(result.is_ok());
-//     }
-
-//     #[tokio::test]
-//     #[serial]
-//     #[ignore = "tests need to be ran with AWS credentials defined in environment"]
-//     async fn test_upload_file() {
-//         let s3_facade = S3Facade::new();
-//         let id = Uuid::new_v4();
-//         let bucket_name = format!("test-bucket-{}", id);
-
-// //         // Create a new bucket for the test
-// //         let create_result = s3_facade.create_bucket(&bucket_name).await;
-// //         assert!(create_result.is_ok());
-
-//         // Create temp file
-//         let mut temp_file = NamedTempFile::new().unwrap();
-//         write!(temp_file, "test file").unwrap();
-//         let file_name = "file.txt";
-//         let file_path = temp_file.path().to_str().unwrap();
-
-//         // Upload the file
-//         let upload_result = s3_facade
-//             .upload_file(&bucket_name, file_path, file_name)
-//             .await;
-//         if let Err(e) = upload_result {
-//             info!("Error: {:?}", e);
-//             panic!();
-//         }
-
-//         assert!(upload_result.is_ok());
-
-//         // Cleanup
-//         let delete_object_req = rusoto_s3::DeleteObjectRequest {
-//             bucket: bucket_name.clone(),
-//             key: file_name.to_owned(),
-//             ..Default::default()
-//         };
-//         let _ = s3_facade
-//             .client
-//             .delete_object(delete_object_req)
-//             .await
-//             .unwrap();
-
-//         let delete_result = s3_facade.delete_bucket(&bucket_name).await;
-//         if let Err(e) = delete_result {
-//             info!("Error: {:?}", e);
-//             panic!();
-//         }
-
-//         assert!(delete_result.is_ok());
-//     }
-
-    // #[tokio::test]
-    // #[serial]
-    // #[ignore = "tests need to be ran with AWS credentials defined in environment"]
-    // async fn test_upload_file_multipart() {
-    //     let s3_facade = S3Facade::new();
-    //     let id = Uuid::new_v4();
-    //     let bucket_name = format!("test-bucket-{}", id);
-
-//         // Create a new bucket for the test
-//         let create_result = s3_facade.create_bucket(&bucket_name).await;
-//         assert!(create_result.is_ok(), "Bucket creation failed");
-
-//         // Generate a file for upload
-//         let mut temp_file = tempfile::NamedTempFile::new().unwrap();
-//         let file_data = vec![0; PART_SIZE + 10]; // Some data larger than one part
-//         temp_file.write_all(&file_data).unwrap();
-//         let file_path = temp_file.path().to_str().unwrap().to_owned();
-//         let file_name = "multipart_upload.txt";
-
-//         // Upload file
-//         let upload_result = s3_facade
-//             .upload_file_multipart(&bucket_name, &file_path, file_name, PART_SIZE)
-//             .await;
-//         if let Err(e) = upload_result {
-//             if let Some(rusoto_err) =
-//                 e.downcast_ref::<rusoto_core::RusotoError<rusoto_s3::UploadPartError>>()
-//             {
-//                 match rusoto_err {
-//                     rusoto_core::RusotoError::HttpDispatch(dispatch_error) => {
-//                         info!("This was an HttpDispatch error: {:?}", dispatch_error);
-//                     }
-//                     rusoto_core::RusotoError::Service(service_error) => {
-//                         info!("This was a Service error: {:?}", service_error);
-//                     }
-//                     rusoto_core::RusotoError::Unknown(unknown_error) => {
-//                         info!("This was an Unknown error: {:?}", unknown_error);
-//                     }
-//                     //Other error handling hereA
-//                     _ => {}
-//                 }
-//             }
-//             panic!("File upload failed");
-//         }
-//         assert!(upload_result.is_ok(), "File upload assertion failed");
-
-//         // Cleanup
-//         let delete_object_req = rusoto_s3::DeleteObjectRequest {
-//             bucket: bucket_name.clone(),
-//             key: file_name.to_owned(),
-//             ..Default::default()
-//         };
-//         let delete_object_result = s3_facade.client.delete_object(delete_object_req).await;
-//         assert!(delete_object_result.is_ok(), "Object deletion failed");
-
-//         let delete_bucket_result = s3_facade.delete_bucket(&bucket_name).await;
-//         if let Err(e) = delete_bucket_result {
-//             info!("Error: {:?}", e);
-//             panic!("Bucket deletion failed");
-//         }
-
-//         assert!(
-//             delete_bucket_result.is_ok(),
-//             "Bucket deletion assertion failed!"
-//         );
-//     }
-
-    // #[tokio::test]
-    // #[serial]
-    // #[ignore = "tests need to be ran with AWS credentials defined in environment"]
-    // async fn test_abort_multipart_upload() {
-    //     let s3_facade = S3Facade::new();
-    //     let id = Uuid::new_v4();
-    //     let bucket_name = format!("test-bucket-{}", id);
-
-//         // Create a new bucket for the test
-//         let create_result = s3_facade.create_bucket(&bucket_name).await;
-//         assert!(create_result.is_ok());
-
-//         // File path
-//         let file_name = "file.txt";
-
-//         // Start multipart upload
-//         let create_req = rusoto_s3::CreateMultipartUploadRequest {
-//             bucket: bucket_name.clone(),
-//             key: file_name.to_owned(),
-//             ..Default::default()
-//         };
-//         let upload_output = s3_facade
-//             .client
-//             .create_multipart_upload(create_req)
-//             .await
-//             .unwrap();
-//         let upload_id = upload_output.upload_id.unwrap();
-
-//         // Force an error by using an invalid upload_id
-//         let invalid_upload_id = "invalid_id";
-//         let part_req = rusoto_s3::UploadPartRequest {
-//             bucket: bucket_name.clone(),
-//             key: file_name.to_owned(),
-//             upload_id: invalid_upload_id.to_string(),
-//             part_number: 1,
-//             body: None, // No actual part
-//             ..Default::default()
-//         };
-
-//         let upload_result = s3_facade.client.upload_part(part_req).await;
-
-//         // Assert that the upload failed
-//         assert!(upload_result.is_err());
-
-//         // Abort
-//         let abort_result = s3_facade
-//             .abort_multipart_upload(&bucket_name, file_name, &upload_id)
-//             .await;
-//         assert!(abort_result.is_ok());
-
-//         // Cleanup
-//         let delete_result = s3_facade.delete_bucket(&bucket_name).await;
-
-//         assert!(delete_result.is_ok());
-//     }
-// }
+// Archived collection files. Files only get here through the archivist, so it can't be appended to.
+pub struct S3Backend {
+    bucket_name: String,
+    client: S3Client,
+}
+
+impl S3Backend {
+    pub fn new(bucket_name: String, client: S3Client) -> S3Backend {
+        S3Backend { bucket_name, client }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    fn tier(&self) -> Tier {
+        Tier::S3
+    }
+
+    async fn append(&self, _collection: &str, _bytes: Vec<u8>, _meta: Metadata) -> Result<(String, u64, u64), String> {
+        Err("S3 storage is read only".to_string())
+    }
+
+    async fn read_range(&self, file_name: &str, start: u64, end: u64) -> Result<Option<Vec<u8>>, String> {
+        read_file(&self.bucket_name, file_name, self.client.clone(), start, end).await
+    }
+
+    // Manifests are stored next to the data files, only the data files are listed
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, String> {
+        let objects = list_objects(&self.bucket_name, prefix, self.client.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(objects
+            .into_iter()
+            .filter(|(key, _)| !key.ends_with(S3_MANIFEST_SUFFIX))
+            .map(|(key, size)| (key, size.max(0) as u64))
+            .collect())
+    }
+
+    async fn delete(&self, file_name: &str) -> Result<(), String> {
+        let manifest_name = format!("{}{}", file_name, S3_MANIFEST_SUFFIX);
+        for key in [file_name, manifest_name.as_str()] {
+            delete_object(&self.bucket_name, key, self.client.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    async fn metadata(&self, file_name: &str) -> Result<Option<Vec<Metadata>>, String> {
+        get_manifest(&self.bucket_name, file_name, self.client.clone()).await
+    }
+
+    //Would mean downloading the manifest
+    async fn count_segments(&self, _file_name: &str) -> Result<Option<usize>, String> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::facades::fake_s3::FakeS3;
+    use serial_test::serial;
+    use std::env;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const BUCKET: &str = "test-bucket";
+
+    fn temp_file(bytes: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn custom_endpoint_client() {
+        let fake = FakeS3::start().await;
+        env::set_var("AWS_ACCESS_KEY_ID", "fake");
+        env::set_var("AWS_SECRET_ACCESS_KEY", "fake");
+
+        let client = create_client(Some(fake.endpoint()), Some("minio"));
+        create_bucket(BUCKET, client.clone()).await.unwrap();
+        assert_eq!(list_buckets(client).await.unwrap(), vec![BUCKET]);
+
+        env::remove_var("AWS_ACCESS_KEY_ID");
+        env::remove_var("AWS_SECRET_ACCESS_KEY");
+    }
+
+    #[tokio::test]
+    async fn create_and_delete_bucket() {
+        let fake = FakeS3::start().await;
+
+        create_bucket(BUCKET, fake.client()).await.unwrap();
+        assert_eq!(list_buckets(fake.client()).await.unwrap(), vec![BUCKET]);
+
+        delete_bucket(BUCKET, fake.client()).await.unwrap();
+        assert!(list_buckets(fake.client()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn upload_and_get_item() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        let file = temp_file(b"hello world");
+
+        upload_file(BUCKET, file.path().to_str().unwrap(), "hello", fake.client())
+            .await
+            .unwrap();
+
+        assert_eq!(get_item(BUCKET, "hello", fake.client()).await.unwrap(), b"hello world");
+        assert!(get_item(BUCKET, "missing", fake.client()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn upload_multipart_in_several_parts() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        let bytes: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let file = temp_file(&bytes);
+
+        upload_file_multipart(BUCKET, file.path().to_str().unwrap(), "multipart", 300, fake.client())
+            .await
+            .unwrap();
+
+        assert_eq!(fake.object(BUCKET, "multipart"), Some(bytes.clone()));
+        assert_eq!(fake.pending_uploads(), 0);
+        assert_eq!(get_item(BUCKET, "multipart", fake.client()).await.unwrap(), bytes);
+    }
+
+    #[tokio::test]
+    async fn abort_pending_multipart_upload() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        let client = fake.client();
+
+        let create_req = rusoto_s3::CreateMultipartUploadRequest {
+            bucket: BUCKET.to_owned(),
+            key: "aborted".to_owned(),
+            ..Default::default()
+        };
+        let upload_id = client.create_multipart_upload(create_req).await.unwrap().upload_id.unwrap();
+        assert_eq!(fake.pending_uploads(), 1);
+
+        abort_multipart_upload(BUCKET, "aborted", &upload_id, client).await.unwrap();
+        assert_eq!(fake.pending_uploads(), 0);
+        assert_eq!(fake.object(BUCKET, "aborted"), None);
+    }
+
+    #[tokio::test]
+    async fn read_byte_ranges() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        let file = temp_file(b"0123456789");
+        upload_file(BUCKET, file.path().to_str().unwrap(), "digits", fake.client())
+            .await
+            .unwrap();
+
+        let read = |start, end| read_file(BUCKET, "digits", fake.client(), start, end);
+        assert_eq!(read(2, 5).await, Ok(Some(b"234".to_vec())));
+        assert_eq!(read(8, 20).await, Ok(Some(b"89".to_vec())));
+        assert_eq!(read(5, 5).await, Ok(Some(Vec::new())));
+        assert_eq!(read(20, 30).await, Ok(Some(Vec::new())));
+        assert_eq!(read_file(BUCKET, "missing", fake.client(), 0, 5).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn list_objects_across_pages() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        for (i, key) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            let file = temp_file(&vec![0; i + 1]);
+            upload_file(BUCKET, file.path().to_str().unwrap(), key, fake.client())
+                .await
+                .unwrap();
+        }
+
+        let objects = list_objects(BUCKET, "", fake.client()).await.unwrap();
+        let expected: Vec<(String, i64)> = vec![("a", 1), ("b", 2), ("c", 3), ("d", 4), ("e", 5)]
+            .into_iter()
+            .map(|(key, size)| (key.to_string(), size))
+            .collect();
+        assert_eq!(objects, expected);
+        let objects = list_objects(BUCKET, "c", fake.client()).await.unwrap();
+        assert_eq!(objects, vec![("c".to_string(), 3)]);
+    }
+
+    #[tokio::test]
+    async fn s3_backend_reads_archived_files() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket(BUCKET);
+        let data = temp_file(b"archived bytes");
+        let manifest = temp_file(b"[]");
+        upload_file(BUCKET, data.path().to_str().unwrap(), "archived", fake.client())
+            .await
+            .unwrap();
+        let manifest_name = format!("archived{}", S3_MANIFEST_SUFFIX);
+        upload_file(BUCKET, manifest.path().to_str().unwrap(), &manifest_name, fake.client())
+            .await
+            .unwrap();
+
+        let backend = S3Backend::new(BUCKET.to_string(), fake.client());
+        assert_eq!(backend.list("").await, Ok(vec![("archived".to_string(), 14)]));
+        assert_eq!(backend.list("other").await, Ok(Vec::new()));
+        assert_eq!(backend.count_segments("archived").await, Ok(None));
+        assert_eq!(backend.read_range("archived", 0, 8).await, Ok(Some(b"archived".to_vec())));
+        assert_eq!(backend.metadata("archived").await, Ok(Some(Vec::new())));
+        let meta = Metadata::new(String::new(), "gzip".to_string(), String::new(), 0, 0, String::new());
+        assert!(backend.append("archived", Vec::new(), meta).await.is_err());
+
+        backend.delete("archived").await.unwrap();
+        assert!(fake.keys(BUCKET).is_empty());
+        assert_eq!(backend.metadata("archived").await, Ok(None));
+    }
+}
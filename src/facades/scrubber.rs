@@ -0,0 +1,328 @@
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    time::{sleep, Duration},
+};
+use tracing::{info, warn};
+
+use super::compression::gzip_decompress;
+use super::efs_facade::{list_collection_files, read_manifest, Metadata};
+use super::integrity;
+use super::shutdown;
+
+//Pause between two segments so the scrubber never competes with the requests
+const SEGMENT_PAUSE: Duration = Duration::from_millis(5);
+
+lazy_static! {
+    static ref SCRUB_FILES: IntCounter = register_int_counter!(
+        "scrub_files_total",
+        "Number of collection files scanned by the scrubber"
+    )
+    .unwrap();
+    static ref SCRUB_SEGMENTS: IntCounter = register_int_counter!(
+        "scrub_segments_total",
+        "Number of manifest segments validated by the scrubber"
+    )
+    .unwrap();
+    static ref SCRUB_BAD_SEGMENTS: IntCounterVec = register_int_counter_vec!(
+        "scrub_bad_segments_total",
+        "Number of segments quarantined by the scrubber",
+        &["reason"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrubFailure {
+    OutOfBounds,
+    Unreadable,
+    Decompression,
+    ChecksumMismatch,
+}
+
+impl ScrubFailure {
+    fn label(&self) -> &'static str {
+        match self {
+            ScrubFailure::OutOfBounds => "out_of_bounds",
+            ScrubFailure::Unreadable => "unreadable",
+            ScrubFailure::Decompression => "decompression",
+            ScrubFailure::ChecksumMismatch => "checksum_mismatch",
+        }
+    }
+}
+
+//One line of the .quarantine file written next to the manifest
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuarantineEntry {
+    pub reason: ScrubFailure,
+    #[serde(flatten)]
+    pub meta: Metadata,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ScrubReport {
+    pub segments: usize,
+    pub bad_segments: usize,
+}
+
+//Runs a scrub pass of base every interval
+pub fn spawn_scrubber(base: String, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sleep(interval) => {},
+                _ = shutdown::wait() => break,
+            }
+            match scrub_all(&base).await {
+                Ok(report) => info!(
+                    segments = report.segments,
+                    bad_segments = report.bad_segments,
+                    "Scrub pass completed"
+                ),
+                Err(err) => warn!(error = %err, "Scrub pass failed"),
+            }
+        }
+    })
+}
+
+pub async fn scrub_all(base: &str) -> Result<ScrubReport, String> {
+    let mut report = ScrubReport::default();
+
+    for (file_path, _) in list_collection_files(base).await? {
+        let file_report = scrub_file(base, &file_path).await?;
+        report.segments += file_report.segments;
+        report.bad_segments += file_report.bad_segments;
+    }
+
+    Ok(report)
+}
+
+/*Steps
+1. Read the manifest and the segments already quarantined
+2. For every other segment, check it is within the data file, decompresses and matches its checksum
+3. Append the bad ones to the .quarantine file
+*/
+pub async fn scrub_file(base: &str, file_path: &str) -> Result<ScrubReport, String> {
+    let mut report = ScrubReport::default();
+
+    let segments = match read_manifest(base, file_path.to_string()).await? {
+        Some(segments) => segments,
+        None => return Ok(report),
+    };
+    let quarantined = read_quarantine(base, file_path).await?;
+
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .open(&format!("{base}/{}.gzip", file_path, base = base))
+        .await
+    {
+        Ok(file) => file,
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => return Ok(report),
+            _ => return Err(err.to_string()),
+        },
+    };
+    let file_len = file.metadata().await.map_err(|e| e.to_string())?.len();
+
+    SCRUB_FILES.inc();
+    for meta in segments {
+        if quarantined.contains(&(meta.start, meta.end)) {
+            continue;
+        }
+
+        report.segments += 1;
+        SCRUB_SEGMENTS.inc();
+
+        if let Some(reason) = check_segment(&mut file, file_len, &meta).await {
+            warn!(
+                file = %file_path,
+                start = meta.start,
+                end = meta.end,
+                reason = reason.label(),
+                "Quarantining bad segment"
+            );
+            SCRUB_BAD_SEGMENTS
+                .with_label_values(&[reason.label()])
+                .inc();
+            quarantine(base, file_path, QuarantineEntry { reason, meta }).await?;
+            report.bad_segments += 1;
+        }
+
+        sleep(SEGMENT_PAUSE).await;
+    }
+
+    Ok(report)
+}
+
+async fn check_segment(
+    file: &mut fs::File,
+    file_len: u64,
+    meta: &Metadata,
+) -> Option<ScrubFailure> {
+    if meta.start > meta.end || meta.end > file_len {
+        return Some(ScrubFailure::OutOfBounds);
+    }
+
+    let mut buffer = vec![0; (meta.end - meta.start) as usize];
+    if file.seek(io::SeekFrom::Start(meta.start)).await.is_err()
+        || file.read_exact(&mut buffer).await.is_err()
+    {
+        return Some(ScrubFailure::Unreadable);
+    }
+
+    if let Some(checksum) = &meta.checksum {
+        if !integrity::verify(&buffer, checksum) {
+            return Some(ScrubFailure::ChecksumMismatch);
+        }
+    }
+
+    //Decompression is CPU bound, keep it off the async workers
+    match tokio::task::spawn_blocking(move || gzip_decompress(buffer)).await {
+        Ok(Ok(_)) => None,
+        _ => Some(ScrubFailure::Decompression),
+    }
+}
+
+pub async fn read_quarantine(base: &str, file_path: &str) -> Result<HashSet<(u64, u64)>, String> {
+    let mut segments = HashSet::new();
+
+    match OpenOptions::new()
+        .read(true)
+        .open(&format!("{base}/{}.quarantine", file_path, base = base))
+        .await
+    {
+        Ok(file) => {
+            let mut lines = BufReader::new(file).lines();
+            while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+                if let Ok(entry) = serde_json::from_str::<QuarantineEntry>(&line) {
+                    segments.insert((entry.meta.start, entry.meta.end));
+                }
+            }
+            Ok(segments)
+        }
+        Err(err) => match err.kind() {
+            io::ErrorKind::NotFound => Ok(segments),
+            _ => Err(err.to_string()),
+        },
+    }
+}
+
+async fn quarantine(base: &str, file_path: &str, entry: QuarantineEntry) -> Result<(), String> {
+    let entry_str = format!(
+        "{}\n",
+        serde_json::to_string(&entry).map_err(|e| e.to_string())?
+    );
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&format!("{base}/{}.quarantine", file_path, base = base))
+        .await
+        .map_err(|e| e.to_string())?;
+    file.write_all(entry_str.as_bytes())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::facades::compression::gzip_compress;
+    use crate::facades::efs_facade::{append_segment, write_metadata};
+
+    fn efs_config(base: &tempfile::TempDir) -> Config {
+        Config {
+            base_path: base.path().to_str().unwrap().to_string(),
+            ..Config::default()
+        }
+    }
+
+    async fn write_segment(
+        config: &Config,
+        collection: &str,
+        bytes: Vec<u8>,
+    ) -> (String, u64, u64) {
+        let checksum = integrity::checksum(&bytes);
+        append_segment(config, collection.to_string(), bytes, |start, end| {
+            Metadata::new(
+                "text/plain".to_string(),
+                "gzip".to_string(),
+                "localhost".to_string(),
+                start,
+                end,
+                checksum,
+            )
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn quarantine_bad_segments() {
+        let base = tempfile::tempdir().unwrap();
+        let config = efs_config(&base);
+
+        let collection = "scrub_collection";
+        let (file, _, _) = write_segment(
+            &config,
+            collection,
+            gzip_compress(b"good".to_vec()).unwrap(),
+        )
+        .await;
+        let (_, start, _) = write_segment(
+            &config,
+            collection,
+            gzip_compress(b"corrupt".to_vec()).unwrap(),
+        )
+        .await;
+        write_segment(&config, collection, b"not gzip".to_vec()).await;
+
+        //A manifest entry pointing past the end of the data file
+        let meta = Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            1_000,
+            2_000,
+            integrity::checksum(b""),
+        );
+        write_metadata(&config, file.clone(), meta).await.unwrap();
+
+        let data_path = base.path().join(format!("{}.gzip", file));
+        let mut data = std::fs::read(&data_path).unwrap();
+        data[start as usize + 12] ^= 0xff;
+        std::fs::write(&data_path, data).unwrap();
+
+        let report = scrub_file(&config.base_path, &file).await.unwrap();
+        assert_eq!(
+            report,
+            ScrubReport {
+                segments: 4,
+                bad_segments: 3
+            }
+        );
+        assert_eq!(
+            read_quarantine(&config.base_path, &file)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        //Quarantined segments are not reported twice
+        let report = scrub_file(&config.base_path, &file).await.unwrap();
+        assert_eq!(
+            report,
+            ScrubReport {
+                segments: 1,
+                bad_segments: 0
+            }
+        );
+    }
+}
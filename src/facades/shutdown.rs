@@ -0,0 +1,83 @@
+use lazy_static::lazy_static;
+use std::sync::OnceLock;
+use tokio::{
+    signal,
+    sync::watch,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use super::efs_facade::sync_collection_file;
+use super::rotation;
+
+lazy_static! {
+    static ref SHUTDOWN: watch::Sender<bool> = watch::channel(false).0;
+}
+
+//When the shutdown started, the deadline is counted from there
+static TRIGGERED_AT: OnceLock<Instant> = OnceLock::new();
+
+pub fn trigger() {
+    TRIGGERED_AT.get_or_init(Instant::now);
+    SHUTDOWN.send_replace(true);
+}
+
+pub fn is_triggered() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+//Resolves once the shutdown started
+pub async fn wait() {
+    let mut receiver = SHUTDOWN.subscribe();
+    _ = receiver.wait_for(|shutting_down| *shutting_down).await;
+}
+
+pub fn deadline(timeout: Duration) -> Instant {
+    *TRIGGERED_AT.get().unwrap_or(&Instant::now()) + timeout
+}
+
+//Resolves on SIGTERM or SIGINT and starts the shutdown
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        if let Err(err) = signal::ctrl_c().await {
+            warn!(error = %err, "Unable to listen for SIGINT");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!(error = %err, "Unable to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+        _ = wait() => {},
+    }
+
+    info!("Shutdown started");
+    trigger();
+}
+
+/*Steps
+1. Wait for the writes still appending to a segment, new writes are blocked meanwhile
+2. fsync every segment that was open, durability mode none included
+*/
+pub async fn close_files() -> Result<usize, String> {
+    let segments = rotation::close_segments().await;
+    for (base, file_path) in &segments {
+        sync_collection_file(base, file_path).await?;
+    }
+    Ok(segments.len())
+}
@@ -0,0 +1,215 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+
+use super::efs_facade::{get_file_path, Metadata};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    Efs,
+    S3,
+    Memory,
+}
+
+impl Tier {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Tier::Efs => "efs",
+            Tier::S3 => "s3",
+            Tier::Memory => "memory",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<Tier> {
+        match label {
+            "efs" => Some(Tier::Efs),
+            "s3" => Some(Tier::S3),
+            "memory" => Some(Tier::Memory),
+            _ => None,
+        }
+    }
+}
+
+//Where the collection files live. Files are named like get_file_path and hold gzip segments.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    fn tier(&self) -> Tier;
+
+    //Appends the bytes and their manifest entry, the backend fills in start & end of meta.
+    //Returns (file, start, end)
+    async fn append(
+        &self,
+        collection: &str,
+        bytes: Vec<u8>,
+        meta: Metadata,
+    ) -> Result<(String, u64, u64), String>;
+
+    //Reads [start, end) of a file, None if the file isn't there
+    async fn read_range(&self, file: &str, start: u64, end: u64)
+        -> Result<Option<Vec<u8>>, String>;
+
+    //Every file starting with prefix with its size in bytes, "" for every file
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, String>;
+
+    async fn delete(&self, file: &str) -> Result<(), String>;
+
+    //Manifest entries of a file, None if the file isn't there
+    async fn metadata(&self, file: &str) -> Result<Option<Vec<Metadata>>, String>;
+
+    //Number of manifest entries when the tier can tell without reading them, None otherwise
+    async fn count_segments(&self, file: &str) -> Result<Option<usize>, String>;
+}
+
+//Tiers are looked up in order, writes go to the first one
+#[derive(Clone)]
+pub struct Storage {
+    tiers: Vec<Arc<dyn StorageBackend>>,
+}
+
+impl Storage {
+    pub fn new(tiers: Vec<Arc<dyn StorageBackend>>) -> Storage {
+        Storage { tiers }
+    }
+
+    pub fn in_memory() -> Storage {
+        Storage::new(vec![Arc::new(MemoryBackend::new())])
+    }
+
+    pub fn tiers(&self) -> &[Arc<dyn StorageBackend>] {
+        &self.tiers
+    }
+
+    pub fn primary(&self) -> &Arc<dyn StorageBackend> {
+        //There is always at least one tier
+        &self.tiers[0]
+    }
+}
+
+//Keeps the files in memory, for tests and dev setups without a mount. Files don't rotate.
+#[derive(Default)]
+pub struct MemoryBackend {
+    //file -> (data, manifest)
+    files: Mutex<HashMap<String, MemoryFile>>,
+}
+
+type MemoryFile = (Vec<u8>, Vec<Metadata>);
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    fn tier(&self) -> Tier {
+        Tier::Memory
+    }
+
+    async fn append(
+        &self,
+        collection: &str,
+        bytes: Vec<u8>,
+        meta: Metadata,
+    ) -> Result<(String, u64, u64), String> {
+        let file = get_file_path(collection.to_string());
+        let mut files = self.files.lock().unwrap();
+        let (data, manifest) = files.entry(file.clone()).or_default();
+
+        let start = data.len() as u64;
+        data.extend_from_slice(&bytes);
+        let end = data.len() as u64;
+        manifest.push(Metadata { start, end, ..meta });
+
+        Ok((file, start, end))
+    }
+
+    async fn read_range(
+        &self,
+        file: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let files = self.files.lock().unwrap();
+        Ok(files.get(file).map(|(data, _)| {
+            let start = (start as usize).min(data.len());
+            let end = (end as usize).clamp(start, data.len());
+            data[start..end].to_vec()
+        }))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, String> {
+        let files = self.files.lock().unwrap();
+        let mut list: Vec<(String, u64)> = files
+            .iter()
+            .filter(|(file, _)| file.starts_with(prefix))
+            .map(|(file, (data, _))| (file.clone(), data.len() as u64))
+            .collect();
+        list.sort();
+        Ok(list)
+    }
+
+    async fn delete(&self, file: &str) -> Result<(), String> {
+        self.files.lock().unwrap().remove(file);
+        Ok(())
+    }
+
+    async fn metadata(&self, file: &str) -> Result<Option<Vec<Metadata>>, String> {
+        let files = self.files.lock().unwrap();
+        Ok(files.get(file).map(|(_, manifest)| manifest.clone()))
+    }
+
+    async fn count_segments(&self, file: &str) -> Result<Option<usize>, String> {
+        let files = self.files.lock().unwrap();
+        Ok(files.get(file).map(|(_, manifest)| manifest.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            0,
+            0,
+            String::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn memory_backend_round_trip() {
+        let backend = MemoryBackend::new();
+
+        let (file, start, end) = backend
+            .append("memory_collection", vec![1; 10], metadata())
+            .await
+            .unwrap();
+        assert_eq!((start, end), (0, 10));
+        let (_, start, end) = backend
+            .append("memory_collection", vec![2; 5], metadata())
+            .await
+            .unwrap();
+        assert_eq!((start, end), (10, 15));
+
+        assert_eq!(
+            backend.read_range(&file, 10, 15).await,
+            Ok(Some(vec![2; 5]))
+        );
+        assert_eq!(backend.list("").await, Ok(vec![(file.clone(), 15)]));
+        assert_eq!(backend.list("other").await, Ok(Vec::new()));
+        assert_eq!(backend.count_segments(&file).await, Ok(Some(2)));
+
+        let manifest = backend.metadata(&file).await.unwrap().unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!((manifest[1].start, manifest[1].end), (10, 15));
+
+        backend.delete(&file).await.unwrap();
+        assert_eq!(backend.read_range(&file, 0, 10).await, Ok(None));
+        assert_eq!(backend.metadata(&file).await, Ok(None));
+    }
+}
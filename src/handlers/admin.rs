@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::super::facades;
+use crate::config::{Config, StorageKind};
+use crate::middlewares::admin_auth::admin_auth;
+use crate::state::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::NaiveDate;
+use facades::disk_monitor::{self, Pressure};
+use facades::efs_facade::{self, DiskUsage};
+use facades::{archivist, rotation};
+use serde::Serialize;
+use tracing::info;
+
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct CollectionUsage {
+    files: usize,
+    bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskReport {
+    disk: DiskUsage,
+    pressure: Pressure,
+    collections: BTreeMap<String, CollectionUsage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReloadReport {
+    //Changed in the config but only applied on the next start
+    restart_required: Vec<&'static str>,
+}
+
+//Operator routes, nested under /admin and only reachable with ADMIN_TOKEN
+pub fn admin_router(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route("/archive", get(journal_handler).post(archive_handler))
+        .route("/rotate", post(rotate_handler))
+        .route("/disk", get(disk_handler))
+        .route("/config/reload", post(reload_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.config.clone(),
+            admin_auth,
+        ))
+}
+
+/*Steps
+1. Validate the date (a past day) & collection params
+2. Queue the collection files of that day for the sealed archiver
+3. 202 with the queued files, the journal tells when they are done
+*/
+pub async fn archive_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let config = state.config.get();
+    if config.storage_backend != StorageKind::Efs
+        || config.s3_bucket.is_none()
+        || state.s3_client.is_none()
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            "S3_BUCKET must be set with the efs storage to archive",
+        )
+            .into_response();
+    }
+    let date = match params
+        .get("date")
+        .map(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+    {
+        Some(Ok(date)) => date,
+        _ => return (StatusCode::BAD_REQUEST, "expected date=YYYY-MM-DD").into_response(),
+    };
+
+    let collection = params.get("collection").map(String::as_str);
+    match archivist::list_day_files(&config.base_path, date, collection).await {
+        Ok(files) => {
+            let queued: Vec<String> = files
+                .into_iter()
+                .filter(|file| archivist::queue_file(file.clone()))
+                .collect();
+            info!(date = %date, files = queued.len(), "Archival queued");
+            (StatusCode::ACCEPTED, Json(queued)).into_response()
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, err).into_response(),
+    }
+}
+
+pub async fn journal_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(archivist::read_journal(state.pg_pool.as_ref()).await)
+}
+
+//Seals the open segments, the archiver picks them up right away
+pub async fn rotate_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let sealed = rotation::seal_segments(&state.config.get().base_path).await;
+    info!(segments = sealed.len(), "Segments sealed by an operator");
+    Json(sealed)
+}
+
+/*Steps
+1. Measure the free space and inodes of BASE_PATH
+2. Sum the collection files by collection
+*/
+pub async fn disk_handler(State(state): State<AppState>) -> Response {
+    let base = state.config.get().base_path.clone();
+    let disk = match efs_facade::disk_usage(&base) {
+        Ok(disk) => disk,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    };
+    let files = match efs_facade::list_collection_files(&base).await {
+        Ok(files) => files,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    };
+
+    let mut collections: BTreeMap<String, CollectionUsage> = BTreeMap::new();
+    for (file, size) in files {
+        if let Some((collection, _, _)) = efs_facade::parse_file_path(&file) {
+            let usage = collections.entry(collection).or_default();
+            usage.files += 1;
+            usage.bytes += size;
+        }
+    }
+
+    let report = DiskReport {
+        disk,
+        pressure: disk_monitor::get_pressure(&base),
+        collections,
+    };
+    (StatusCode::OK, Json(report)).into_response()
+}
+
+/*Steps
+1. Load the config again (.env, CONFIG_FILE, environment), an invalid one changes nothing
+2. Keep what is only read at startup and report it if it changed
+3. Swap the config, the next requests use it
+*/
+pub async fn reload_handler(State(state): State<AppState>) -> Response {
+    let loaded = match Config::load() {
+        Ok(loaded) => loaded,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let (config, restart_required) = apply_reload(&state.config.get(), loaded);
+    state.config.set(config);
+    info!(restart_required = ?restart_required, "Config reloaded");
+    (StatusCode::OK, Json(ReloadReport { restart_required })).into_response()
+}
+
+//The loaded config with the startup only fields of the running one, and the ones that differed
+fn apply_reload(running: &Config, mut loaded: Config) -> (Config, Vec<&'static str>) {
+    let mut restart_required = Vec::new();
+    macro_rules! keep {
+        ($($field:ident),*) => {$(
+            if loaded.$field != running.$field {
+                restart_required.push(stringify!($field));
+                loaded.$field = running.$field.clone();
+            }
+        )*};
+    }
+    keep!(
+        addr,
+        with_logs,
+        base_path,
+        legacy_path,
+        storage_backend,
+        node_id,
+        node_id_file,
+        s3_bucket,
+        s3_endpoint,
+        s3_region,
+        postgres,
+        postgres_migrate,
+        with_catalog,
+        with_recovery,
+        with_scrubber,
+        scrub_interval,
+        shutdown_timeout,
+        telemetry
+    );
+    (loaded, restart_required)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_router;
+    use crate::facades::fake_s3::FakeS3;
+    use crate::facades::shutdown;
+    use axum::body::Body;
+    use chrono::Utc;
+    use hyper::{body::to_bytes, header, Method, Request};
+    use serial_test::serial;
+    use std::{env, time::Duration};
+    use tower::ServiceExt;
+
+    const TOKEN: &str = "admin-secret";
+
+    async fn call(
+        state: AppState,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Body::empty()).unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+        (status, body)
+    }
+
+    fn efs_state(base_path: &str) -> AppState {
+        AppState::from_config(Config {
+            base_path: base_path.to_string(),
+            admin_token: Some(TOKEN.to_string()),
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    async fn post_payload(state: &AppState, collection: &str) -> String {
+        let request = Request::post(format!("/collection/{}", collection))
+            .header("Content-Type", "text/plain")
+            .header("Host", "localhost")
+            .body(Body::from("admin payload"))
+            .unwrap();
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        assert!(response.status().is_success());
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let reference = String::from_utf8(body.to_vec()).unwrap();
+        reference.split_once('?').unwrap().0.to_string()
+    }
+
+    #[tokio::test]
+    async fn admin_requires_the_token() {
+        let (status, _) = call(AppState::in_memory(), Method::GET, "/admin/archive", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let state = efs_state("/");
+        let (status, _) = call(state.clone(), Method::GET, "/admin/archive", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(state.clone(), Method::GET, "/admin/archive", Some("guess")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = call(state, Method::GET, "/admin/archive", Some(TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["pending"].is_array());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn disk_usage_and_rotation() {
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap();
+        let state = efs_state(base_path);
+        let file = post_payload(&state, "admin_disk_collection").await;
+
+        let (status, body) = call(state.clone(), Method::GET, "/admin/disk", Some(TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["pressure"], "normal");
+        assert_eq!(body["collections"]["admin_disk_collection"]["files"], 1);
+        assert!(body["disk"]["free_bytes"].as_u64().unwrap() > 0);
+
+        let (status, body) = call(state, Method::POST, "/admin/rotate", Some(TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!([file]));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn archive_a_past_day() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket("admin-bucket");
+        env::set_var("AWS_ACCESS_KEY_ID", "fake");
+        env::set_var("AWS_SECRET_ACCESS_KEY", "fake");
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap();
+
+        let state = AppState::from_config(Config {
+            s3_bucket: Some("admin-bucket".to_string()),
+            s3_endpoint: Some(fake.endpoint().to_string()),
+            s3_region: Some("fake".to_string()),
+            ..(*efs_state(base_path).config.get()).clone()
+        })
+        .unwrap();
+        let file = post_payload(&state, "admin_archived_collection").await;
+        //Written today, renamed as if it was from an earlier day
+        let old_file = file.replace(&Utc::now().format("%Y-%m-%d").to_string(), "2023-08-01");
+        for extension in [".gzip", ".manifest"] {
+            std::fs::rename(
+                base.path().join(format!("{}{}", file, extension)),
+                base.path().join(format!("{}{}", old_file, extension)),
+            )
+            .unwrap();
+        }
+
+        let today = format!("/admin/archive?date={}", Utc::now().date_naive());
+        let (status, _) = call(state.clone(), Method::POST, &today, Some(TOKEN)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let archiver = archivist::spawn_sealed_archiver(
+            base_path.to_string(),
+            "admin-bucket".to_string(),
+            fake.client(),
+            None,
+            false,
+        );
+        let uri = "/admin/archive?date=2023-08-01&collection=admin_archived_collection";
+        let (status, body) = call(state.clone(), Method::POST, uri, Some(TOKEN)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body, serde_json::json!([old_file]));
+
+        let mut archived = false;
+        for _ in 0..100 {
+            let (_, journal) =
+                call(state.clone(), Method::GET, "/admin/archive", Some(TOKEN)).await;
+            archived = journal["recent"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|entry| entry["file"] == old_file.as_str() && entry["error"].is_null());
+            if archived {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(archived);
+        assert!(fake.object("admin-bucket", &old_file).is_some());
+
+        archiver.abort();
+        assert!(!shutdown::is_triggered());
+        env::remove_var("AWS_ACCESS_KEY_ID");
+        env::remove_var("AWS_SECRET_ACCESS_KEY");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn reload_keeps_the_startup_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.toml");
+        std::fs::write(&file, "app_port = 6000\nretry_after_seconds = 7\n").unwrap();
+        env::set_var("CONFIG_FILE", &file);
+
+        let state = efs_state("/");
+        let (status, body) = call(
+            state.clone(),
+            Method::POST,
+            "/admin/config/reload",
+            Some(TOKEN),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["restart_required"], serde_json::json!(["addr"]));
+        let config = state.config.get();
+        assert_eq!(config.retry_after, Duration::from_secs(7));
+        assert_eq!(config.addr.port(), 5000);
+        //ADMIN_TOKEN isn't in the file anymore, the admin routes are gone
+        assert_eq!(config.admin_token, None);
+
+        std::fs::write(&file, "compression_level = 12\n").unwrap();
+        let state = efs_state("/");
+        let (status, _) = call(
+            state.clone(),
+            Method::POST,
+            "/admin/config/reload",
+            Some(TOKEN),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(state.config.get().compression_level, 6);
+
+        env::remove_var("CONFIG_FILE");
+    }
+}
@@ -1,46 +1,71 @@
 use std::collections::HashMap;
-use std::time::Instant;
 
 use crate::facades::efs_facade::Metadata;
 
 use super::super::facades;
-use axum::extract::{Path, Query, State};
+use super::listing::segments_handler;
+use crate::config::StorageKind;
+use crate::middlewares::tracing::{record_compression, record_reference, record_tier};
+use crate::state::AppState;
+use axum::extract::{Path, State};
 use axum::{
     http::{
-        header::{self, HeaderMap, HeaderName},
+        header::{self, HeaderMap},
         StatusCode,
     },
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use deadpool_postgres::{Object, Pool};
-use facades::compression::gzip_compress;
-use facades::efs_facade::{
-    append_bytes_collection as write_efs, get_collection_byte_range as read_efs, write_metadata,
-};
-use facades::s3::{init_client as init_s3_client, read_file as read_s3};
+use facades::catalog::{self, CatalogEntry};
+use facades::compression::gzip_compress_level;
+use facades::dedup;
+use facades::disk_monitor::{self, Pressure};
+use facades::efs_facade::{parse_file_path, DISK_FULL};
+use facades::efs_migration;
+use facades::idempotency;
+use facades::integrity;
+use facades::scrubber;
+use facades::storage::Storage;
 use hyper::body::to_bytes;
 use hyper::{Body, Method, Request};
 
 pub async fn collection_handler(
+    State(state): State<AppState>,
     Path(collection): Path<String>,
     request: Request<Body>,
 ) -> impl IntoResponse {
     match *request.method() {
+        //The wildcard route also catches /collection/{name}/segments
+        Method::GET if collection.ends_with("/segments") => {
+            let params = extract_query_params(&request.uri().to_string());
+            let name = collection.trim_end_matches("/segments").to_string();
+            segments_handler(&state.storage, name, params)
+                .await
+                .into_response()
+        }
         Method::GET => {
             let params = extract_query_params(&request.uri().to_string());
             match (
                 params.get("start").map(|s| s.parse::<u64>()),
                 params.get("end").map(|e| e.parse::<u64>()),
             ) {
-                (Some(Ok(start)), Some(Ok(end))) => {
-                    match get_handler(collection, start, end).await {
+                (Some(Ok(start)), Some(Ok(end))) if start < end => {
+                    record_reference(&format!("{}?start={}&end={}", collection, start, end));
+                    match get_handler(&state, collection, start, end).await {
                         Ok(Some(bytes)) => {
+                            let checksum = integrity::checksum(&bytes);
                             let mut headers = HeaderMap::new();
                             headers.insert(
                                 header::CONTENT_TYPE,
                                 "application/octet-stream".parse().unwrap(),
                             );
                             headers.insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+                            headers.insert(
+                                header::ETAG,
+                                integrity::etag_header(&checksum).parse().unwrap(),
+                            );
+                            if let Some(digest) = integrity::digest_header(&checksum) {
+                                headers.insert("Digest", digest.parse().unwrap());
+                            }
                             (StatusCode::OK, headers, bytes).into_response()
                         }
                         Ok(None) => (
@@ -59,23 +84,61 @@ pub async fn collection_handler(
             }
         }
         Method::POST => {
-            let content_type = request
-                .headers()
-                .get("Content-Type")
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            let host = request
-                .headers()
-                .get("Host")
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
+            //Rejected before the body is read, the client retries once segments are archived
+            let config = state.config.get();
+            if config.storage_backend == StorageKind::Efs
+                && disk_monitor::get_pressure(&config.base_path) == Pressure::Critical
+            {
+                return storage_full(&state);
+            }
+            let (content_type, host) = match (
+                request.headers().get("Content-Type").map(|v| v.to_str()),
+                request.headers().get("Host").map(|v| v.to_str()),
+            ) {
+                (Some(Ok(content_type)), Some(Ok(host))) => {
+                    (content_type.to_string(), host.to_string())
+                }
+                _ => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        "Content-Type and Host headers are required".to_string(),
+                    )
+                        .into_response()
+                }
+            };
+            let idempotency_key = match request.headers().get("Idempotency-Key") {
+                Some(value) => match value.to_str() {
+                    Ok(key) if idempotency::is_valid_key(key) => Some(key.to_string()),
+                    _ => {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            "invalid Idempotency-Key header".to_string(),
+                        )
+                            .into_response()
+                    }
+                },
+                None => None,
+            };
             let bytes = to_bytes(request.into_body()).await.unwrap().to_vec();
-            match post_handler(collection, bytes, content_type, host).await {
-                Ok(file_path) => (StatusCode::OK, file_path).into_response(),
+            match post_handler(
+                &state,
+                collection,
+                bytes,
+                content_type,
+                host,
+                idempotency_key,
+            )
+            .await
+            {
+                Ok(file_path) => {
+                    record_reference(&file_path);
+                    (StatusCode::OK, file_path).into_response()
+                }
+                //Full before the monitor noticed
+                Err(err) if err.starts_with(DISK_FULL) => {
+                    disk_monitor::set_pressure(&config.base_path, Pressure::Critical);
+                    storage_full(&state)
+                }
                 Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
             }
         }
@@ -96,47 +159,251 @@ pub async fn collection_handler(
     }
 }
 
+//503 when the archiver frees space on its own, 507 when only an operator can
+fn storage_full(state: &AppState) -> Response {
+    let config = state.config.get();
+    let status = match config.s3_bucket {
+        Some(_) => StatusCode::SERVICE_UNAVAILABLE,
+        None => StatusCode::INSUFFICIENT_STORAGE,
+    };
+    (
+        status,
+        [(
+            header::RETRY_AFTER,
+            config.retry_after.as_secs().to_string(),
+        )],
+        "not enough space left to store the payload".to_string(),
+    )
+        .into_response()
+}
+
 /*Steps
 1. extract archive and range from reference
-2. Check efs (return if found)
-3. Check S3 (return if found)
-4. If nothing found... cry :(
+2. Map a reference of the old facade to where the migration moved it
+3. Refuse the ranges the scrubber quarantined
+4. With the catalog, read from the tier it points to (one query, no manifest lookups)
+5. Otherwise check every tier in order (return if the whole range is found and the checksum matches)
+6. If nothing found... cry :(
 */
-async fn get_handler(collection: String, start: u64, end: u64) -> Result<Option<Vec<u8>>, String> {
-    let mut res: Option<Vec<u8>> = read_efs(collection, start, end).await?;
+pub async fn get_handler(
+    state: &AppState,
+    collection: String,
+    start: u64,
+    end: u64,
+) -> Result<Option<Vec<u8>>, String> {
+    let config = state.config.get();
+    //Legacy archives are named after the collection alone, without node and date
+    let (collection, start, end) = match parse_file_path(&collection) {
+        None if config.storage_backend == StorageKind::Efs => {
+            let legacy = format!("{}?start={}&end={}", collection, start, end);
+            efs_migration::resolve_reference(&config.base_path, &legacy)
+                .await?
+                .unwrap_or((collection, start, end))
+        }
+        _ => (collection, start, end),
+    };
+
+    if config.storage_backend == StorageKind::Efs
+        && scrubber::read_quarantine(&config.base_path, &collection)
+            .await?
+            .contains(&(start, end))
+    {
+        return Err(format!(
+            "segment quarantined by the scrubber: {}?start={}&end={}",
+            collection, start, end
+        ));
+    }
+    if let Some(pool) = state.catalog() {
+        if let Some(entry) = catalog::lookup(pool, &collection, start, end).await {
+            if let Some(bytes) = read_from_catalog(&state.storage, &entry).await? {
+                return Ok(Some(bytes));
+            }
+        }
+    }
+    read_from_tiers(&state.storage, collection, start, end).await
+}
+
+//None when the tier of the entry doesn't have the segment (yet), the tiers are checked then
+async fn read_from_catalog(
+    storage: &Storage,
+    entry: &CatalogEntry,
+) -> Result<Option<Vec<u8>>, String> {
+    let tier = match storage
+        .tiers()
+        .iter()
+        .find(|tier| tier.tier() == entry.tier)
+    {
+        Some(tier) => tier,
+        None => return Ok(None),
+    };
+    let file = entry.s3_key.as_deref().unwrap_or(&entry.file);
+    let bytes = match tier.read_range(file, entry.start, entry.end).await? {
+        Some(bytes) if bytes.len() as u64 == entry.end - entry.start => bytes,
+        _ => return Ok(None),
+    };
+    match &entry.checksum {
+        Some(checksum) if !integrity::verify(&bytes, checksum) => {
+            integrity::record_mismatch(tier.tier().label());
+            Ok(None)
+        }
+        _ => {
+            record_tier(tier.tier());
+            Ok(Some(bytes))
+        }
+    }
+}
 
-    if res.is_none() {
-        //read S3
-        // let client = init_s3_client();
-        // res = read_s3(&collection, client, start, end).await.map(|val| Some(val))?;
+async fn read_from_tiers(
+    storage: &Storage,
+    collection: String,
+    start: u64,
+    end: u64,
+) -> Result<Option<Vec<u8>>, String> {
+    let mut expected: Option<String> = None;
+    let mut corrupted = false;
+
+    for tier in storage.tiers() {
+        if expected.is_none() {
+            //Archived files took their manifest with them
+            expected = tier
+                .metadata(&collection)
+                .await?
+                .and_then(|segments| {
+                    segments
+                        .into_iter()
+                        .find(|meta| meta.start == start && meta.end == end)
+                })
+                .and_then(|meta| meta.checksum);
+        }
+
+        if let Some(bytes) = tier.read_range(&collection, start, end).await? {
+            //A short read means the range goes past the end of the file
+            if bytes.len() as u64 != end - start {
+                continue;
+            }
+            match &expected {
+                Some(checksum) if !integrity::verify(&bytes, checksum) => {
+                    integrity::record_mismatch(tier.tier().label());
+                    corrupted = true;
+                }
+                _ => {
+                    record_tier(tier.tier());
+                    return Ok(Some(bytes));
+                }
+            }
+        }
     }
 
-    Ok(res)
+    match corrupted {
+        true => Err(format!(
+            "checksum mismatch for {}?start={}&end={}",
+            collection, start, end
+        )),
+        false => Ok(None),
+    }
 }
 
 /*Steps
-1. Compress bytes
-2. Ask BD for current offset
-3. Create file name
-4. Send to EFS
-5. Return file name
+1. If an Idempotency-Key was already used, return its original reference (Postgres if configured)
+2. If dedup is enabled, return the reference of an identical payload stored today
+3. Compress bytes
+4. Ask BD for current offset
+5. Create file name
+6. Send to the first storage tier
+7. Return file name
 */
 async fn post_handler(
+    state: &AppState,
     collection: String,
     bytes: Vec<u8>,
     content_type: String,
     host: String,
+    idempotency_key: Option<String>,
 ) -> Result<String, String> {
+    let config = state.config.get();
+    //Held until the reference is recorded so a concurrent retry waits for it
+    let _key_guard = match &idempotency_key {
+        Some(key) => {
+            let guard = idempotency::lock_key(&collection, key).await;
+            let window = config.idempotency_window;
+            let found = match &state.pg_pool {
+                Some(pool) => {
+                    idempotency::find_reference_pg(pool, &collection, key, window).await?
+                }
+                None => idempotency::find_reference(&config, &collection, key).await?,
+            };
+            if let Some(reference) = found {
+                return Ok(reference);
+            }
+            Some(guard)
+        }
+        None => None,
+    };
+
+    let reference = store_payload(state, collection.clone(), bytes, content_type, host).await?;
+
+    if let Some(key) = &idempotency_key {
+        match &state.pg_pool {
+            Some(pool) => {
+                let window = config.idempotency_window;
+                idempotency::record_reference_pg(pool, &collection, key, &reference, window).await?
+            }
+            None => {
+                idempotency::record_reference(&config, &collection, key, reference.clone()).await?
+            }
+        }
+    }
+
+    Ok(reference)
+}
+
+async fn store_payload(
+    state: &AppState,
+    collection: String,
+    bytes: Vec<u8>,
+    content_type: String,
+    host: String,
+) -> Result<String, String> {
+    let config = state.config.get();
+    let hash = if config.with_dedup {
+        let hash = dedup::hash_bytes(&bytes);
+        let found = dedup::find_reference(&config, &collection, &hash, bytes.len()).await?;
+        if let Some(reference) = found {
+            return Ok(reference);
+        }
+        Some(hash)
+    } else {
+        None
+    };
+
     //Start the timer
     // let compress_start = Instant::now();
-    match gzip_compress(bytes) {
+    let raw_bytes = bytes.len();
+    match gzip_compress_level(bytes, config.compression_level) {
         Ok(compressed) => {
+            record_compression(raw_bytes, compressed.len());
             // println!("COMPRESS => {}ms", compress_start.elapsed().as_millis().to_string());
 
+            let checksum = integrity::checksum(&compressed);
+
             // let write_efs_start = Instant::now();
-            let write_res = write_efs(collection.clone(), compressed)
-                .await
-                .map_err(|e| e.to_string())?;
+            //The backend fills in where the bytes landed
+            let meta = Metadata::new(content_type, "gzip".to_string(), host, 0, 0, checksum);
+            let write_res = state
+                .storage
+                .primary()
+                .append(&collection, compressed, meta.clone())
+                .await?;
+            record_tier(state.storage.primary().tier());
+            if let Some(pool) = state.catalog() {
+                let entry = CatalogEntry::new(
+                    &collection,
+                    (&write_res.0, write_res.1, write_res.2),
+                    &meta,
+                    state.storage.primary().tier(),
+                );
+                catalog::record_segment(pool, &entry).await;
+            }
             let formatted_path = format!(
                 "{file}?start={start}&end={end}",
                 file = write_res.0,
@@ -146,16 +413,9 @@ async fn post_handler(
             .to_string();
             // println!("EFS => {}ms", write_efs_start.elapsed().as_millis().to_string());
 
-            // let meta_start = Instant::now();
-            let meta = Metadata::new(
-                content_type,
-                "gzip".to_string(),
-                host,
-                write_res.1,
-                write_res.2,
-            );
-            write_metadata(collection, meta).await?;
-            // println!("META => {}ms", meta_start.elapsed().as_millis().to_string());
+            if let Some(hash) = hash {
+                dedup::record_reference(&config, &collection, hash, formatted_path.clone()).await?;
+            }
 
             Ok(formatted_path)
         }
@@ -175,7 +435,7 @@ async fn post_handler(
 //     }
 // }
 
-fn extract_query_params(url: &str) -> HashMap<String, String> {
+pub fn extract_query_params(url: &str) -> HashMap<String, String> {
     let mut params: HashMap<String, String> = HashMap::new();
 
     if let Some(query_str) = url.split_once('?') {
@@ -194,10 +454,18 @@ mod tests {
     use std::{fs, path::Path};
 
     use super::*;
-    use chrono::{Datelike, Utc};
-    use facades::compression::{gzip_compress as compress, gzip_decompress as decompress};
-    use facades::efs_facade::get_file_path;
-    use tokio_postgres::Config;
+    use crate::config::Config;
+    use crate::create_router;
+    use crate::state::SharedConfig;
+    use axum::Router;
+    use facades::archivist;
+    use facades::compression::gzip_decompress;
+    use facades::efs_facade::{self, EfsBackend};
+    use facades::fake_s3::FakeS3;
+    use facades::s3::S3Backend;
+    use facades::storage::Tier;
+    use std::sync::Arc;
+    use tower::ServiceExt;
 
     fn load_test_files() -> Vec<Vec<u8>> {
         let mut files: Vec<Vec<u8>> = Vec::new();
@@ -211,12 +479,6 @@ mod tests {
         files
     }
 
-    fn delete_test_folders() {
-        let dir_path = "test_collection";
-
-        assert!(fs::remove_dir_all(dir_path).is_ok());
-    }
-
     fn load_test_file(index: usize) -> Vec<u8> {
         let path_str =
             format!("test/collections_testing/test_files/test_{}.txt", index).to_string();
@@ -226,37 +488,424 @@ mod tests {
         fs::read(path).unwrap()
     }
 
-    // #[tokio::test]
-    // async fn get_post_integration_test() {
-    //     let test_files = load_test_files();
+    #[tokio::test]
+    async fn post_duplicate_with_dedup() {
+        //With the memory backend the base path is never written to
+        let state = AppState::in_memory();
+        state.config.set(Config {
+            base_path: "/nonexistent".to_string(),
+            storage_backend: StorageKind::Memory,
+            with_dedup: true,
+            ..Config::default()
+        });
+
+        let bytes = load_test_file(1);
+        let post = |bytes: Vec<u8>| {
+            post_handler(
+                &state,
+                "dedup_test_collection".to_string(),
+                bytes,
+                "text/plain".to_string(),
+                "localhost".to_string(),
+                None,
+            )
+        };
+
+        let first = post(bytes.clone()).await.unwrap();
+        let second = post(bytes.clone()).await.unwrap();
+        let other = post(load_test_file(2)).await.unwrap();
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[tokio::test]
+    async fn post_retry_with_idempotency_key() {
+        //With the memory backend the base path is never written to
+        let state = AppState::in_memory();
+        state.config.set(Config {
+            base_path: "/nonexistent".to_string(),
+            storage_backend: StorageKind::Memory,
+            ..Config::default()
+        });
+
+        let post = |bytes: Vec<u8>, key: Option<&str>| {
+            post_handler(
+                &state,
+                "idempotency_test_collection".to_string(),
+                bytes,
+                "text/plain".to_string(),
+                "localhost".to_string(),
+                key.map(|k| k.to_string()),
+            )
+        };
 
-    //     let test_collection_name = "test_collection".to_string();
+        let first = post(load_test_file(3), Some("key-1")).await.unwrap();
+        //The retry is not written again, even if the body differs
+        let retry = post(load_test_file(4), Some("key-1")).await.unwrap();
+        let other = post(load_test_file(3), Some("key-2")).await.unwrap();
+        let no_key = post(load_test_file(3), None).await.unwrap();
 
-    //     for bytes in test_files.iter() {
-    //         let collection_name = test_collection_name.clone();
-    //         //Add the value
-    //         let post_res = post_handler(collection_name.clone(), bytes.to_vec()).await;
-    //         assert!(post_res.is_ok());
+        assert_eq!(first, retry);
+        assert_ne!(first, other);
+        assert_ne!(other, no_key);
+    }
+
+    #[tokio::test]
+    async fn get_detects_corrupted_segment() {
+        let base = tempfile::tempdir().unwrap();
+        let state = AppState {
+            storage: Storage::new(vec![Arc::new(EfsBackend::new(
+                base.path().to_str().unwrap(),
+            ))]),
+            ..AppState::in_memory()
+        };
+
+        let reference = post_handler(
+            &state,
+            "integrity_test_collection".to_string(),
+            load_test_file(5),
+            "text/plain".to_string(),
+            "localhost".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+        let (file, _) = reference.split_once('?').unwrap();
+        let params = extract_query_params(&reference);
+        let start = params.get("start").unwrap().parse::<u64>().unwrap();
+        let end = params.get("end").unwrap().parse::<u64>().unwrap();
+
+        let res = get_handler(&state, file.to_string(), start, end).await;
+        assert!(matches!(res, Ok(Some(_))));
+
+        //Flip a byte of the stored segment
+        let data_path = base.path().join(format!("{}.gzip", file));
+        let mut data = fs::read(&data_path).unwrap();
+        data[(start + 10) as usize] ^= 0xff;
+        fs::write(&data_path, data).unwrap();
+
+        let res = get_handler(&state, file.to_string(), start, end).await;
+        assert!(res.is_err());
+    }
 
-    //         let file_path = post_res.unwrap();
-    //         let params = extract_query_params(&file_path.clone());
-    //         let start = params.get("start").unwrap().parse::<u64>().unwrap();
-    //         let end = params.get("end").unwrap().parse::<u64>().unwrap();
+    fn efs_state(base: &Path) -> AppState {
+        AppState {
+            storage: Storage::new(vec![Arc::new(EfsBackend::new(base.to_str().unwrap()))]),
+            ..AppState::in_memory()
+        }
+    }
 
-    //         let get_res = get_handler(get_file_path(collection_name), start, end).await;
-    //         assert!(get_res.is_ok());
-    //         assert!(get_res.as_ref().unwrap().is_some());
+    fn post_request(collection: &str, bytes: Vec<u8>) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("/collection/{}", collection))
+            .header("Content-Type", "text/plain")
+            .header("Host", "localhost")
+            .body(Body::from(bytes))
+            .unwrap()
+    }
 
-    //         let compressed_bytes = get_res.unwrap().unwrap();
+    fn get_request(reference: &str) -> Request<Body> {
+        Request::builder()
+            .uri(format!("/collection/{}", reference))
+            .body(Body::empty())
+            .unwrap()
+    }
 
-    //         //Validate that the get function works
-    //         assert_eq!(compress(bytes.clone()).unwrap(), compressed_bytes);
+    async fn send(router: &Router, request: Request<Body>) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = to_bytes(response.into_body()).await.unwrap().to_vec();
+        (status, headers, body)
+    }
 
-    //         let decompress = decompress(compressed_bytes.clone()).unwrap();
+    async fn post(router: &Router, collection: &str, bytes: Vec<u8>) -> String {
+        let (status, _, body) = send(router, post_request(collection, bytes)).await;
+        assert_eq!(status, StatusCode::OK);
+        String::from_utf8(body).unwrap()
+    }
 
-    //         assert_eq!(decompress.len(), bytes.len());
-    //         //Make sure the values are the same
-    //         assert_eq!(decompress, bytes.clone())
-    //     }
-    // }
+    async fn get_decompressed(router: &Router, reference: &str) -> Vec<u8> {
+        let (status, headers, body) = send(router, get_request(reference)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        gzip_decompress(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_post_integration_test() {
+        let base = tempfile::tempdir().unwrap();
+        let router = create_router(efs_state(base.path()));
+
+        let mut references = Vec::new();
+        for bytes in load_test_files() {
+            let reference = post(&router, "integration_test_collection", bytes.clone()).await;
+            assert_eq!(get_decompressed(&router, &reference).await, bytes);
+            references.push(reference);
+        }
+
+        //Every payload is still readable once the others were appended
+        for (reference, bytes) in references.iter().zip(load_test_files()) {
+            assert_eq!(get_decompressed(&router, reference).await, bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_writers() {
+        let base = tempfile::tempdir().unwrap();
+        let router = create_router(efs_state(base.path()));
+
+        let writers: Vec<_> = load_test_files()
+            .into_iter()
+            .map(|bytes| {
+                let router = router.clone();
+                let request = post_request("concurrent_test_collection", bytes.clone());
+                tokio::spawn(async move {
+                    let response = router.oneshot(request).await.unwrap();
+                    assert_eq!(response.status(), StatusCode::OK);
+                    let reference = to_bytes(response.into_body()).await.unwrap();
+                    (String::from_utf8(reference.to_vec()).unwrap(), bytes)
+                })
+            })
+            .collect();
+
+        let mut ranges = Vec::new();
+        for writer in writers {
+            let (reference, bytes) = writer.await.unwrap();
+            assert_eq!(get_decompressed(&router, &reference).await, bytes);
+
+            let (file, _) = reference.split_once('?').unwrap();
+            let params = extract_query_params(&reference);
+            let start = params["start"].parse::<u64>().unwrap();
+            let end = params["end"].parse::<u64>().unwrap();
+            ranges.push((file.to_string(), start, end));
+        }
+
+        //No two writes share bytes
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            assert!(pair[0].0 != pair[1].0 || pair[0].2 <= pair[1].1);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_missing_ranges() {
+        let base = tempfile::tempdir().unwrap();
+        let router = create_router(efs_state(base.path()));
+
+        let reference = post(&router, "missing_test_collection", load_test_file(6)).await;
+        let (file, _) = reference.split_once('?').unwrap();
+        let end = extract_query_params(&reference)["end"]
+            .parse::<u64>()
+            .unwrap();
+
+        let missing = [
+            format!("{}?start={}&end={}", file, end, end + 10),
+            format!("{}?start=0&end={}", file, end + 1),
+            format!("{}x?start=0&end={}", file, end),
+        ];
+        for reference in missing {
+            let (status, _, _) = send(&router, get_request(&reference)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", reference);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_refuses_quarantined_segment() {
+        let fake = FakeS3::start().await;
+        fake.create_bucket("quarantine-bucket");
+        let base = tempfile::tempdir().unwrap();
+        let config = Config {
+            base_path: base.path().to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+        let state = AppState::from_config(config.clone()).unwrap();
+        let router = create_router(AppState {
+            storage: Storage::new(vec![
+                state.storage.primary().clone(),
+                Arc::new(S3Backend::new(
+                    "quarantine-bucket".to_string(),
+                    fake.client(),
+                )),
+            ]),
+            ..state
+        });
+
+        //The checksum matches, only the scrubber knows it doesn't decompress
+        let bytes = b"not gzip".to_vec();
+        let checksum = integrity::checksum(&bytes);
+        let (file, start, end) = efs_facade::append_segment(
+            &config,
+            "quarantined_collection".to_string(),
+            bytes,
+            |start, end| {
+                Metadata::new(
+                    "text/plain".to_string(),
+                    "gzip".to_string(),
+                    "localhost".to_string(),
+                    start,
+                    end,
+                    checksum,
+                )
+            },
+        )
+        .await
+        .unwrap();
+        let reference = format!("{}?start={}&end={}", file, start, end);
+        let (status, _, _) = send(&router, get_request(&reference)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let report = scrubber::scrub_file(&config.base_path, &file)
+            .await
+            .unwrap();
+        assert_eq!(report.bad_segments, 1);
+        let (status, _, body) = send(&router, get_request(&reference)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(String::from_utf8(body).unwrap().contains("quarantined"));
+
+        //Not archived, S3 would serve it without knowing
+        let archived =
+            archivist::archive_file(&config.base_path, &file, "quarantine-bucket", fake.client())
+                .await;
+        assert!(archived.is_err());
+        assert!(fake.keys("quarantine-bucket").is_empty());
+        let (status, _, _) = send(&router, get_request(&reference)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn get_a_migrated_legacy_reference() {
+        let legacy = tempfile::tempdir().unwrap();
+        let archive_path = legacy.path().join("legacy_get_collection");
+        fs::create_dir(&archive_path).unwrap();
+        let bytes = load_test_file(7);
+        let compressed = gzip_compress_level(bytes.clone(), 6).unwrap();
+        fs::write(archive_path.join("10-20"), &compressed).unwrap();
+
+        let base = tempfile::tempdir().unwrap();
+        let config = Config {
+            base_path: base.path().to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+        efs_migration::migrate_legacy_archives(legacy.path().to_str().unwrap(), &config)
+            .await
+            .unwrap();
+
+        let router = create_router(AppState::from_config(config).unwrap());
+        let reference = "legacy_get_collection?start=10&end=20";
+        assert_eq!(get_decompressed(&router, reference).await, bytes);
+        let (status, _, _) =
+            send(&router, get_request("legacy_get_collection?start=0&end=10")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn bad_params() {
+        let router = create_router(AppState::in_memory());
+
+        for reference in [
+            "bad_params_collection",
+            "bad_params_collection?start=0",
+            "bad_params_collection?start=abc&end=10",
+            "bad_params_collection?start=10&end=10",
+            "bad_params_collection?start=10&end=5",
+        ] {
+            let (status, _, _) = send(&router, get_request(reference)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", reference);
+        }
+
+        let no_headers = Request::builder()
+            .method(Method::POST)
+            .uri("/collection/bad_params_collection")
+            .body(Body::from("bytes"))
+            .unwrap();
+        let (status, _, _) = send(&router, no_headers).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let mut bad_key = post_request("bad_params_collection", b"bytes".to_vec());
+        bad_key
+            .headers_mut()
+            .insert("Idempotency-Key", "k".repeat(1000).parse().unwrap());
+        let (status, _, _) = send(&router, bad_key).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn writes_rejected_when_disk_is_full() {
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap().to_string();
+        let config = Config {
+            base_path: base_path.clone(),
+            ..Config::default()
+        };
+        let router = create_router(AppState::from_config(config.clone()).unwrap());
+
+        disk_monitor::set_pressure(&base_path, Pressure::Critical);
+        let (status, headers, _) =
+            send(&router, post_request("full_collection", vec![1; 10])).await;
+        assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(headers[header::RETRY_AFTER], "30");
+
+        //The archiver will make room
+        let archived = create_router(AppState {
+            config: SharedConfig::new(Config {
+                s3_bucket: Some("full-bucket".to_string()),
+                ..config
+            }),
+            ..AppState::from_config(Config {
+                base_path: base_path.clone(),
+                ..Config::default()
+            })
+            .unwrap()
+        });
+        let (status, headers, _) =
+            send(&archived, post_request("full_collection", vec![1; 10])).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(headers.contains_key(header::RETRY_AFTER));
+
+        disk_monitor::set_pressure(&base_path, Pressure::Normal);
+        post(&router, "full_collection", vec![1; 10]).await;
+    }
+
+    #[tokio::test]
+    async fn read_through_a_catalog_entry() {
+        let storage = Storage::in_memory();
+        let compressed = gzip_compress_level(b"catalog payload".to_vec(), 6).unwrap();
+        let meta = Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            0,
+            0,
+            integrity::checksum(&compressed),
+        );
+        let (file, start, end) = storage
+            .primary()
+            .append("catalog_collection", compressed.clone(), meta.clone())
+            .await
+            .unwrap();
+        let entry = CatalogEntry::new(
+            "catalog_collection",
+            (&file, start, end),
+            &meta,
+            Tier::Memory,
+        );
+
+        let bytes = read_from_catalog(&storage, &entry).await.unwrap();
+        assert_eq!(bytes, Some(compressed));
+
+        //Stale entries fall back to the tiers
+        let archived = CatalogEntry {
+            tier: Tier::S3,
+            ..entry.clone()
+        };
+        assert_eq!(read_from_catalog(&storage, &archived).await, Ok(None));
+        let corrupted = CatalogEntry {
+            checksum: Some(integrity::checksum(b"other")),
+            ..entry
+        };
+        assert_eq!(read_from_catalog(&storage, &corrupted).await, Ok(None));
+    }
 }
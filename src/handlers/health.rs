@@ -0,0 +1,289 @@
+use std::{collections::HashMap, future::Future, sync::Mutex, time::Duration};
+
+use super::super::facades;
+use crate::config::StorageKind;
+use crate::state::AppState;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use chrono::Utc;
+use facades::{efs_facade, postgres_facade, s3, shutdown};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::time::Instant;
+
+//A dependency that doesn't answer within this is down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    //Last result of the remote checks by target, the load balancer polls every node often
+    static ref CHECKS: Mutex<HashMap<String, (Instant, Check)>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disk: Option<efs_facade::DiskUsage>,
+    checked_at: String,
+}
+
+impl Check {
+    fn new(result: Result<(), String>) -> Check {
+        Check {
+            healthy: result.is_ok(),
+            error: result.err(),
+            disk: None,
+            checked_at: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    status: &'static str,
+    checks: HashMap<&'static str, Check>,
+}
+
+//The process answers, the dependencies are not checked
+pub async fn live_handler() -> impl IntoResponse {
+    Json(HealthReport {
+        status: "ok",
+        checks: HashMap::new(),
+    })
+}
+
+/*Steps
+1. Not ready once the shutdown started
+2. Check EFS (writable, enough free space), Postgres and S3 in parallel, only what is configured
+3. 200 if every check passed, 503 with the failing checks otherwise
+*/
+pub async fn ready_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let (efs, postgres, s3) =
+        tokio::join!(check_efs(&state), check_postgres(&state), check_s3(&state));
+
+    let mut checks = HashMap::new();
+    for (name, check) in [("efs", efs), ("postgres", postgres), ("s3", s3)] {
+        if let Some(check) = check {
+            checks.insert(name, check);
+        }
+    }
+    if shutdown::is_triggered() {
+        checks.insert("shutdown", Check::new(Err("shutting down".to_string())));
+    }
+
+    let healthy = checks.values().all(|check| check.healthy);
+    let status = match healthy {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let report = HealthReport {
+        status: if healthy { "ok" } else { "fail" },
+        checks,
+    };
+    (status, Json(report))
+}
+
+async fn check_efs(state: &AppState) -> Option<Check> {
+    let config = state.config.get();
+    if config.storage_backend != StorageKind::Efs {
+        return None;
+    }
+    let base = &config.base_path;
+
+    let disk = efs_facade::disk_usage(base);
+    let writable = with_timeout(efs_facade::check_writable(base)).await;
+    let mut check = Check::new(writable.and(match &disk {
+        Ok(disk) if disk.free_bytes < config.min_free_bytes => Err(format!(
+            "{} bytes free, {} required",
+            disk.free_bytes, config.min_free_bytes
+        )),
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.clone()),
+    }));
+    check.disk = disk.ok();
+    Some(check)
+}
+
+async fn check_postgres(state: &AppState) -> Option<Check> {
+    let config = state.config.get();
+    let pool = state.pg_pool.as_ref()?;
+    let pg = config.postgres.as_ref()?;
+    let target = format!("postgres:{}/{}", pg.host, pg.db);
+    Some(cached(target, config.health_cache, postgres_facade::ping(pool)).await)
+}
+
+async fn check_s3(state: &AppState) -> Option<Check> {
+    let config = state.config.get();
+    let (bucket, client) = match (&config.s3_bucket, &state.s3_client) {
+        (Some(bucket), Some(client)) => (bucket, client.clone()),
+        _ => return None,
+    };
+    let target = format!(
+        "s3:{}/{}",
+        config.s3_endpoint.as_deref().unwrap_or_default(),
+        bucket
+    );
+    Some(cached(target, config.health_cache, s3::head_bucket(bucket, client)).await)
+}
+
+//Reuses the last result of a target for ttl, otherwise runs the check
+async fn cached(
+    target: String,
+    ttl: Duration,
+    check: impl Future<Output = Result<(), String>>,
+) -> Check {
+    if let Some((checked, check)) = CHECKS.lock().unwrap().get(&target) {
+        if checked.elapsed() < ttl {
+            return check.clone();
+        }
+    }
+
+    let check = Check::new(with_timeout(check).await);
+    CHECKS
+        .lock()
+        .unwrap()
+        .insert(target, (Instant::now(), check.clone()));
+    check
+}
+
+async fn with_timeout(check: impl Future<Output = Result<(), String>>) -> Result<(), String> {
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or(Err(format!("no answer within {:?}", CHECK_TIMEOUT)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, PostgresConfig, PostgresRecycling, PostgresTls};
+    use crate::create_router;
+    use crate::facades::fake_s3::FakeS3;
+    use crate::facades::postgres_facade::{create_config, create_pool};
+    use crate::state::SharedConfig;
+    use axum::body::Body;
+    use hyper::{body::to_bytes, Request};
+    use tower::ServiceExt;
+
+    async fn get(state: AppState, uri: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = create_router(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn efs_state(base_path: &str, min_free_bytes: u64) -> AppState {
+        AppState::from_config(Config {
+            base_path: base_path.to_string(),
+            min_free_bytes,
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn live_without_dependencies() {
+        let (status, body) = get(AppState::in_memory(), "/health/live").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn ready_checks_efs() {
+        let base = tempfile::tempdir().unwrap();
+        let base_path = base.path().to_str().unwrap();
+
+        let (status, body) = get(efs_state(base_path, 0), "/health/ready").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["checks"]["efs"]["healthy"], true);
+        assert!(
+            body["checks"]["efs"]["disk"]["free_bytes"]
+                .as_u64()
+                .unwrap()
+                > 0
+        );
+
+        //Not enough space left
+        let (status, body) = get(efs_state(base_path, u64::MAX), "/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "fail");
+        assert_eq!(body["checks"]["efs"]["healthy"], false);
+
+        //Unmounted
+        let state = efs_state(base_path, 0);
+        drop(base);
+        let (status, body) = get(state, "/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["checks"]["efs"]["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn ready_checks_postgres() {
+        //Nothing listens there
+        let mut pg_config = create_config("127.0.0.1", "user", "password", "ready_db");
+        pg_config.port(1);
+        let state = AppState {
+            config: SharedConfig::new(Config {
+                storage_backend: StorageKind::Memory,
+                postgres: Some(PostgresConfig {
+                    host: "127.0.0.1:1".to_string(),
+                    user: "user".to_string(),
+                    password: "password".to_string(),
+                    db: "ready_db".to_string(),
+                    pool_size: 1,
+                    connect_timeout: None,
+                    wait_timeout: None,
+                    recycle_timeout: None,
+                    recycling_method: PostgresRecycling::Fast,
+                    tls: PostgresTls::default(),
+                }),
+                ..Config::default()
+            }),
+            pg_pool: Some(create_pool(pg_config, 1).unwrap()),
+            ..AppState::in_memory()
+        };
+
+        let (status, body) = get(state, "/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["checks"]["postgres"]["healthy"], false);
+        assert!(body["checks"].get("efs").is_none());
+    }
+
+    #[tokio::test]
+    async fn ready_checks_s3_and_caches_the_result() {
+        let fake = FakeS3::start().await;
+        let state = AppState {
+            config: SharedConfig::new(Config {
+                storage_backend: StorageKind::Memory,
+                s3_bucket: Some("ready-bucket".to_string()),
+                s3_endpoint: Some(fake.endpoint().to_string()),
+                health_cache: Duration::from_secs(60),
+                ..Config::default()
+            }),
+            s3_client: Some(fake.client()),
+            ..AppState::in_memory()
+        };
+
+        let (status, body) = get(state.clone(), "/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["checks"]["s3"]["healthy"], false);
+
+        //The failure is reused until the cache expires
+        fake.create_bucket("ready-bucket");
+        let (status, cached) = get(state.clone(), "/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(cached["checks"]["s3"], body["checks"]["s3"]);
+
+        let state = AppState {
+            config: SharedConfig::new(Config {
+                health_cache: Duration::ZERO,
+                ..(*state.config.get()).clone()
+            }),
+            ..state
+        };
+        let (status, body) = get(state, "/health/ready").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["checks"]["s3"]["healthy"], true);
+    }
+}
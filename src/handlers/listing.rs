@@ -0,0 +1,339 @@
+use std::{collections::HashMap, sync::Arc};
+
+use super::super::facades;
+use crate::state::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{NaiveDate, Utc};
+use deadpool_postgres::Pool;
+use facades::catalog;
+use facades::efs_facade::{parse_file_path, split_segment, Metadata};
+use facades::storage::{Storage, StorageBackend, Tier};
+use serde::Serialize;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Serialize)]
+pub struct CollectionEntry {
+    file: String,
+    collection: String,
+    date: String,
+    tier: Tier,
+    size: u64,
+    //null when the tier can't count them cheaply (S3 without the catalog)
+    segments: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentEntry {
+    file: String,
+    tier: Tier,
+    reference: String,
+    #[serde(flatten)]
+    meta: Metadata,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentPage {
+    collection: String,
+    date: String,
+    segments: Vec<SegmentEntry>,
+    next_cursor: Option<String>,
+}
+
+/*Steps
+1. List the collection files of every storage tier, only those of ?collection= if given
+2. Count their segments with the catalog, or with whatever is cheap for the tier
+3. Return every tier
+*/
+pub async fn collections_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let collection = params.get("collection").map(String::as_str);
+    match list_collections(&state.storage, state.catalog(), collection).await {
+        Ok(collections) => (StatusCode::OK, Json(collections)).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    }
+}
+
+/*Steps
+1. Validate the date, cursor & limit params
+2. Find every manifest of the collection for that day (tiers in order)
+3. Skip everything up to the cursor and return one page of segments
+*/
+pub async fn segments_handler(
+    storage: &Storage,
+    collection: String,
+    params: HashMap<String, String>,
+) -> impl IntoResponse {
+    let date = match params.get("date") {
+        Some(date) => match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+            Ok(date) => date.format("%Y-%m-%d").to_string(),
+            Err(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "unable to parse date param (expected YYYY-MM-DD)".to_string(),
+                )
+                    .into_response()
+            }
+        },
+        None => Utc::now().format("%Y-%m-%d").to_string(),
+    };
+
+    let cursor = match params.get("cursor").map(|c| parse_cursor(c)) {
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "unable to parse cursor param".to_string(),
+            )
+                .into_response()
+        }
+        Some(Some(cursor)) => Some(cursor),
+        None => None,
+    };
+
+    let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) if limit > 0 => limit.min(MAX_PAGE_SIZE),
+        Some(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "unable to parse limit param".to_string(),
+            )
+                .into_response()
+        }
+        None => DEFAULT_PAGE_SIZE,
+    };
+
+    match list_segments(storage, &collection, &date, cursor, limit).await {
+        Ok((segments, next_cursor)) => (
+            StatusCode::OK,
+            Json(SegmentPage {
+                collection,
+                date,
+                segments,
+                next_cursor,
+            }),
+        )
+            .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+    }
+}
+
+async fn list_collections(
+    storage: &Storage,
+    catalog: Option<&Pool>,
+    collection: Option<&str>,
+) -> Result<Vec<CollectionEntry>, String> {
+    let prefix = collection.map(|c| format!("{}-", c)).unwrap_or_default();
+    let mut files = Vec::new();
+    for tier in storage.tiers() {
+        for (file, size) in tier.list(&prefix).await? {
+            match parse_file_path(&file) {
+                Some((name, _, _)) if collection.map(|c| c != name).unwrap_or(false) => {}
+                Some((name, _, date)) => files.push((tier, file, name, date, size)),
+                None => {}
+            }
+        }
+    }
+
+    //One query for every file rather than a manifest per file
+    let counts = match catalog {
+        Some(pool) => {
+            let names: Vec<String> = files.iter().map(|(_, file, ..)| file.clone()).collect();
+            catalog::segment_counts(pool, &names).await
+        }
+        None => None,
+    };
+
+    let mut collections = Vec::new();
+    for (tier, file, collection, date, size) in files {
+        let segments = match &counts {
+            Some(counts) => Some(counts.get(&file).copied().unwrap_or(0)),
+            None => tier.count_segments(&file).await?,
+        };
+        collections.push(CollectionEntry {
+            file,
+            collection,
+            date,
+            tier: tier.tier(),
+            size,
+            segments,
+        });
+    }
+
+    Ok(collections)
+}
+
+async fn list_segments(
+    storage: &Storage,
+    collection: &str,
+    date: &str,
+    cursor: Option<(String, usize)>,
+    limit: usize,
+) -> Result<(Vec<SegmentEntry>, Option<String>), String> {
+    let belongs_to_day = |file: &str| {
+        parse_file_path(file)
+            .map(|(c, _, d)| c == collection && d == date)
+            .unwrap_or(false)
+    };
+
+    //Every file of the day, with the tier it lives on. The first tier wins if a file is in several.
+    let prefix = format!("{}-", collection);
+    let mut files: Vec<(String, &Arc<dyn StorageBackend>)> = Vec::new();
+    for tier in storage.tiers() {
+        for (file, _) in tier.list(&prefix).await? {
+            if belongs_to_day(&file) && !files.iter().any(|(f, _)| *f == file) {
+                files.push((file, tier));
+            }
+        }
+    }
+
+    //Rotated segments follow their day file in order, _10 comes after _2
+    let order = |file: &str| {
+        let (day_path, segment) = split_segment(file);
+        (day_path.to_string(), segment)
+    };
+    files.sort_by_key(|(file, _)| order(file));
+
+    let mut segments = Vec::new();
+    for (file, tier) in files {
+        let skip = match &cursor {
+            Some((cursor_file, _)) if order(&file) < order(cursor_file) => continue,
+            Some((cursor_file, index)) if file == *cursor_file => *index,
+            _ => 0,
+        };
+
+        let manifest = tier.metadata(&file).await?.unwrap_or_default();
+
+        for (index, meta) in manifest.into_iter().enumerate().skip(skip) {
+            if segments.len() == limit {
+                return Ok((segments, Some(format_cursor(&file, index))));
+            }
+            segments.push(SegmentEntry {
+                reference: format!(
+                    "{file}?start={start}&end={end}",
+                    file = file,
+                    start = meta.start,
+                    end = meta.end
+                ),
+                file: file.clone(),
+                tier: tier.tier(),
+                meta,
+            });
+        }
+    }
+
+    Ok((segments, None))
+}
+
+//A cursor points to the next segment to return: "<file>.<index in manifest>"
+fn format_cursor(file: &str, index: usize) -> String {
+    format!("{}.{}", file, index)
+}
+
+fn parse_cursor(cursor: &str) -> Option<(String, usize)> {
+    let (file, index) = cursor.rsplit_once('.')?;
+    Some((file.to_string(), index.parse::<usize>().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use facades::efs_facade::get_file_path;
+    use facades::integrity::checksum;
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = format_cursor("collection-1-2023-08-01", 12);
+        assert_eq!(
+            parse_cursor(&cursor),
+            Some(("collection-1-2023-08-01".to_string(), 12))
+        );
+    }
+
+    #[test]
+    fn invalid_cursor() {
+        assert!(parse_cursor("collection-1-2023-08-01").is_none());
+        assert!(parse_cursor("collection-1-2023-08-01.abc").is_none());
+    }
+
+    #[tokio::test]
+    async fn page_through_segments() {
+        let storage = Storage::in_memory();
+
+        let collection = "listing_collection".to_string();
+        for i in 0..5u8 {
+            let meta = Metadata::new(
+                "text/plain".to_string(),
+                "gzip".to_string(),
+                "localhost".to_string(),
+                0,
+                0,
+                checksum(&[i; 10]),
+            );
+            let (file, _, _) = storage
+                .primary()
+                .append(&collection, vec![i; 10], meta)
+                .await
+                .unwrap();
+            assert_eq!(file, get_file_path(collection.clone()));
+        }
+
+        let date = Utc::now().format("%Y-%m-%d").to_string();
+        let (first, cursor) = list_segments(&storage, &collection, &date, None, 3)
+            .await
+            .unwrap();
+        assert_eq!(first.len(), 3);
+        assert_eq!(first[0].meta.start, 0);
+        assert_eq!(first[2].meta.end, 30);
+
+        let cursor = parse_cursor(&cursor.unwrap());
+        let (second, cursor) = list_segments(&storage, &collection, &date, cursor, 3)
+            .await
+            .unwrap();
+        assert_eq!(second.len(), 2);
+        assert_eq!(second[0].meta.start, 30);
+        assert!(cursor.is_none());
+
+        let collections = list_collections(&storage, None, None).await.unwrap();
+        assert_eq!(collections.len(), 1);
+        assert_eq!(collections[0].size, 50);
+        assert_eq!(collections[0].segments, Some(5));
+        assert_eq!(collections[0].tier, Tier::Memory);
+
+        //Same prefix, another collection
+        let other = "listing_collection-other";
+        let meta = Metadata::new(
+            "text/plain".to_string(),
+            "gzip".to_string(),
+            "localhost".to_string(),
+            0,
+            0,
+            checksum(&[9; 10]),
+        );
+        storage
+            .primary()
+            .append(other, vec![9; 10], meta)
+            .await
+            .unwrap();
+        assert_eq!(
+            list_collections(&storage, None, None).await.unwrap().len(),
+            2
+        );
+        let filtered = list_collections(&storage, None, Some(&collection))
+            .await
+            .unwrap();
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].collection, collection);
+        let (segments, _) = list_segments(&storage, &collection, &date, None, 10)
+            .await
+            .unwrap();
+        assert_eq!(segments.len(), 5);
+    }
+}
@@ -1,6 +1,6 @@
 use axum::response::{Response, IntoResponse};
 use axum::body::Full;
-use prometheus::{register_int_counter_vec, IntCounterVec, Encoder, TextEncoder};
+use prometheus::{Encoder, TextEncoder};
 
 pub async fn handle_metrics() -> impl IntoResponse {
     let encoder = TextEncoder::new();
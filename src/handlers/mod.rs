@@ -1,3 +1,6 @@
+pub mod admin;
 pub mod collections;
 pub mod general;
-pub mod metrics;
\ No newline at end of file
+pub mod health;
+pub mod listing;
+pub mod metrics;
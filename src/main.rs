@@ -2,64 +2,150 @@ pub mod middlewares;
 use middlewares::tracing::tracing_fn;
 
 pub mod handlers;
+use handlers::admin::admin_router;
 use handlers::collections::collection_handler;
 use handlers::general::pong;
+use handlers::health::{live_handler, ready_handler};
+use handlers::listing::collections_handler;
 use handlers::metrics::handle_metrics;
 
 pub mod facades;
+use facades::{archivist, disk_monitor, efs_migration, migrations, node, postgres_facade, recovery, scrubber, shutdown};
+
+pub mod cli;
+use cli::{Cli, Command};
+
+pub mod config;
+use config::{Config, StorageKind};
+
+pub mod state;
+use state::AppState;
 
-use crate::facades::compression::{gzip_compress, gzip_decompress};
 use crate::middlewares::tracing;
 use axum::{
     middleware,
     routing::{any, get},
     Router,
 };
-use std::{env, net::SocketAddr};
+use clap::Parser;
 
-#[derive(Clone)]
-pub struct Config {
-    pub secret: String,
-}
-
-fn create_addr(host: &str, port: &str) -> Result<SocketAddr, String> {
-    let format = format!("{}:{}", host, port);
-    format
-        .parse::<SocketAddr>()
-        .map_err(|_| format!("{} is not a valid app address", format))
+fn create_router(state: AppState) -> Router {
+    Router::new()
+        .route("/ping", get(pong))
+        .route("/health/live", get(live_handler))
+        .route("/health/ready", get(ready_handler))
+        .route("/collections", get(collections_handler))
+        .route("/collection/*collection", any(collection_handler))
+        .route("/metrics", get(handle_metrics))
+        .nest("/admin", admin_router(&state))
+        .layer(middleware::from_fn_with_state(
+            state.config.clone(),
+            tracing_fn,
+        ))
+        .with_state(state)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if env::var("WITH_LOGS")
-        .map(|v| v == "true")
-        .unwrap_or(true)
-    {
-        tracing::init_tracing()?;
+    let cli = Cli::parse();
+
+    //Invalid config stops the app before it accepts anything
+    let config = Config::load()?;
+
+    match cli.command {
+        None | Some(Command::Serve) => serve(config).await,
+        Some(command) => Ok(cli::run(command, &config, &mut std::io::stdout()).await?),
+    }
+}
+
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let tracer_provider = tracing::init_tracing(&config)?;
+
+    let state = AppState::from_config(config)?;
+    let config = state.config.get();
+
+    //Refuse to start on a schema this binary wasn't built for, before anything on disk is touched
+    if let Some(pool) = &state.pg_pool {
+        let mut client = postgres_facade::get_client(pool).await?;
+        if config.postgres_migrate {
+            let applied = migrations::migrate(&mut client).await?;
+            println!("APPLIED MIGRATIONS => {:?}", applied);
+        }
+        migrations::check_version(&client).await?;
+    }
+
+    println!("NODE ID => {}", node::init_node_id(&config)?);
+
+    //One shot, migrated archives are deleted from LEGACY_PATH
+    if let Some(legacy_path) = &config.legacy_path {
+        match efs_migration::migrate_legacy_archives(legacy_path, &config).await {
+            Ok(migrated) => println!("MIGRATED LEGACY PAYLOADS => {}", migrated.len()),
+            Err(err) => println!("LEGACY MIGRATION FAILED => {}", err),
+        }
+    }
+
+    //Repair what a crash may have left behind before accepting writes
+    if config.with_recovery {
+        if let Err(err) = recovery::recover_all(&config.base_path).await {
+            println!("RECOVERY FAILED => {}", err);
+        }
+    }
+
+    if config.with_scrubber {
+        scrubber::spawn_scrubber(config.base_path.clone(), config.scrub_interval);
+    }
+
+    //Sealed segments go to S3 as soon as they rotate
+    let archiver = match (&config.s3_bucket, &state.s3_client) {
+        (Some(bucket), Some(client)) => Some(archivist::spawn_sealed_archiver(
+            config.base_path.clone(),
+            bucket.clone(),
+            client.clone(),
+            state.pg_pool.clone(),
+            config.with_catalog,
+        )),
+        _ => None,
+    };
+
+    //Writes are rejected before BASE_PATH is actually full
+    if config.storage_backend == StorageKind::Efs {
+        disk_monitor::spawn_disk_monitor(state.config.clone());
     }
 
     // build our application with a route
-    let app = Router::new()
-        .route("/ping", get(pong))
-        .route("/collection/*collection", any(collection_handler))
-        .route("/metrics", get(handle_metrics))
-        .layer(middleware::from_fn(tracing_fn));
-
-    let app_host = env::var("APP_HOST").unwrap_or("0.0.0.0".to_string());
-    let app_port = env::var("APP_PORT").unwrap_or("5000".to_string());
-    //Create app url
-    let addr = create_addr(&app_host, &app_port);
-
-    match addr {
-        Ok(valid_addr) => {
-            // run it
-            println!("listening on {}", valid_addr);
-            axum::Server::bind(&valid_addr)
-                .serve(app.into_make_service())
-                .await
-                .unwrap();
+    let addr = config.addr;
+    let shutdown_timeout = config.shutdown_timeout;
+    let app = create_router(state);
+
+    // run it, on SIGTERM/SIGINT stop accepting and let the in-flight requests finish
+    println!("listening on {}", addr);
+    let server = axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown::wait_for_signal());
+    let server_deadline = async {
+        shutdown::wait().await;
+        tokio::time::sleep_until(shutdown::deadline(shutdown_timeout)).await;
+    };
+    tokio::select! {
+        served = server => served?,
+        _ = server_deadline => println!("SHUTDOWN DEADLINE REACHED => dropping open connections"),
+    }
+
+    //Everything below shares what is left of the deadline
+    let deadline = shutdown::deadline(shutdown_timeout);
+    match tokio::time::timeout_at(deadline, shutdown::close_files()).await {
+        Ok(Ok(files)) => println!("CLOSED FILES => {}", files),
+        Ok(Err(err)) => println!("CLOSING FILES FAILED => {}", err),
+        Err(_) => println!("SHUTDOWN DEADLINE REACHED => writes still in flight"),
+    }
+    if let Some(archiver) = archiver {
+        if tokio::time::timeout_at(deadline, archiver).await.is_err() {
+            println!("SHUTDOWN DEADLINE REACHED => archiving interrupted, the segment stays on EFS");
         }
-        Err(err) => println!("ABORTING => {}", err.to_string()),
+    }
+    //Spans still batched are sent before exiting
+    if let Some(provider) = tracer_provider {
+        provider.force_flush();
     }
 
     Ok(())
@@ -68,15 +154,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use axum::body::Body;
+    use hyper::{body::to_bytes, Request};
+    use tower::ServiceExt;
 
     #[test]
-    fn create_valid_addr() {
-        let addr = create_addr("127.0.0.1", "5000");
-        assert!(addr.is_ok())
+    fn create_valid_router() {
+        let _router = create_router(AppState::in_memory());
     }
-    #[test]
-    fn create_invalid_addr() {
-        let addr = create_addr("123.456.789", "ab99");
-        assert!(addr.is_err())
+
+    #[tokio::test]
+    async fn requests_are_measured() {
+        let router = create_router(AppState::in_memory());
+
+        let ping = Request::builder().uri("/ping").body(Body::empty()).unwrap();
+        router.clone().oneshot(ping).await.unwrap();
+
+        let metrics = Request::builder().uri("/metrics").body(Body::empty()).unwrap();
+        let response = router.oneshot(metrics).await.unwrap();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.contains(
+            r#"http_requests_total{collection="",method="GET",route="/ping",status="200"}"#
+        ));
+        assert!(body.contains("http_request_duration_seconds_bucket"));
     }
 }
@@ -0,0 +1,58 @@
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::state::SharedConfig;
+
+/*Steps
+1. Without ADMIN_TOKEN the admin routes don't exist (404)
+2. Expect "Authorization: Bearer {ADMIN_TOKEN}", 401 otherwise
+*/
+pub async fn admin_auth<B>(
+    State(config): State<SharedConfig>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = match &config.get().admin_token {
+        Some(token) => token.clone(),
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes()))
+        .unwrap_or(false);
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "invalid or missing admin token",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+//Compares every byte so the time taken doesn't tell how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
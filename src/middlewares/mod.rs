@@ -1 +1,3 @@
-pub mod tracing;
\ No newline at end of file
+pub mod admin_auth;
+pub mod telemetry;
+pub mod tracing;
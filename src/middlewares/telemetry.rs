@@ -0,0 +1,202 @@
+use axum::http::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{TraceError, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    export::trace::{ExportResult, SpanData, SpanExporter},
+    runtime,
+    trace::{self, Tracer, TracerProvider},
+    Resource,
+};
+use std::{
+    fmt,
+    fs::OpenOptions,
+    future::{self, Future},
+    io::{self, Write},
+    pin::Pin,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use crate::config::{TelemetryConfig, TelemetryExporter};
+
+//Writes every span as a JSON line, used for the stdout and file exporters
+pub struct JsonSpanExporter {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonSpanExporter {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        JsonSpanExporter {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+}
+
+impl fmt::Debug for JsonSpanExporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JsonSpanExporter")
+    }
+}
+
+fn unix_nanos(time: std::time::SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn span_to_json(span: &SpanData) -> serde_json::Value {
+    let attributes: serde_json::Map<String, serde_json::Value> = span
+        .attributes
+        .iter()
+        .map(|attribute| {
+            (
+                attribute.key.to_string(),
+                attribute.value.to_string().into(),
+            )
+        })
+        .collect();
+    serde_json::json!({
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": span.parent_span_id.to_string(),
+        "name": span.name,
+        "start": unix_nanos(span.start_time),
+        "end": unix_nanos(span.end_time),
+        "attributes": attributes,
+        "status": format!("{:?}", span.status),
+    })
+}
+
+impl SpanExporter for JsonSpanExporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let mut writer = self.writer.lock().unwrap();
+        let written = batch
+            .iter()
+            .try_for_each(|span| writeln!(writer, "{}", span_to_json(span)))
+            .and_then(|_| writer.flush())
+            .map_err(|e| TraceError::Other(Box::new(e)));
+        Box::pin(future::ready(written))
+    }
+}
+
+/*Steps
+1. Build the exporter picked in the config, OTLP spans are sent in batches
+2. Tag the spans with the service name
+3. Continue the traces of the clients with the W3C traceparent header
+*/
+pub fn create_provider(config: &TelemetryConfig) -> Result<TracerProvider, String> {
+    let trace_config = trace::config().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        config.service_name.clone(),
+    )]));
+    let builder = TracerProvider::builder().with_config(trace_config);
+
+    let builder = match &config.exporter {
+        TelemetryExporter::Otlp(endpoint) => {
+            let exporter = opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint)
+                .build_span_exporter()
+                .map_err(|e| e.to_string())?;
+            builder.with_batch_exporter(exporter, runtime::Tokio)
+        }
+        TelemetryExporter::Stdout => {
+            builder.with_simple_exporter(JsonSpanExporter::new(io::stdout()))
+        }
+        TelemetryExporter::File(path) => {
+            let file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .map_err(|e| format!("Unable to open {}: {}", path, e))?;
+            builder.with_simple_exporter(JsonSpanExporter::new(file))
+        }
+    };
+
+    global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+    Ok(builder.build())
+}
+
+pub fn create_tracer(provider: &TracerProvider) -> Tracer {
+    provider.tracer(env!("CARGO_PKG_NAME"))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+//Trace context sent by the client, empty without a valid traceparent header
+pub fn extract_parent(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_router, state::AppState};
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[tokio::test]
+    async fn traceparent_is_continued() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spans.jsonl");
+        let provider = create_provider(&TelemetryConfig {
+            exporter: TelemetryExporter::File(path.to_str().unwrap().to_string()),
+            service_name: "test".to_string(),
+        })
+        .unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(create_tracer(&provider)));
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let router = create_router(AppState::in_memory());
+        let request = Request::post("/collection/traced_collection")
+            .header("Content-Type", "text/plain")
+            .header("Host", "localhost")
+            .header("traceparent", format!("00-{}-{}-01", TRACE_ID, PARENT_ID))
+            .body(Body::from("traced payload"))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert!(response.status().is_success());
+
+        drop(guard);
+        provider.force_flush();
+
+        let spans: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(spans.iter().all(|span| span["trace_id"] == TRACE_ID));
+        let request_span = spans
+            .iter()
+            .find(|span| span["attributes"]["http.route"] == "/collection/*collection")
+            .unwrap();
+        assert_eq!(request_span["parent_span_id"], PARENT_ID);
+        assert!(spans
+            .iter()
+            .any(|span| span["name"] == "compress"
+                && span["parent_span_id"] == request_span["span_id"]));
+    }
+}
@@ -1,58 +1,358 @@
 use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, State},
     response::Response,
-    http::Request,
+    http::{header, HeaderMap, HeaderValue, Request},
     middleware::Next
 };
-use tracing::info;
-use std::{time::Instant, env};
-use opentelemetry::global;
+use lazy_static::lazy_static;
+use prometheus::{exponential_buckets, register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use opentelemetry_sdk::trace::TracerProvider;
+use tracing::{field, info, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use std::{collections::{BTreeMap, HashSet}, time::Instant, sync::Mutex};
+
+use crate::config::Config;
+use crate::state::SharedConfig;
+use crate::facades::efs_facade::parse_file_path;
+use crate::facades::storage::Tier;
+use super::telemetry;
 use tracing_subscriber::{
     fmt, layer::SubscriberExt, util::SubscriberInitExt,
 };
 
-pub async fn tracing_fn<B>(request: Request<B>, next: Next<B>) -> Response {
+//Collections past this many share the "other" label, names come from the clients
+const MAX_COLLECTION_LABELS: usize = 100;
+
+//Generated when the client doesn't send one, returned in the response either way
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Number of HTTP requests by route, collection and status",
+        &["method", "route", "collection", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time taken to answer HTTP requests",
+        &["method", "route", "collection"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_SIZE: HistogramVec = register_histogram_vec!(
+        "http_request_size_bytes",
+        "Size of the HTTP request bodies",
+        &["method", "route", "collection"],
+        exponential_buckets(64.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    static ref HTTP_RESPONSE_SIZE: HistogramVec = register_histogram_vec!(
+        "http_response_size_bytes",
+        "Size of the HTTP response bodies",
+        &["method", "route", "collection"],
+        exponential_buckets(64.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    static ref COLLECTION_LABELS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+pub async fn tracing_fn<B>(State(config): State<SharedConfig>, mut request: Request<B>, next: Next<B>) -> Response {
+    let config = config.get();
+
+    //The handlers and the logs see the same ID as the client
+    let request_id = get_request_id(request.headers());
+    request.headers_mut().insert(REQUEST_ID_HEADER, request_id.clone());
+
     //Extract necessary information from the request
     let method = request.method().to_string();
     let url = request.uri().to_string();
-    let headers = format!("{:?}", request.headers());
-    
+    let headers = redact_headers(
+        request.headers(),
+        config.log_headers_allow.as_deref(),
+        &config.log_headers_deny,
+    );
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or("unmatched".to_string());
+    let collection = get_collection_label(request.uri().path());
+    let request_size = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<f64>().ok());
+
+    //Spans of the facades are children of this one, which continues the trace of the client
+    let span = info_span!(
+        "request",
+        otel.name = %format!("{} {}", method, route),
+        otel.kind = "server",
+        http.method = %method,
+        http.route = %route,
+        http.status_code = field::Empty,
+        request_id = request_id.to_str().unwrap_or_default(),
+        collection = %collection,
+        reference = field::Empty,
+        tier = field::Empty,
+        bytes_in = field::Empty,
+        bytes_out = field::Empty,
+        compression_ratio = field::Empty,
+    );
+    span.set_parent(telemetry::extract_parent(request.headers()));
+
     //Start the timer
     let start = Instant::now();
-    
+
     //Execute the next middleware/request
-    let response = next.run(request).await;
-    
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+
     //Extract necessary information from the response
     let res_status = response.status().to_string();
-    
+
     //The request time (in ms)
-    let request_time = start.elapsed().as_millis().to_string();
-    
+    let elapsed = start.elapsed();
+    let request_time = elapsed.as_millis().to_string();
+
+    let labels = [method.as_str(), route.as_str(), collection.as_str()];
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, &collection, response.status().as_str()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+    if let Some(size) = request_size {
+        HTTP_REQUEST_SIZE.with_label_values(&labels).observe(size);
+        span.record("bytes_in", size as u64);
+    }
+    //Streamed bodies have no exact size
+    if let Some(size) = response.body().size_hint().exact() {
+        HTTP_RESPONSE_SIZE.with_label_values(&labels).observe(size as f64);
+        span.record("bytes_out", size);
+    }
+
     // uncomment in production
     //Log tracing information
-    if env::var("WITH_LOGS").map(|v| v == "true").unwrap_or(true) {
-        info!(
-            method = %method,
-            url = %url,
-            headers = %headers,
-            res_status = %res_status,
-            request_time = %request_time,
-            "Request processed"
-        );
+    //The payload fields are on the request span, logged with the event
+    if config.with_logs {
+        span.in_scope(|| {
+            info!(
+                method = %method,
+                url = %url,
+                headers = %headers,
+                res_status = %res_status,
+                request_time = %request_time,
+                "Request processed"
+            )
+        });
     } else {
        // println!("{} {} => {} ({}ms)", method, url, res_status, request_time);
     }
 
-    
+
     response
 }
 
-pub fn init_tracing() -> Result<(), Box<dyn std::error::Error>> {
+//Keeps the ID sent by the client if it is printable and short enough
+fn get_request_id(headers: &HeaderMap) -> HeaderValue {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .filter(|id| {
+            let id = id.as_bytes();
+            !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.iter().all(u8::is_ascii_graphic)
+        })
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_str(&format!("{:032x}", rand::random::<u128>())).unwrap())
+}
+
+//Headers outside the allow list are left out, the values of the denied ones are replaced
+fn redact_headers(headers: &HeaderMap, allow: Option<&[String]>, deny: &[String]) -> String {
+    let mut logged: BTreeMap<&str, String> = BTreeMap::new();
+    for (name, value) in headers {
+        let name = name.as_str();
+        if allow.map(|allow| !allow.iter().any(|h| h == name)).unwrap_or(false) {
+            continue;
+        }
+        let value = if deny.iter().any(|h| h == name) {
+            "[REDACTED]"
+        } else {
+            value.to_str().unwrap_or("[BINARY]")
+        };
+        logged
+            .entry(name)
+            .and_modify(|values| {
+                values.push_str(", ");
+                values.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    format!("{:?}", logged)
+}
+
+//Called by the handlers, recorded on the request span so they end up in the request log and the trace
+pub fn record_reference(reference: &str) {
+    Span::current().record("reference", reference);
+}
+
+pub fn record_tier(tier: Tier) {
+    Span::current().record("tier", tier.label());
+}
+
+pub fn record_compression(raw_bytes: usize, stored_bytes: usize) {
+    if stored_bytes > 0 {
+        Span::current().record("compression_ratio", raw_bytes as f64 / stored_bytes as f64);
+    }
+}
+
+//Collection of a /collection/... request, a reference names the file the collection is written to
+fn get_collection_label(path: &str) -> String {
+    let name = match path.strip_prefix("/collection/") {
+        Some(name) => name.trim_end_matches("/segments"),
+        None => return String::new(),
+    };
+    let collection = parse_file_path(name)
+        .map(|(collection, _, _)| collection)
+        .unwrap_or(name.to_string());
+
+    let mut labels = COLLECTION_LABELS.lock().unwrap();
+    if labels.contains(&collection) {
+        collection
+    } else if labels.len() < MAX_COLLECTION_LABELS {
+        labels.insert(collection.clone());
+        collection
+    } else {
+        "other".to_string()
+    }
+}
+
+//The provider is returned so main can flush the spans left on shutdown
+pub fn init_tracing(config: &Config) -> Result<Option<TracerProvider>, Box<dyn std::error::Error>> {
     // Create a tracing layer that logs to stdout in JSON format
-    let json_layer = fmt::Layer::new().json();
+    let json_layer = config.with_logs.then(|| fmt::Layer::new().json());
+
+    // Export the spans with OpenTelemetry when an exporter is configured
+    let provider = match &config.telemetry {
+        Some(telemetry) => Some(telemetry::create_provider(telemetry)?),
+        None => None,
+    };
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(telemetry::create_tracer(provider)));
+
+    // Initialize the tracing subscriber with the JSON and OpenTelemetry layers
+    tracing_subscriber::registry().with(json_layer).with(otel_layer).try_init()?;
+
+    Ok(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_router, state::AppState};
+    use axum::body::Body;
+    use std::{io, sync::Arc};
+    use tower::ServiceExt;
+
+    //Collects the JSON logs of a test
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn post_request(request_id: Option<&str>) -> Request<Body> {
+        let mut request = Request::post("/collection/logged_collection")
+            .header("Content-Type", "text/plain")
+            .header("Host", "localhost")
+            .header("Content-Length", "17")
+            .header("Authorization", "Bearer secret-token")
+            .header("Cookie", "session=secret-cookie");
+        if let Some(request_id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        request.body(Body::from("a logged payload!")).unwrap()
+    }
 
-    // Initialize the tracing subscriber with the JSON layer
-    tracing_subscriber::registry().with(json_layer).try_init()?;
+    #[test]
+    fn headers_are_redacted() {
+        let config = Config::default();
+        let request = post_request(None);
 
-    Ok(())
-}
\ No newline at end of file
+        let logged = redact_headers(request.headers(), None, &config.log_headers_deny);
+        assert!(logged.contains(r#""authorization": "[REDACTED]""#));
+        assert!(logged.contains(r#""cookie": "[REDACTED]""#));
+        assert!(logged.contains(r#""host": "localhost""#));
+        assert!(!logged.contains("secret"));
+
+        let allow = vec!["host".to_string(), "authorization".to_string()];
+        let logged = redact_headers(request.headers(), Some(&allow), &config.log_headers_deny);
+        assert_eq!(logged, r#"{"authorization": "[REDACTED]", "host": "localhost"}"#);
+    }
+
+    #[tokio::test]
+    async fn request_id_is_returned() {
+        let router = create_router(AppState::in_memory());
+
+        let response = router.clone().oneshot(post_request(Some("client-id-1"))).await.unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "client-id-1");
+
+        //Replaced when it can't be logged as is
+        let response = router.clone().oneshot(post_request(Some("not valid"))).await.unwrap();
+        let generated = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        assert_eq!(generated.len(), 32);
+
+        let response = router.oneshot(post_request(None)).await.unwrap();
+        assert_ne!(response.headers()[REQUEST_ID_HEADER], generated.as_str());
+    }
+
+    #[tokio::test]
+    async fn payload_fields_are_logged() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(fmt::Layer::new().json().with_writer(move || writer.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let router = create_router(AppState::in_memory());
+        let response = router.oneshot(post_request(Some("logged-request"))).await.unwrap();
+        assert!(response.status().is_success());
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Request processed"))
+            .unwrap();
+        let log: serde_json::Value = serde_json::from_str(line).unwrap();
+        let fields = &log["span"];
+        assert_eq!(fields["request_id"], "logged-request");
+        assert_eq!(fields["collection"], "logged_collection");
+        assert_eq!(fields["tier"], "memory");
+        assert_eq!(fields["bytes_in"], 17);
+        assert!(fields["reference"].as_str().unwrap().starts_with("logged_collection"));
+        assert!(fields["compression_ratio"].as_f64().unwrap() > 0.0);
+        assert!(!line.contains("secret"));
+    }
+
+    #[test]
+    fn collection_labels() {
+        assert_eq!(get_collection_label("/ping"), "");
+        assert_eq!(get_collection_label("/collection/labels_collection"), "labels_collection");
+        assert_eq!(get_collection_label("/collection/labels_collection/segments"), "labels_collection");
+        assert_eq!(
+            get_collection_label("/collection/labels_collection-node1-2023-08-01_2"),
+            "labels_collection"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use axum::extract::FromRef;
+use deadpool_postgres::Pool;
+use rusoto_s3::S3Client;
+use std::sync::{Arc, RwLock};
+
+use crate::config::{Config, StorageKind};
+use crate::facades::efs_facade::EfsBackend;
+use crate::facades::postgres_facade::create_pool_from_config;
+use crate::facades::s3::{create_client as create_s3_client, S3Backend};
+use crate::facades::storage::{MemoryBackend, Storage, StorageBackend};
+
+//Config of the running app, swapped as a whole when it is reloaded
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> SharedConfig {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    //Snapshot, a request keeps the config it started with
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: Config) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+//Everything the handlers share, built once from the config at startup
+#[derive(Clone)]
+pub struct AppState {
+    pub config: SharedConfig,
+    pub storage: Storage,
+    pub pg_pool: Option<Pool>,
+    pub s3_client: Option<S3Client>,
+}
+
+impl AppState {
+    /*Steps
+    1. One S3 client for the whole app if a bucket is configured
+    2. The Postgres pool if Postgres is configured (connections are opened on first use)
+    3. The storage tiers: EFS or memory first, then S3
+    */
+    pub fn from_config(config: Config) -> Result<AppState, String> {
+        let s3_client = config
+            .s3_bucket
+            .as_ref()
+            .map(|_| create_s3_client(config.s3_endpoint.as_deref(), config.s3_region.as_deref()));
+
+        let pg_pool = match &config.postgres {
+            Some(pg) => Some(create_pool_from_config(pg)?),
+            None => None,
+        };
+
+        let storage_backend = config.storage_backend;
+        let tier_config = (config.s3_bucket.clone(), s3_client.clone());
+        let shared = SharedConfig::new(config);
+
+        //EFS reads the write path settings from the shared config so a reload reaches it
+        let mut tiers: Vec<Arc<dyn StorageBackend>> = Vec::new();
+        match storage_backend {
+            StorageKind::Efs => tiers.push(Arc::new(EfsBackend::with_config(shared.clone()))),
+            StorageKind::Memory => tiers.push(Arc::new(MemoryBackend::new())),
+        }
+        if let (Some(bucket), Some(client)) = tier_config {
+            tiers.push(Arc::new(S3Backend::new(bucket, client)));
+        }
+
+        Ok(AppState {
+            config: shared,
+            storage: Storage::new(tiers),
+            pg_pool,
+            s3_client,
+        })
+    }
+
+    //Postgres pool when the segment catalog is enabled
+    pub fn catalog(&self) -> Option<&Pool> {
+        self.pg_pool
+            .as_ref()
+            .filter(|_| self.config.get().with_catalog)
+    }
+
+    //Default config with everything in memory
+    pub fn in_memory() -> AppState {
+        AppState {
+            config: SharedConfig::new(Config {
+                storage_backend: StorageKind::Memory,
+                ..Config::default()
+            }),
+            storage: Storage::in_memory(),
+            pg_pool: None,
+            s3_client: None,
+        }
+    }
+}
+
+impl FromRef<AppState> for Storage {
+    fn from_ref(state: &AppState) -> Storage {
+        state.storage.clone()
+    }
+}
+
+impl FromRef<AppState> for SharedConfig {
+    fn from_ref(state: &AppState) -> SharedConfig {
+        state.config.clone()
+    }
+}